@@ -0,0 +1,4 @@
+fn main() {
+    // sqlx::migrate! embeds the migrations at compile time, so rebuild when they change
+    println!("cargo:rerun-if-changed=migrations");
+}
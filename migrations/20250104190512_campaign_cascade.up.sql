@@ -0,0 +1,8 @@
+-- Add migration script here
+ALTER TABLE campaign_players
+    DROP CONSTRAINT campaign_players_campaign_id_fkey,
+    ADD CONSTRAINT campaign_players_campaign_id_fkey FOREIGN KEY (campaign_id) REFERENCES campaign (id) ON DELETE CASCADE;
+
+ALTER TABLE campaign_invites
+    DROP CONSTRAINT campaign_invites_campaign_id_fkey,
+    ADD CONSTRAINT campaign_invites_campaign_id_fkey FOREIGN KEY (campaign_id) REFERENCES campaign (id) ON DELETE CASCADE;
@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE users RENAME COLUMN discord_refresh_token TO refresh_token;
+ALTER TABLE users RENAME COLUMN discord_access_token TO access_token;
+
+DROP TABLE session_tokens;
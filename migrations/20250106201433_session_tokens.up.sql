@@ -0,0 +1,19 @@
+-- Add migration script here
+-- Tokens handed out to clients, only the sha256 hash of the token is stored
+CREATE TABLE session_tokens (
+	id SERIAL PRIMARY KEY,
+	user_id INTEGER NOT NULL,
+	token_hash TEXT NOT NULL UNIQUE,
+	device TEXT,
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+	last_used_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP NOT NULL,
+
+	CONSTRAINT fk_user FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX session_tokens_user_id ON session_tokens (user_id);
+
+-- Discord tokens were used as client tokens before, those stay on the server now
+ALTER TABLE users RENAME COLUMN access_token TO discord_access_token;
+ALTER TABLE users RENAME COLUMN refresh_token TO discord_refresh_token;
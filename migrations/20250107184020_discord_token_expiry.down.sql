@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE users DROP COLUMN discord_token_expires_at;
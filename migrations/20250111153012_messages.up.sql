@@ -0,0 +1,15 @@
+-- Chat messages sent over /ws, session_id is null for the campaign wide room
+CREATE TABLE messages (
+	id BIGSERIAL PRIMARY KEY,
+	campaign_id INTEGER NOT NULL,
+	session_id INTEGER,
+	author_id INTEGER NOT NULL,
+	content TEXT NOT NULL,
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+	CONSTRAINT fk_campaign FOREIGN KEY (campaign_id) REFERENCES campaign (id) ON DELETE CASCADE,
+	CONSTRAINT fk_session FOREIGN KEY (session_id) REFERENCES dnd_session (id) ON DELETE CASCADE,
+	CONSTRAINT fk_user FOREIGN KEY (author_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX messages_room ON messages (campaign_id, session_id, id);
@@ -0,0 +1 @@
+DROP TABLE dice_rolls;
@@ -0,0 +1,20 @@
+-- Every roll made over /ws, with the seed it was rolled with so it can be checked later
+CREATE TABLE dice_rolls (
+	id BIGSERIAL PRIMARY KEY,
+	campaign_id INTEGER NOT NULL,
+	session_id INTEGER,
+	roller_id INTEGER NOT NULL,
+	notation TEXT NOT NULL,
+	label TEXT,
+	-- the u64 seed stored as its bits
+	seed BIGINT NOT NULL,
+	result JSONB NOT NULL,
+	total BIGINT NOT NULL,
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+	CONSTRAINT fk_campaign FOREIGN KEY (campaign_id) REFERENCES campaign (id) ON DELETE CASCADE,
+	CONSTRAINT fk_session FOREIGN KEY (session_id) REFERENCES dnd_session (id) ON DELETE CASCADE,
+	CONSTRAINT fk_user FOREIGN KEY (roller_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX dice_rolls_room ON dice_rolls (campaign_id, session_id, id);
@@ -0,0 +1,3 @@
+ALTER TABLE dice_rolls DROP COLUMN visibility, DROP COLUMN recipients;
+ALTER TABLE messages DROP COLUMN visibility, DROP COLUMN recipients;
+DROP TYPE visibility;
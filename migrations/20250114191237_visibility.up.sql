@@ -0,0 +1,10 @@
+-- Who can see a chat message or dice roll. recipients are the user ids a whisper is for
+CREATE TYPE visibility AS ENUM ('public', 'whisper', 'dm', 'self', 'blind');
+
+ALTER TABLE messages
+	ADD COLUMN visibility visibility NOT NULL DEFAULT 'public',
+	ADD COLUMN recipients INTEGER[] NOT NULL DEFAULT '{}';
+
+ALTER TABLE dice_rolls
+	ADD COLUMN visibility visibility NOT NULL DEFAULT 'public',
+	ADD COLUMN recipients INTEGER[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,31 @@
+-- Player characters. `extra` is for whatever a game system needs that doesn't have a column
+CREATE TABLE characters (
+	id SERIAL PRIMARY KEY,
+	campaign_id INTEGER NOT NULL,
+	owner_id INTEGER NOT NULL,
+	name VARCHAR(128) NOT NULL,
+	class VARCHAR(64) NOT NULL DEFAULT '',
+	level INTEGER NOT NULL DEFAULT 1,
+	strength INTEGER NOT NULL DEFAULT 10,
+	dexterity INTEGER NOT NULL DEFAULT 10,
+	constitution INTEGER NOT NULL DEFAULT 10,
+	intelligence INTEGER NOT NULL DEFAULT 10,
+	wisdom INTEGER NOT NULL DEFAULT 10,
+	charisma INTEGER NOT NULL DEFAULT 10,
+	max_hp INTEGER NOT NULL DEFAULT 0,
+	current_hp INTEGER NOT NULL DEFAULT 0,
+	temp_hp INTEGER NOT NULL DEFAULT 0,
+	armor_class INTEGER NOT NULL DEFAULT 10,
+	skills TEXT[] NOT NULL DEFAULT '{}',
+	proficiencies TEXT[] NOT NULL DEFAULT '{}',
+	inventory JSONB NOT NULL DEFAULT '[]',
+	notes TEXT NOT NULL DEFAULT '',
+	extra JSONB NOT NULL DEFAULT '{}',
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+	last_updated TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+	CONSTRAINT fk_campaign FOREIGN KEY (campaign_id) REFERENCES campaign (id) ON DELETE CASCADE,
+	CONSTRAINT fk_owner FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX characters_campaign ON characters (campaign_id);
@@ -0,0 +1,2 @@
+-- Goes up by one on every change, so concurrent edits can be rejected instead of overwriting each other
+ALTER TABLE characters ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,42 @@
+-- Combat tracker. A dnd session has at most one encounter going at a time, ended ones are kept
+CREATE TABLE encounters (
+	id SERIAL PRIMARY KEY,
+	session_id INTEGER NOT NULL,
+	name VARCHAR(128) NOT NULL DEFAULT '',
+	-- 0 while initiative is being rolled, before the first turn
+	round INTEGER NOT NULL DEFAULT 0,
+	current_combatant_id INTEGER,
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+	last_updated TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+	ended_at TIMESTAMP,
+
+	CONSTRAINT fk_session FOREIGN KEY (session_id) REFERENCES dnd_session (id) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX encounters_active ON encounters (session_id) WHERE ended_at IS NULL;
+
+-- Characters and monsters in an encounter. `owner_id` is whoever controls it besides the DMs, NULL
+-- for monsters
+CREATE TABLE combatants (
+	id SERIAL PRIMARY KEY,
+	encounter_id INTEGER NOT NULL,
+	character_id INTEGER,
+	owner_id INTEGER,
+	name VARCHAR(128) NOT NULL,
+	initiative INTEGER NOT NULL,
+	-- the dice roll when the initiative wasn't set by hand
+	initiative_roll JSONB,
+	-- turn order, 0 goes first
+	position INTEGER NOT NULL,
+	delayed BOOLEAN NOT NULL DEFAULT FALSE,
+	-- the trigger of a readied action
+	readied TEXT,
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+	CONSTRAINT fk_encounter FOREIGN KEY (encounter_id) REFERENCES encounters (id) ON DELETE CASCADE,
+	CONSTRAINT fk_character FOREIGN KEY (character_id) REFERENCES characters (id) ON DELETE SET NULL,
+	CONSTRAINT fk_owner FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX combatants_encounter ON combatants (encounter_id, position);
+CREATE UNIQUE INDEX combatants_character ON combatants (encounter_id, character_id);
@@ -0,0 +1,22 @@
+-- Conditions and effects on combatants, e.g. poisoned or bless for 10 rounds
+CREATE TABLE effects (
+	id SERIAL PRIMARY KEY,
+	combatant_id INTEGER NOT NULL,
+	name VARCHAR(64) NOT NULL,
+	-- where it came from, e.g. "Bless", and the combatant that caused it
+	source VARCHAR(128),
+	source_combatant_id INTEGER,
+	-- NULL for effects that last until they're removed
+	duration_rounds INTEGER,
+	-- it ends at the start of `ends_on_turn_of`'s turn in round `ends_round`, or at the start of
+	-- that round if there's no such combatant (anymore)
+	ends_round INTEGER,
+	ends_on_turn_of INTEGER,
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+	CONSTRAINT fk_combatant FOREIGN KEY (combatant_id) REFERENCES combatants (id) ON DELETE CASCADE,
+	CONSTRAINT fk_source FOREIGN KEY (source_combatant_id) REFERENCES combatants (id) ON DELETE SET NULL,
+	CONSTRAINT fk_ends_on_turn_of FOREIGN KEY (ends_on_turn_of) REFERENCES combatants (id) ON DELETE SET NULL
+);
+
+CREATE INDEX effects_combatant ON effects (combatant_id);
@@ -0,0 +1,8 @@
+ALTER TABLE campaign_players ALTER COLUMN role DROP NOT NULL;
+ALTER TABLE campaign_players ALTER COLUMN role DROP DEFAULT;
+ALTER TABLE campaign_players ALTER COLUMN role TYPE VARCHAR(25) USING (
+	CASE WHEN role IN ('owner', 'co_dm') THEN 'dm' ELSE 'player' END
+);
+ALTER TABLE campaign_players ALTER COLUMN role SET DEFAULT 'player';
+
+DROP TYPE campaign_role;
@@ -0,0 +1,15 @@
+-- The owner is the campaign's user_id, co-DMs can do everything but delete the campaign or manage
+-- other DMs, and spectators can watch and chat but not play
+CREATE TYPE campaign_role AS ENUM ('owner', 'co_dm', 'player', 'spectator');
+
+UPDATE campaign_players p SET role = CASE
+	WHEN p.role = 'dm' AND p.player_id = c.user_id THEN 'owner'
+	WHEN p.role = 'dm' THEN 'co_dm'
+	ELSE 'player'
+END
+FROM campaign c WHERE c.id = p.campaign_id;
+
+ALTER TABLE campaign_players ALTER COLUMN role DROP DEFAULT;
+ALTER TABLE campaign_players ALTER COLUMN role TYPE campaign_role USING role::campaign_role;
+ALTER TABLE campaign_players ALTER COLUMN role SET DEFAULT 'player';
+ALTER TABLE campaign_players ALTER COLUMN role SET NOT NULL;
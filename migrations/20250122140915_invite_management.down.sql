@@ -0,0 +1,21 @@
+-- Only one invite per campaign fits, keep the newest
+DELETE FROM campaign_invites i
+WHERE EXISTS (SELECT 1 FROM campaign_invites n WHERE n.campaign_id = i.campaign_id AND n.id > i.id);
+
+UPDATE campaign_invites SET uses = CASE
+	WHEN expires_at <= (now() AT TIME ZONE 'utc') THEN 0
+	WHEN max_uses IS NULL THEN 2147483647
+	ELSE GREATEST(max_uses - uses, 0)
+END;
+
+DROP INDEX campaign_invites_campaign_id_idx;
+
+ALTER TABLE campaign_invites
+	DROP CONSTRAINT campaign_invites_uses_check,
+	DROP CONSTRAINT campaign_invites_invite_key,
+	DROP COLUMN id,
+	DROP COLUMN max_uses,
+	DROP COLUMN expires_at,
+	DROP COLUMN role,
+	DROP COLUMN created_by,
+	ADD PRIMARY KEY (campaign_id);
@@ -0,0 +1,20 @@
+-- Any number of invites per campaign. `uses` counts how many times an invite was used instead of
+-- how many uses it has left, and max_uses/expires_at are NULL when there's no limit
+ALTER TABLE campaign_invites DROP CONSTRAINT campaign_invites_pkey;
+
+ALTER TABLE campaign_invites
+	ADD COLUMN id SERIAL PRIMARY KEY,
+	ADD COLUMN max_uses INTEGER CHECK (max_uses >= 0),
+	ADD COLUMN expires_at TIMESTAMP,
+	-- what the people joining with it become
+	ADD COLUMN role campaign_role NOT NULL DEFAULT 'player' CHECK (role <> 'owner'),
+	ADD COLUMN created_by INTEGER REFERENCES users (id) ON DELETE SET NULL,
+	ADD CONSTRAINT campaign_invites_invite_key UNIQUE (invite);
+
+-- invites were made with 0 uses and nothing ever set them, so 0 means no limit rather than used up
+UPDATE campaign_invites i SET max_uses = NULLIF(i.uses, 0), uses = 0, created_by = c.user_id
+FROM campaign c WHERE c.id = i.campaign_id;
+
+ALTER TABLE campaign_invites ADD CONSTRAINT campaign_invites_uses_check CHECK (uses >= 0);
+
+CREATE INDEX campaign_invites_campaign_id_idx ON campaign_invites (campaign_id);
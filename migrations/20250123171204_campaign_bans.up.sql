@@ -0,0 +1,9 @@
+-- People kicked with ?ban=true, they can't join again with any invite until they're unbanned
+CREATE TABLE campaign_bans (
+	campaign_id INTEGER NOT NULL REFERENCES campaign (id) ON DELETE CASCADE,
+	user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	banned_by INTEGER REFERENCES users (id) ON DELETE SET NULL,
+	created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+	PRIMARY KEY (campaign_id, user_id)
+);
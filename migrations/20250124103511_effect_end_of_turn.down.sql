@@ -0,0 +1 @@
+ALTER TABLE effects DROP COLUMN ends_at_end_of_turn;
@@ -0,0 +1,3 @@
+-- Effects like "until the end of your next turn" end when `ends_on_turn_of`'s turn in round
+-- `ends_round` is over instead of when it starts
+ALTER TABLE effects ADD COLUMN ends_at_end_of_turn BOOLEAN NOT NULL DEFAULT false;
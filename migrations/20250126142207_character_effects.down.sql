@@ -0,0 +1,8 @@
+DELETE FROM effects WHERE combatant_id IS NULL;
+
+DROP INDEX effects_character;
+
+ALTER TABLE effects
+	DROP CONSTRAINT effects_target,
+	DROP COLUMN character_id,
+	ALTER COLUMN combatant_id SET NOT NULL;
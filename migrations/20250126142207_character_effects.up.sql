@@ -0,0 +1,14 @@
+-- Effects can be on a character outside of combat, e.g. poisoned or cursed until it's removed.
+-- Effects on a character's combatant also have its character_id, so the ones without a duration go
+-- back to the character when the encounter ends instead of staying behind with the combatant
+ALTER TABLE effects
+	ALTER COLUMN combatant_id DROP NOT NULL,
+	ADD COLUMN character_id INTEGER,
+	ADD CONSTRAINT fk_character FOREIGN KEY (character_id) REFERENCES characters (id) ON DELETE CASCADE,
+	ADD CONSTRAINT effects_target CHECK (combatant_id IS NOT NULL OR character_id IS NOT NULL);
+
+UPDATE effects f SET character_id = c.character_id
+FROM combatants c JOIN encounters e ON e.id = c.encounter_id
+WHERE c.id = f.combatant_id AND e.ended_at IS NULL;
+
+CREATE INDEX effects_character ON effects (character_id) WHERE combatant_id IS NULL;
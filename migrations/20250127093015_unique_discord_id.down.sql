@@ -0,0 +1 @@
+DROP INDEX session_discord_id;
@@ -0,0 +1,7 @@
+-- One user per discord account. Logins that raced each other could leave more than one session row
+-- for the same account, the oldest one is kept since that's the user most likely to have campaigns
+DELETE FROM session s
+USING session older
+WHERE older.discord_id = s.discord_id AND older.id < s.id;
+
+CREATE UNIQUE INDEX session_discord_id ON session (discord_id);
@@ -1,48 +1,135 @@
-use actix_web::{get, post, web};
-use serde::Deserialize;
+use actix_web::{delete, get, patch, post, web, HttpResponse};
+use serde::{Deserialize, Deserializer};
 
-use crate::{db, AppState};
+use crate::{
+    auth::AuthenticatedUser,
+    db::{self, JoinError},
+    error::ApiError,
+    permissions::{self, Permission},
+    AppState,
+};
 
 #[derive(Deserialize)]
 struct CreateCampaignBody {
     name: String,
 }
 
-#[post("/api/create/campaign")]
+#[derive(Deserialize)]
+struct UpdateCampaignBody {
+    name: Option<String>,
+    // missing leaves the image alone, null removes it
+    #[serde(default, deserialize_with = "present")]
+    image_link: Option<Option<String>>,
+}
+
+// Some for any value that's in the body, including null
+fn present<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Option<String>>, D::Error> {
+    Option::deserialize(d).map(Some)
+}
+
+fn validate_name(name: &str) -> Result<(), ApiError> {
+    if name.trim().is_empty() || name.chars().count() > 128 {
+        return Err(ApiError::BadRequest(String::from(
+            "Campaign name must be between 1 and 128 characters",
+        )));
+    }
+    Ok(())
+}
+
+fn not_found(e: sqlx::Error) -> ApiError {
+    match e {
+        sqlx::Error::RowNotFound => ApiError::NotFound(String::from("Campaign not found")),
+        e => e.into(),
+    }
+}
+
+#[post("/campaigns")]
 pub async fn create_campaign(
     data: web::Data<AppState>,
-    req: actix_web::HttpRequest,
+    user: AuthenticatedUser,
     body: web::Json<CreateCampaignBody>,
-) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    if let Some(header) = req.headers().get("Authorization") {
-        let access_token = &header.to_str().unwrap()[7..];
-
-        let res = db::create_dnd_campaign(&data.db_conn, access_token, &body.name)
-            .await
-            .map_err(|_| actix_web::error::ErrorForbidden("Failed to create session"))?;
+) -> Result<HttpResponse, ApiError> {
+    validate_name(&body.name)?;
 
-        return Ok(actix_web::HttpResponse::Ok().body(serde_json::to_string(&res).unwrap()));
-    }
+    let res = db::create_dnd_campaign(&data.db_conn, user.id, &body.name).await?;
 
-    Err(actix_web::error::ErrorForbidden("No token"))
+    Ok(HttpResponse::Created().json(res))
 }
 
-#[get("/api/get/campaigns")]
+#[get("/campaigns")]
 pub async fn get_campaigns(
     data: web::Data<AppState>,
-    req: actix_web::HttpRequest,
-) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    if let Some(header) = req.headers().get("Authorization") {
-        let access_token = &header.to_str().unwrap()[7..];
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let res = db::get_dnd_campaigns(&data.db_conn, user.id).await?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[get("/campaigns/{campaign_id}")]
+pub async fn get_campaign(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let res = db::get_dnd_campaign(&data.db_conn, user.id, path.into_inner())
+        .await
+        .map_err(not_found)?;
 
-        let res = db::get_dnd_campaigns(&data.db_conn, access_token)
-            .await
-            .map_err(|_| actix_web::error::ErrorForbidden("Failed to get campaigns"))?;
+    Ok(HttpResponse::Ok().json(res))
+}
 
-        return Ok(actix_web::HttpResponse::Ok().body(serde_json::to_string(&res).unwrap()));
+#[patch("/campaigns/{campaign_id}")]
+pub async fn update_campaign(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<UpdateCampaignBody>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(name) = &body.name {
+        validate_name(name)?;
     }
+    let campaign_id = path.into_inner();
+    permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::EditCampaign,
+    )
+    .await?;
+
+    let res = db::update_dnd_campaign(
+        &data.db_conn,
+        campaign_id,
+        body.name.as_deref(),
+        body.image_link.as_ref().map(Option::as_deref),
+    )
+    .await
+    .map_err(not_found)?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[delete("/campaigns/{campaign_id}")]
+pub async fn delete_campaign(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let campaign_id = path.into_inner();
+    permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::DeleteCampaign,
+    )
+    .await?;
 
-    Err(actix_web::error::ErrorForbidden("No token"))
+    db::delete_dnd_campaign(&data.db_conn, campaign_id)
+        .await
+        .map_err(not_found)?;
+
+    Ok(HttpResponse::NoContent().finish())
 }
 
 #[derive(Deserialize)]
@@ -50,26 +137,42 @@ struct JoinCampaignBody {
     invite: String,
 }
 
-#[post("/api/join/campaign")]
+#[post("/campaigns/join")]
 pub async fn join_campaign(
     data: web::Data<AppState>,
-    req: actix_web::HttpRequest,
+    user: AuthenticatedUser,
     body: web::Json<JoinCampaignBody>,
-) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    if let Some(header) = req.headers().get("Authorization") {
-        let access_token = &header.to_str().unwrap()[7..];
-
-        let res = db::join_dnd_campaign(&data.db_conn, access_token, &body.invite).await;
-        match res {
-            Ok(_) => return Ok(actix_web::HttpResponse::Ok().body("Joined campaign")),
-            Err(e) => {
-                return Err(actix_web::error::ErrorForbidden(format!(
-                    "Invite has no more uses or something else went wrong: {}",
-                    e
-                )))
-            }
-        };
-    };
-
-    Err(actix_web::error::ErrorForbidden("No token"))
+) -> Result<HttpResponse, JoinError> {
+    let res = db::join_dnd_campaign(&data.db_conn, user.id, &body.invite).await?;
+
+    // `joined` is from before it said which campaign
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "joined": true,
+        "campaign_id": res.campaign_id,
+        "role": res.role,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_link(body: serde_json::Value) -> Option<Option<String>> {
+        serde_json::from_value::<UpdateCampaignBody>(body)
+            .unwrap()
+            .image_link
+    }
+
+    #[test]
+    fn null_image_link_clears_it() {
+        assert_eq!(image_link(serde_json::json!({ "name": "a" })), None);
+        assert_eq!(
+            image_link(serde_json::json!({ "image_link": null })),
+            Some(None)
+        );
+        assert_eq!(
+            image_link(serde_json::json!({ "image_link": "https://example.com/a.png" })),
+            Some(Some(String::from("https://example.com/a.png")))
+        );
+    }
 }
@@ -0,0 +1,639 @@
+use std::collections::{BTreeSet, HashSet};
+
+use actix_web::{delete, get, patch, post, web, HttpResponse};
+use serde::Deserialize;
+
+use crate::{
+    api::encounters::{check_effect, MAX_EFFECTS},
+    auth::AuthenticatedUser,
+    db::{self, Character, CharacterFields, CharacterSummary, CharacterView, NewEffect},
+    error::ApiError,
+    hub::PublishToCampaign,
+    json_patch::{self, PatchOp},
+    permissions::{self, Permission},
+    protocol::ServerMessage,
+    rooms::{ConnectionId, SeenBy},
+    AppState,
+};
+
+const MAX_LIST_ENTRIES: usize = 100;
+const MAX_INVENTORY_ITEMS: usize = 500;
+const MAX_NOTES_LENGTH: usize = 20_000;
+const MAX_EXTRA_SIZE: usize = 64 * 1024;
+
+const MAX_PATCH_OPS: usize = 100;
+
+// An effect that lasts until it's removed, ones with a duration only work in an encounter
+#[derive(Deserialize)]
+struct EffectBody {
+    name: String,
+    source: Option<String>,
+}
+
+fn check_length(field: &str, value: &str, min: usize, max: usize) -> Result<(), ApiError> {
+    let length = value.trim().chars().count();
+    if length < min || value.chars().count() > max {
+        return Err(ApiError::BadRequest(format!(
+            "{} must be between {} and {} characters",
+            field, min, max
+        )));
+    }
+    Ok(())
+}
+
+fn check_range(field: &str, value: Option<i32>, min: i32, max: i32) -> Result<(), ApiError> {
+    if value.is_some_and(|value| !(min..=max).contains(&value)) {
+        return Err(ApiError::BadRequest(format!(
+            "{} must be between {} and {}",
+            field, min, max
+        )));
+    }
+    Ok(())
+}
+
+fn check_list(field: &str, values: &Option<Vec<String>>) -> Result<(), ApiError> {
+    let Some(values) = values else {
+        return Ok(());
+    };
+    if values.len() > MAX_LIST_ENTRIES {
+        return Err(ApiError::BadRequest(format!(
+            "{} can have at most {} entries",
+            field, MAX_LIST_ENTRIES
+        )));
+    }
+    for value in values {
+        check_length(field, value, 1, 64)?;
+    }
+    Ok(())
+}
+
+// Also used for websocket patches
+pub fn validate(fields: &CharacterFields) -> Result<(), ApiError> {
+    if let Some(name) = &fields.name {
+        check_length("name", name, 1, 128)?;
+    }
+    if let Some(class) = &fields.class {
+        check_length("class", class, 0, 64)?;
+    }
+    check_range("level", fields.level, 1, 30)?;
+    for (field, value) in [
+        ("strength", fields.strength),
+        ("dexterity", fields.dexterity),
+        ("constitution", fields.constitution),
+        ("intelligence", fields.intelligence),
+        ("wisdom", fields.wisdom),
+        ("charisma", fields.charisma),
+    ] {
+        check_range(field, value, 1, 30)?;
+    }
+    check_range("max_hp", fields.max_hp, 0, 10_000)?;
+    check_range("current_hp", fields.current_hp, -10_000, 10_000)?;
+    check_range("temp_hp", fields.temp_hp, 0, 10_000)?;
+    check_range("armor_class", fields.armor_class, 0, 100)?;
+    check_list("skills", &fields.skills)?;
+    check_list("proficiencies", &fields.proficiencies)?;
+
+    if let Some(inventory) = &fields.inventory {
+        if inventory.len() > MAX_INVENTORY_ITEMS {
+            return Err(ApiError::BadRequest(format!(
+                "inventory can have at most {} items",
+                MAX_INVENTORY_ITEMS
+            )));
+        }
+        for item in inventory {
+            check_length("Item name", &item.name, 1, 128)?;
+            check_range("Item quantity", Some(item.quantity), 0, 1_000_000)?;
+            if let Some(notes) = &item.notes {
+                check_length("Item notes", notes, 0, 1000)?;
+            }
+        }
+    }
+    if let Some(notes) = &fields.notes {
+        check_length("notes", notes, 0, MAX_NOTES_LENGTH)?;
+    }
+    if let Some(extra) = &fields.extra {
+        if !extra.is_object() {
+            return Err(ApiError::BadRequest(String::from(
+                "extra must be an object",
+            )));
+        }
+        if extra.to_string().len() > MAX_EXTRA_SIZE {
+            return Err(ApiError::BadRequest(format!(
+                "extra can be at most {} bytes",
+                MAX_EXTRA_SIZE
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn not_found(e: sqlx::Error) -> ApiError {
+    match e {
+        sqlx::Error::RowNotFound => ApiError::NotFound(String::from("Character not found")),
+        e => e.into(),
+    }
+}
+
+// Gets the character and makes sure the user can change it. Owners that are only spectating now
+// can't
+pub async fn get_editable_character(
+    data: &AppState,
+    user_id: i32,
+    character_id: i32,
+) -> Result<Character, ApiError> {
+    let character = db::get_character(&data.db_conn, user_id, character_id)
+        .await
+        .map_err(not_found)?;
+    if !character.editable {
+        return Err(ApiError::Forbidden(String::from(
+            "Only the owner and the DMs can change this character",
+        )));
+    }
+    permissions::require(
+        &data.db_conn,
+        user_id,
+        character.campaign_id,
+        Permission::PlayCharacters,
+    )
+    .await?;
+    Ok(character)
+}
+
+// Sends a created or changed character to every room of its campaign, as `event`. Whoever can edit
+// it gets the whole sheet (the sender's copy with its request_id) and everyone else the summary.
+// The character is already saved when this fails, the error says so
+pub async fn broadcast_character(
+    data: &AppState,
+    mut character: Character,
+    event: fn(CharacterView) -> ServerMessage,
+    sender: Option<(ConnectionId, Option<String>)>,
+) -> Result<(), ApiError> {
+    let editors: HashSet<String> =
+        match db::get_character_editors(&data.db_conn, character.campaign_id, character.owner_id)
+            .await
+        {
+            Ok(editors) => editors.into_iter().collect(),
+            Err(err) => {
+                log::error!(
+                    "Failed to get editors of character {}: {}",
+                    character.id,
+                    err
+                );
+                return Err(ApiError::Internal(format!(
+                "The character was saved at version {} but couldn't be sent out, fetch it again",
+                character.version
+            )));
+            }
+        };
+
+    data.hub.do_send(PublishToCampaign {
+        campaign_id: character.campaign_id,
+        message: event(CharacterView::Summary(CharacterSummary::from(&character))),
+        seen_by: SeenBy::Except(editors.clone()),
+        sender: None,
+    });
+    character.editable = true;
+    data.hub.do_send(PublishToCampaign {
+        campaign_id: character.campaign_id,
+        message: event(CharacterView::from(character)),
+        seen_by: SeenBy::Users(editors),
+        sender,
+    });
+    Ok(())
+}
+
+// Sends the character's effects outside of combat to every room of its campaign
+pub async fn publish_effects(data: &AppState, campaign_id: i32, character_id: i32) {
+    match db::get_character_effects(&data.db_conn, character_id).await {
+        Ok(effects) => data.hub.do_send(PublishToCampaign {
+            campaign_id,
+            message: ServerMessage::CharacterEffects {
+                character_id,
+                effects,
+            },
+            seen_by: SeenBy::Everyone,
+            sender: None,
+        }),
+        Err(err) => log::error!(
+            "Failed to get effects of character {}: {}",
+            character_id,
+            err
+        ),
+    }
+}
+
+fn conflict() -> ApiError {
+    ApiError::Conflict(String::from("The character was changed since that version"))
+}
+
+// The character's fields with the patch applied, if it's still at `version`
+fn patched_fields(
+    character: &Character,
+    version: i32,
+    patch: &[PatchOp],
+) -> Result<CharacterFields, ApiError> {
+    if patch.len() > MAX_PATCH_OPS {
+        return Err(ApiError::BadRequest(format!(
+            "A patch can have at most {} ops",
+            MAX_PATCH_OPS
+        )));
+    }
+    if character.version != version {
+        return Err(conflict());
+    }
+
+    // patching from an empty inventory would throw away whatever was stored
+    let fields = CharacterFields::try_from(character).map_err(|e| {
+        ApiError::BadRequest(format!(
+            "The character's inventory is invalid, replace it before patching: {}",
+            e
+        ))
+    })?;
+    let mut doc = serde_json::to_value(fields).unwrap();
+    let keys = |doc: &serde_json::Value| -> BTreeSet<String> {
+        doc.as_object()
+            .map(|fields| fields.keys().cloned().collect())
+            .unwrap_or_default()
+    };
+    let before = keys(&doc);
+    // a patch can grow the sheet by as much as extra can hold, validate catches the rest
+    let max_size = doc.to_string().len() + MAX_EXTRA_SIZE;
+    json_patch::apply(&mut doc, patch, max_size)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    // adding or removing top level fields doesn't mean anything
+    if keys(&doc) != before {
+        return Err(ApiError::BadRequest(String::from(
+            "A patch can only change the character's fields, not add or remove them",
+        )));
+    }
+
+    // every field of a sheet is set, and null would read as leaving it alone
+    if let Some((field, _)) = doc
+        .as_object()
+        .and_then(|fields| fields.iter().find(|(_, value)| value.is_null()))
+    {
+        return Err(ApiError::BadRequest(format!("{} can't be null", field)));
+    }
+
+    let fields: CharacterFields =
+        serde_json::from_value(doc).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    validate(&fields)?;
+    Ok(fields)
+}
+
+// Applies a websocket PatchCharacter. Only works if the character is still at `version`, and it has
+// to be in `campaign_id` since that's the room the patch came from
+pub async fn patch_character(
+    data: &AppState,
+    user_id: i32,
+    campaign_id: i32,
+    character_id: i32,
+    version: i32,
+    patch: &[PatchOp],
+) -> Result<Character, ApiError> {
+    let character = get_editable_character(data, user_id, character_id).await?;
+    if character.campaign_id != campaign_id {
+        return Err(ApiError::NotFound(String::from("Character not found")));
+    }
+    let fields = patched_fields(&character, version, patch)?;
+
+    db::update_character(&data.db_conn, character_id, &fields, Some(version))
+        .await
+        .map_err(|e| match e {
+            // deleted or changed in the meantime
+            sqlx::Error::RowNotFound => conflict(),
+            e => e.into(),
+        })
+}
+
+#[get("/campaigns/{campaign_id}/characters")]
+pub async fn get_characters(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let campaign_id = path.into_inner();
+    permissions::require(&data.db_conn, user.id, campaign_id, Permission::View).await?;
+
+    let characters = db::get_characters(&data.db_conn, user.id, campaign_id).await?;
+    let res: Vec<CharacterView> = characters.into_iter().map(CharacterView::from).collect();
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[post("/campaigns/{campaign_id}/characters")]
+pub async fn create_character(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<CharacterFields>,
+) -> Result<HttpResponse, ApiError> {
+    let campaign_id = path.into_inner();
+    if body.name.is_none() {
+        return Err(ApiError::BadRequest(String::from("name is required")));
+    }
+    validate(&body)?;
+    permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::PlayCharacters,
+    )
+    .await?;
+
+    let character = db::create_character(&data.db_conn, user.id, campaign_id, &body).await?;
+    // the response has the character either way, a failed broadcast is only logged
+    let _ = broadcast_character(
+        &data,
+        character.clone(),
+        ServerMessage::CharacterCreated,
+        None,
+    )
+    .await;
+    Ok(HttpResponse::Created().json(character))
+}
+
+#[get("/characters/{character_id}")]
+pub async fn get_character(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let character = db::get_character(&data.db_conn, user.id, path.into_inner())
+        .await
+        .map_err(not_found)?;
+    Ok(HttpResponse::Ok().json(CharacterView::from(character)))
+}
+
+#[patch("/characters/{character_id}")]
+pub async fn update_character(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<CharacterFields>,
+) -> Result<HttpResponse, ApiError> {
+    validate(&body)?;
+    let character = get_editable_character(&data, user.id, path.into_inner()).await?;
+
+    let character = db::update_character(&data.db_conn, character.id, &body, None)
+        .await
+        .map_err(not_found)?;
+    // the response has the character either way, a failed broadcast is only logged
+    let _ = broadcast_character(
+        &data,
+        character.clone(),
+        ServerMessage::CharacterUpdated,
+        None,
+    )
+    .await;
+    Ok(HttpResponse::Ok().json(character))
+}
+
+#[delete("/characters/{character_id}")]
+pub async fn delete_character(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let character = get_editable_character(&data, user.id, path.into_inner()).await?;
+
+    db::delete_character(&data.db_conn, character.id)
+        .await
+        .map_err(not_found)?;
+    data.hub.do_send(PublishToCampaign {
+        campaign_id: character.campaign_id,
+        message: ServerMessage::CharacterDeleted { id: character.id },
+        seen_by: SeenBy::Everyone,
+        sender: None,
+    });
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// While the character is in an encounter its effects are on its combatant instead
+#[get("/characters/{character_id}/effects")]
+pub async fn get_effects(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let character = db::get_character(&data.db_conn, user.id, path.into_inner())
+        .await
+        .map_err(not_found)?;
+
+    let effects = db::get_character_effects(&data.db_conn, character.id).await?;
+    Ok(HttpResponse::Ok().json(effects))
+}
+
+fn check_not_in_encounter(in_encounter: bool) -> Result<(), ApiError> {
+    if in_encounter {
+        return Err(ApiError::Conflict(String::from(
+            "The character is in an encounter, change the effects on its combatant",
+        )));
+    }
+    Ok(())
+}
+
+#[post("/characters/{character_id}/effects")]
+pub async fn add_effect(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<EffectBody>,
+) -> Result<HttpResponse, ApiError> {
+    check_effect(&body.name, body.source.as_deref())?;
+    let character = get_editable_character(&data, user.id, path.into_inner()).await?;
+    check_not_in_encounter(db::character_in_encounter(&data.db_conn, character.id).await?)?;
+    if db::get_character_effects(&data.db_conn, character.id)
+        .await?
+        .len()
+        >= MAX_EFFECTS
+    {
+        return Err(ApiError::BadRequest(format!(
+            "A character can have at most {} effects",
+            MAX_EFFECTS
+        )));
+    }
+
+    let body = body.into_inner();
+    let effect = db::add_effect(
+        &mut *data.db_conn.acquire().await?,
+        NewEffect {
+            combatant_id: None,
+            character_id: Some(character.id),
+            name: body.name.trim().to_string(),
+            source: body.source.map(|source| source.trim().to_string()),
+            source_combatant_id: None,
+            duration_rounds: None,
+            ends_round: None,
+            ends_on_turn_of: None,
+            ends_at_end_of_turn: false,
+        },
+    )
+    .await?;
+    publish_effects(&data, character.campaign_id, character.id).await;
+    Ok(HttpResponse::Created().json(effect))
+}
+
+#[delete("/characters/{character_id}/effects/{effect_id}")]
+pub async fn remove_effect(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (character_id, effect_id) = path.into_inner();
+    let character = get_editable_character(&data, user.id, character_id).await?;
+    check_not_in_encounter(db::character_in_encounter(&data.db_conn, character.id).await?)?;
+
+    db::delete_character_effect(&data.db_conn, character.id, effect_id)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => ApiError::NotFound(String::from("Effect not found")),
+            e => e.into(),
+        })?;
+    publish_effects(&data, character.campaign_id, character.id).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn character() -> Character {
+        let now = chrono::Utc::now().naive_utc();
+        Character {
+            id: 1,
+            campaign_id: 1,
+            owner_id: 1,
+            name: String::from("Vex"),
+            class: String::from("Ranger"),
+            level: 3,
+            strength: 10,
+            dexterity: 16,
+            constitution: 12,
+            intelligence: 10,
+            wisdom: 14,
+            charisma: 8,
+            max_hp: 24,
+            current_hp: 24,
+            temp_hp: 0,
+            armor_class: 14,
+            skills: vec![String::from("stealth")],
+            proficiencies: Vec::new(),
+            inventory: serde_json::json!([]),
+            notes: String::new(),
+            extra: serde_json::json!({}),
+            created_at: now,
+            last_updated: now,
+            version: 4,
+            editable: true,
+        }
+    }
+
+    fn patch(value: serde_json::Value) -> Vec<PatchOp> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn applies_at_the_current_version() {
+        let ops = patch(serde_json::json!([
+            { "op": "replace", "path": "/current_hp", "value": 7 },
+            { "op": "add", "path": "/skills/-", "value": "perception" },
+        ]));
+        let fields = patched_fields(&character(), 4, &ops).unwrap();
+        assert_eq!(fields.current_hp, Some(7));
+        assert_eq!(
+            fields.skills,
+            Some(vec![String::from("stealth"), String::from("perception")])
+        );
+    }
+
+    #[test]
+    fn rejects_a_stale_version() {
+        let ops = patch(serde_json::json!([
+            { "op": "replace", "path": "/current_hp", "value": 7 },
+        ]));
+        for version in [3, 5] {
+            assert!(matches!(
+                patched_fields(&character(), version, &ops),
+                Err(ApiError::Conflict(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_adding_or_removing_fields() {
+        for ops in [
+            serde_json::json!([{ "op": "add", "path": "/speed", "value": 30 }]),
+            serde_json::json!([{ "op": "remove", "path": "/notes" }]),
+        ] {
+            assert!(matches!(
+                patched_fields(&character(), 4, &patch(ops)),
+                Err(ApiError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_nulling_a_field() {
+        for ops in [
+            serde_json::json!([{ "op": "replace", "path": "/notes", "value": null }]),
+            serde_json::json!([{ "op": "add", "path": "/current_hp", "value": null }]),
+        ] {
+            assert!(matches!(
+                patched_fields(&character(), 4, &patch(ops)),
+                Err(ApiError::BadRequest(_))
+            ));
+        }
+
+        // nulls inside a field are up to the field
+        let ops = patch(serde_json::json!([
+            { "op": "add", "path": "/extra/speed", "value": null },
+        ]));
+        let fields = patched_fields(&character(), 4, &ops).unwrap();
+        assert_eq!(fields.extra, Some(serde_json::json!({ "speed": null })));
+    }
+
+    #[test]
+    fn rejects_a_broken_stored_inventory() {
+        let mut character = character();
+        character.inventory = serde_json::json!([{ "name": 3 }]);
+        let ops = patch(serde_json::json!([
+            { "op": "replace", "path": "/current_hp", "value": 7 },
+        ]));
+        assert!(matches!(
+            patched_fields(&character, 4, &ops),
+            Err(ApiError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn validates_the_result() {
+        let ops = patch(serde_json::json!([
+            { "op": "replace", "path": "/level", "value": 0 },
+        ]));
+        assert!(matches!(
+            patched_fields(&character(), 4, &ops),
+            Err(ApiError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_a_patch_that_keeps_doubling_the_sheet() {
+        let ops: Vec<serde_json::Value> = (0..MAX_PATCH_OPS)
+            .map(|i| serde_json::json!({ "op": "copy", "from": "/extra", "path": format!("/extra/k{}", i) }))
+            .collect();
+        assert!(matches!(
+            patched_fields(&character(), 4, &patch(serde_json::Value::Array(ops))),
+            Err(ApiError::BadRequest(_))
+        ));
+
+        let mut character = character();
+        character.extra = serde_json::json!({ "k0": "x".repeat(1000) });
+        let ops: Vec<serde_json::Value> = (1..MAX_PATCH_OPS)
+            .map(|i| serde_json::json!({ "op": "copy", "from": format!("/extra/k{}", i - 1), "path": format!("/extra/k{}", i) }))
+            .collect();
+        assert!(matches!(
+            patched_fields(&character, 4, &patch(serde_json::Value::Array(ops))),
+            Err(ApiError::BadRequest(_))
+        ));
+    }
+}
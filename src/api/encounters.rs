@@ -0,0 +1,656 @@
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::Deserialize;
+use sqlx::PgConnection;
+
+use crate::{
+    api::characters,
+    auth::AuthenticatedUser,
+    combat::{self, Duration},
+    db::{self, CampaignRole, Effect, Encounter, NewCombatant, NewEffect},
+    dice,
+    error::ApiError,
+    hub::PublishToCampaign,
+    permissions::{self, Permission},
+    protocol::ServerMessage,
+    rooms::SeenBy,
+    AppState,
+};
+
+const MAX_COMBATANTS: usize = 100;
+const MAX_TRIGGER_LENGTH: usize = 256;
+pub const MAX_EFFECTS: usize = 50;
+
+#[derive(Deserialize)]
+struct EncounterBody {
+    #[serde(default)]
+    name: String,
+}
+
+// Either a character from the campaign or a monster with a name. Without an initiative it's
+// rolled, 1d20 plus the bonus (or the character's dexterity modifier)
+#[derive(Deserialize)]
+struct CombatantBody {
+    character_id: Option<i32>,
+    name: Option<String>,
+    initiative: Option<i32>,
+    initiative_bonus: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct ReadyBody {
+    trigger: String,
+}
+
+// Without a duration the effect lasts until it's removed
+#[derive(Deserialize)]
+struct EffectBody {
+    name: String,
+    source: Option<String>,
+    source_combatant_id: Option<i32>,
+    duration: Option<Duration>,
+    // "until the end of its next turn" instead of the start
+    #[serde(default)]
+    ends_at_end_of_turn: bool,
+}
+
+// Also used for effects on characters outside of combat
+pub fn check_effect(name: &str, source: Option<&str>) -> Result<(), ApiError> {
+    let length = name.trim().chars().count();
+    if length == 0 || name.chars().count() > 64 {
+        return Err(ApiError::BadRequest(String::from(
+            "name must be between 1 and 64 characters",
+        )));
+    }
+    if source.is_some_and(|source| source.chars().count() > 128) {
+        return Err(ApiError::BadRequest(String::from(
+            "source can be at most 128 characters",
+        )));
+    }
+    Ok(())
+}
+
+fn check_name(name: &str, min: usize) -> Result<(), ApiError> {
+    let length = name.trim().chars().count();
+    if length < min || name.chars().count() > 128 {
+        return Err(ApiError::BadRequest(format!(
+            "Name must be between {} and 128 characters",
+            min
+        )));
+    }
+    Ok(())
+}
+
+fn not_found(e: sqlx::Error) -> ApiError {
+    match e {
+        sqlx::Error::RowNotFound => ApiError::NotFound(String::from("Encounter not found")),
+        e => e.into(),
+    }
+}
+
+fn publish(data: &AppState, encounter: &Encounter, message: ServerMessage) {
+    data.hub.do_send(PublishToCampaign {
+        campaign_id: encounter.campaign_id,
+        message,
+        seen_by: SeenBy::Everyone,
+        sender: None,
+    });
+}
+
+// Sends the changed encounter, then the effects that ran out and, if a new turn started, a
+// reminder of what's on whoever's turn it is now. `turn` is the (round, current combatant) from
+// before the change
+fn publish_update(
+    data: &AppState,
+    encounter: &Encounter,
+    turn: (i32, Option<i32>),
+    expired: Vec<Effect>,
+) {
+    publish(
+        data,
+        encounter,
+        ServerMessage::EncounterUpdated(encounter.clone()),
+    );
+    if !expired.is_empty() {
+        publish(
+            data,
+            encounter,
+            ServerMessage::EffectsExpired {
+                encounter_id: encounter.id,
+                effects: expired,
+            },
+        );
+    }
+
+    if let Some(current) = combat::reminder(encounter, turn) {
+        publish(
+            data,
+            encounter,
+            ServerMessage::EffectReminder {
+                encounter_id: encounter.id,
+                round: encounter.round,
+                combatant_id: current.id,
+                effects: current.effects.clone(),
+            },
+        );
+    }
+}
+
+// The user's role in the encounter's campaign, they can't see the encounter if they aren't in it
+async fn campaign_role(
+    data: &AppState,
+    user_id: i32,
+    encounter: &Encounter,
+) -> Result<CampaignRole, ApiError> {
+    db::get_campaign_role(&data.db_conn, user_id, encounter.campaign_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(String::from("Encounter not found")))
+}
+
+// Who's changing an encounter, DMs can do anything and players can act for their own combatants
+struct Actor {
+    user_id: i32,
+    role: CampaignRole,
+}
+
+impl Actor {
+    fn is_dm(&self) -> bool {
+        self.role.is_dm()
+    }
+
+    fn check_controls(&self, encounter: &Encounter, combatant_id: i32) -> Result<(), ApiError> {
+        let combatant = encounter
+            .combatants
+            .iter()
+            .find(|c| c.id == combatant_id)
+            .ok_or_else(|| ApiError::NotFound(String::from("Combatant not found")))?;
+        let plays =
+            combatant.owner_id == Some(self.user_id) && self.role.can(Permission::PlayCharacters);
+        if !self.is_dm() && !plays {
+            return Err(ApiError::Forbidden(format!(
+                "Only the DMs and {}'s owner can do that",
+                combatant.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Locks an encounter that's still going on for the rest of the transaction
+async fn lock_encounter(
+    data: &AppState,
+    conn: &mut PgConnection,
+    user_id: i32,
+    encounter_id: i32,
+) -> Result<(Encounter, Actor), ApiError> {
+    let encounter = db::lock_encounter(conn, encounter_id)
+        .await
+        .map_err(not_found)?;
+    let role = campaign_role(data, user_id, &encounter).await?;
+    if encounter.ended_at.is_some() {
+        return Err(ApiError::Conflict(String::from("The encounter is over")));
+    }
+
+    Ok((encounter, Actor { user_id, role }))
+}
+
+// Locks the encounter, lets `change` update it and saves it. `change` returns the effects that ran
+// out, everyone in the campaign gets the encounter as saved
+async fn change_encounter(
+    data: &AppState,
+    user_id: i32,
+    encounter_id: i32,
+    change: impl FnOnce(&mut Encounter, &Actor) -> Result<Vec<Effect>, ApiError>,
+) -> Result<Encounter, ApiError> {
+    let mut tx = data.db_conn.begin().await?;
+    let (mut encounter, actor) = lock_encounter(data, &mut tx, user_id, encounter_id).await?;
+    let turn = (encounter.round, encounter.current_combatant_id);
+    let expired = change(&mut encounter, &actor)?;
+    let encounter = db::save_encounter(&mut tx, &encounter).await?;
+    tx.commit().await?;
+
+    publish_update(data, &encounter, turn, expired);
+    Ok(encounter)
+}
+
+async fn get_visible_encounter(
+    data: &AppState,
+    user_id: i32,
+    encounter_id: i32,
+) -> Result<Encounter, ApiError> {
+    let mut conn = data.db_conn.acquire().await?;
+    let encounter = db::get_encounter(&mut conn, encounter_id)
+        .await
+        .map_err(not_found)?;
+    campaign_role(data, user_id, &encounter).await?;
+    Ok(encounter)
+}
+
+// The encounter going on in the session right now
+#[get("/sessions/{session_id}/encounter")]
+pub async fn get_active_encounter(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let session_id = path.into_inner();
+    db::get_dnd_session(&data.db_conn, user.id, session_id)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => ApiError::NotFound(String::from("Session not found")),
+            e => e.into(),
+        })?;
+
+    let encounter_id = db::get_active_encounter_id(&data.db_conn, session_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(String::from("No encounter going on")))?;
+    let encounter = get_visible_encounter(&data, user.id, encounter_id).await?;
+    Ok(HttpResponse::Ok().json(encounter))
+}
+
+#[post("/sessions/{session_id}/encounter")]
+pub async fn start_encounter(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<EncounterBody>,
+) -> Result<HttpResponse, ApiError> {
+    check_name(&body.name, 0)?;
+    let session = db::get_dnd_session(&data.db_conn, user.id, path.into_inner())
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => ApiError::NotFound(String::from("Session not found")),
+            e => e.into(),
+        })?;
+    permissions::require(&data.db_conn, user.id, session.campaign_id, Permission::Dm).await?;
+
+    let encounter = db::create_encounter(&data.db_conn, session.id, body.name.trim())
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(ref err) if err.is_unique_violation() => ApiError::Conflict(
+                String::from("There's already an encounter going on in this session"),
+            ),
+            e => e.into(),
+        })?;
+    publish(
+        &data,
+        &encounter,
+        ServerMessage::EncounterStarted(encounter.clone()),
+    );
+    Ok(HttpResponse::Created().json(encounter))
+}
+
+#[get("/encounters/{encounter_id}")]
+pub async fn get_encounter(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let encounter = get_visible_encounter(&data, user.id, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(encounter))
+}
+
+#[post("/encounters/{encounter_id}/end")]
+pub async fn end_encounter(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let mut tx = data.db_conn.begin().await?;
+    let (encounter, actor) = lock_encounter(&data, &mut tx, user.id, path.into_inner()).await?;
+    if !actor.is_dm() {
+        return Err(permissions::forbidden(Permission::Dm));
+    }
+
+    let combatant_ids: Vec<i32> = encounter.combatants.iter().map(|c| c.id).collect();
+    let released = db::release_character_effects(&mut tx, &combatant_ids).await?;
+    let encounter = db::end_encounter(&mut tx, encounter.id).await?;
+    tx.commit().await?;
+    publish(
+        &data,
+        &encounter,
+        ServerMessage::EncounterEnded(encounter.clone()),
+    );
+    for character_id in released {
+        characters::publish_effects(&data, encounter.campaign_id, character_id).await;
+    }
+    Ok(HttpResponse::Ok().json(encounter))
+}
+
+#[post("/encounters/{encounter_id}/combatants")]
+pub async fn add_combatant(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<CombatantBody>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(name) = &body.name {
+        check_name(name, 1)?;
+    }
+    if body
+        .initiative_bonus
+        .is_some_and(|bonus| !(-50..=50).contains(&bonus))
+    {
+        return Err(ApiError::BadRequest(String::from(
+            "initiative_bonus must be between -50 and 50",
+        )));
+    }
+    if body
+        .initiative
+        .is_some_and(|initiative| !(-100..=100).contains(&initiative))
+    {
+        return Err(ApiError::BadRequest(String::from(
+            "initiative must be between -100 and 100",
+        )));
+    }
+
+    let mut tx = data.db_conn.begin().await?;
+    let (mut encounter, actor) = lock_encounter(&data, &mut tx, user.id, path.into_inner()).await?;
+    if encounter.combatants.len() >= MAX_COMBATANTS {
+        return Err(ApiError::BadRequest(format!(
+            "An encounter can have at most {} combatants",
+            MAX_COMBATANTS
+        )));
+    }
+
+    // players can only bring their own characters, monsters are up to the DMs
+    let (character, name) = match body.character_id {
+        Some(character_id) => {
+            let character_not_found = || ApiError::NotFound(String::from("Character not found"));
+            let character = db::get_character(&data.db_conn, user.id, character_id)
+                .await
+                .map_err(|e| match e {
+                    sqlx::Error::RowNotFound => character_not_found(),
+                    e => e.into(),
+                })?;
+            if character.campaign_id != encounter.campaign_id {
+                return Err(character_not_found());
+            }
+            if !character.editable {
+                return Err(ApiError::Forbidden(String::from(
+                    "Only the owner and the DMs can add this character",
+                )));
+            }
+            // same as get_editable_character, an owner who got demoted to spectator can't play it
+            if !actor.role.can(Permission::PlayCharacters) {
+                return Err(permissions::forbidden(Permission::PlayCharacters));
+            }
+            let name = body.name.clone().unwrap_or_else(|| character.name.clone());
+            (Some(character), name)
+        }
+        None if !actor.is_dm() => return Err(permissions::forbidden(Permission::Dm)),
+        None => match &body.name {
+            Some(name) => (None, name.clone()),
+            None => return Err(ApiError::BadRequest(String::from("A monster needs a name"))),
+        },
+    };
+
+    let (initiative, initiative_roll) = match body.initiative {
+        Some(initiative) => (initiative, None),
+        None => {
+            let bonus = body.initiative_bonus.unwrap_or_else(|| {
+                character
+                    .as_ref()
+                    .map_or(0, |c| (c.dexterity - 10).div_euclid(2))
+            });
+            let roll = dice::parse(&format!("1d20{:+}", bonus))
+                .map_err(|e| ApiError::BadRequest(e.to_string()))?
+                .roll();
+            (roll.total as i32, Some(roll))
+        }
+    };
+
+    let combatant = db::add_combatant(
+        &mut tx,
+        encounter.id,
+        NewCombatant {
+            character_id: character.as_ref().map(|c| c.id),
+            owner_id: character.as_ref().map(|c| c.owner_id),
+            name: name.trim().to_string(),
+            initiative,
+            initiative_roll,
+        },
+    )
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(ref err) if err.is_unique_violation() => {
+            ApiError::Conflict(String::from("That character is already in the encounter"))
+        }
+        e => e.into(),
+    })?;
+    // its effects moved from the character to the combatant
+    let took_effects = !combatant.effects.is_empty();
+    combat::add(&mut encounter, combatant);
+    let encounter = db::save_encounter(&mut tx, &encounter).await?;
+    tx.commit().await?;
+
+    publish(
+        &data,
+        &encounter,
+        ServerMessage::EncounterUpdated(encounter.clone()),
+    );
+    if let (Some(character), true) = (character, took_effects) {
+        characters::publish_effects(&data, encounter.campaign_id, character.id).await;
+    }
+    Ok(HttpResponse::Created().json(encounter))
+}
+
+#[delete("/encounters/{encounter_id}/combatants/{combatant_id}")]
+pub async fn remove_combatant(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (encounter_id, combatant_id) = path.into_inner();
+    let mut tx = data.db_conn.begin().await?;
+    let (mut encounter, actor) = lock_encounter(&data, &mut tx, user.id, encounter_id).await?;
+    actor.check_controls(&encounter, combatant_id)?;
+
+    let turn = (encounter.round, encounter.current_combatant_id);
+    let expired = combat::remove(&mut encounter, combatant_id)?;
+    let released = db::release_character_effects(&mut tx, &[combatant_id]).await?;
+    db::delete_combatant(&mut tx, combatant_id).await?;
+    let encounter = db::save_encounter(&mut tx, &encounter).await?;
+    tx.commit().await?;
+
+    publish_update(&data, &encounter, turn, expired);
+    for character_id in released {
+        characters::publish_effects(&data, encounter.campaign_id, character_id).await;
+    }
+    Ok(HttpResponse::Ok().json(encounter))
+}
+
+// Ends the current turn. The DMs start the first one, after that it's also up to whoever's turn it is
+#[post("/encounters/{encounter_id}/next")]
+pub async fn next_turn(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let encounter = change_encounter(&data, user.id, path.into_inner(), |encounter, actor| {
+        match encounter.current_combatant_id {
+            Some(current) => actor.check_controls(encounter, current)?,
+            None if !actor.is_dm() => return Err(permissions::forbidden(Permission::Dm)),
+            None => {}
+        }
+        Ok(combat::next_turn(encounter)?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(encounter))
+}
+
+#[post("/encounters/{encounter_id}/combatants/{combatant_id}/delay")]
+pub async fn delay(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (encounter_id, combatant_id) = path.into_inner();
+    let encounter = change_encounter(&data, user.id, encounter_id, |encounter, actor| {
+        actor.check_controls(encounter, combatant_id)?;
+        Ok(combat::delay(encounter, combatant_id)?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(encounter))
+}
+
+// A delayed combatant steps back in and takes its turn now
+#[post("/encounters/{encounter_id}/combatants/{combatant_id}/undelay")]
+pub async fn undelay(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (encounter_id, combatant_id) = path.into_inner();
+    let encounter = change_encounter(&data, user.id, encounter_id, |encounter, actor| {
+        actor.check_controls(encounter, combatant_id)?;
+        Ok(combat::undelay(encounter, combatant_id)?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(encounter))
+}
+
+#[post("/encounters/{encounter_id}/combatants/{combatant_id}/ready")]
+pub async fn ready(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+    body: web::Json<ReadyBody>,
+) -> Result<HttpResponse, ApiError> {
+    let length = body.trigger.trim().chars().count();
+    if length == 0 || body.trigger.chars().count() > MAX_TRIGGER_LENGTH {
+        return Err(ApiError::BadRequest(format!(
+            "trigger must be between 1 and {} characters",
+            MAX_TRIGGER_LENGTH
+        )));
+    }
+
+    let (encounter_id, combatant_id) = path.into_inner();
+    let trigger = body.into_inner().trigger.trim().to_string();
+    let encounter = change_encounter(&data, user.id, encounter_id, |encounter, actor| {
+        actor.check_controls(encounter, combatant_id)?;
+        Ok(combat::ready(encounter, combatant_id, trigger)?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(encounter))
+}
+
+// The readied action was used
+#[post("/encounters/{encounter_id}/combatants/{combatant_id}/trigger")]
+pub async fn trigger_readied(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (encounter_id, combatant_id) = path.into_inner();
+    let encounter = change_encounter(&data, user.id, encounter_id, |encounter, actor| {
+        actor.check_controls(encounter, combatant_id)?;
+        combat::trigger(encounter, combatant_id)?;
+        Ok(Vec::new())
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(encounter))
+}
+
+#[post("/encounters/{encounter_id}/combatants/{combatant_id}/effects")]
+pub async fn add_effect(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+    body: web::Json<EffectBody>,
+) -> Result<HttpResponse, ApiError> {
+    check_effect(&body.name, body.source.as_deref())?;
+    let duration_rounds = match body.duration {
+        Some(duration @ Duration::Rounds(1..=1000))
+        | Some(duration @ Duration::Minutes(1..=100)) => Some(duration.rounds()),
+        Some(_) => {
+            return Err(ApiError::BadRequest(String::from(
+                "duration must be between 1 and 1000 rounds or 1 and 100 minutes",
+            )))
+        }
+        None => None,
+    };
+
+    let (encounter_id, combatant_id) = path.into_inner();
+    let mut tx = data.db_conn.begin().await?;
+    let (mut encounter, actor) = lock_encounter(&data, &mut tx, user.id, encounter_id).await?;
+    // whoever caused it can put it on someone else
+    match body.source_combatant_id {
+        Some(source) if actor.check_controls(&encounter, source).is_ok() => {}
+        _ => actor.check_controls(&encounter, combatant_id)?,
+    }
+    if let Some(source) = body.source_combatant_id {
+        if !encounter.combatants.iter().any(|c| c.id == source) {
+            return Err(ApiError::BadRequest(String::from(
+                "source_combatant_id isn't in the encounter",
+            )));
+        }
+    }
+    let target = encounter
+        .combatants
+        .iter()
+        .find(|c| c.id == combatant_id)
+        .ok_or_else(|| ApiError::NotFound(String::from("Combatant not found")))?;
+    if target.effects.len() >= MAX_EFFECTS {
+        return Err(ApiError::BadRequest(format!(
+            "A combatant can have at most {} effects",
+            MAX_EFFECTS
+        )));
+    }
+
+    let (ends_round, ends_on_turn_of) = match duration_rounds {
+        Some(rounds) => {
+            let (ends_round, ends_on_turn_of) = combat::effect_end(&encounter, rounds);
+            (Some(ends_round), ends_on_turn_of)
+        }
+        None => (None, None),
+    };
+    let character_id = target.character_id;
+    let body = body.into_inner();
+    let effect = db::add_effect(
+        &mut tx,
+        NewEffect {
+            combatant_id: Some(combatant_id),
+            character_id,
+            name: body.name.trim().to_string(),
+            source: body.source.map(|source| source.trim().to_string()),
+            source_combatant_id: body.source_combatant_id,
+            duration_rounds,
+            ends_round,
+            ends_on_turn_of,
+            ends_at_end_of_turn: body.ends_at_end_of_turn && ends_on_turn_of.is_some(),
+        },
+    )
+    .await?;
+    let turn = (encounter.round, encounter.current_combatant_id);
+    combat::add_effect(&mut encounter, effect)?;
+    let encounter = db::save_encounter(&mut tx, &encounter).await?;
+    tx.commit().await?;
+
+    publish_update(&data, &encounter, turn, Vec::new());
+    Ok(HttpResponse::Created().json(encounter))
+}
+
+#[delete("/encounters/{encounter_id}/combatants/{combatant_id}/effects/{effect_id}")]
+pub async fn remove_effect(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (encounter_id, combatant_id, effect_id) = path.into_inner();
+    let encounter = change_encounter(&data, user.id, encounter_id, |encounter, actor| {
+        let source = encounter
+            .combatants
+            .iter()
+            .flat_map(|c| &c.effects)
+            .find(|effect| effect.id == effect_id)
+            .and_then(|effect| effect.source_combatant_id);
+        match source {
+            Some(source) if actor.check_controls(encounter, source).is_ok() => {}
+            _ => actor.check_controls(encounter, combatant_id)?,
+        }
+        combat::remove_effect(encounter, combatant_id, effect_id)?;
+        Ok(Vec::new())
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(encounter))
+}
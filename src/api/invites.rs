@@ -0,0 +1,171 @@
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::Deserialize;
+
+use crate::{
+    auth::AuthenticatedUser,
+    db::{self, CampaignRole},
+    error::ApiError,
+    permissions::{self, can_invite_as, Permission},
+    AppState,
+};
+
+#[derive(Deserialize)]
+struct CreateInviteBody {
+    #[serde(default = "default_role")]
+    role: CampaignRole,
+    // no limit when left out
+    max_uses: Option<i32>,
+    expires_at: Option<chrono::NaiveDateTime>,
+}
+
+fn default_role() -> CampaignRole {
+    CampaignRole::Player
+}
+
+fn not_found(e: sqlx::Error) -> ApiError {
+    match e {
+        sqlx::Error::RowNotFound => ApiError::NotFound(String::from("Invite not found")),
+        e => e.into(),
+    }
+}
+
+fn check_role(actor: CampaignRole, role: CampaignRole) -> Result<(), ApiError> {
+    if !can_invite_as(actor, role) {
+        return Err(ApiError::Forbidden(String::from(
+            "You can't manage invites for that role",
+        )));
+    }
+    Ok(())
+}
+
+#[get("/campaigns/{campaign_id}/invites")]
+pub async fn get_invites(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let campaign_id = path.into_inner();
+    permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::ManageMembers,
+    )
+    .await?;
+
+    let res = db::get_campaign_invites(&data.db_conn, campaign_id).await?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[post("/campaigns/{campaign_id}/invites")]
+pub async fn create_invite(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<CreateInviteBody>,
+) -> Result<HttpResponse, ApiError> {
+    let campaign_id = path.into_inner();
+    let actor = permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::ManageMembers,
+    )
+    .await?;
+    check_role(actor, body.role)?;
+
+    if body.max_uses.is_some_and(|max_uses| max_uses < 1) {
+        return Err(ApiError::BadRequest(String::from(
+            "max_uses has to be at least 1",
+        )));
+    }
+    if body
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= chrono::Utc::now().naive_utc())
+    {
+        return Err(ApiError::BadRequest(String::from(
+            "expires_at has to be in the future",
+        )));
+    }
+
+    let res = db::create_campaign_invite(
+        &data.db_conn,
+        campaign_id,
+        user.id,
+        body.role,
+        body.max_uses,
+        body.expires_at,
+    )
+    .await?;
+
+    Ok(HttpResponse::Created().json(res))
+}
+
+// Replaces the code, the old one stops working but the limits and uses so far stay
+#[post("/campaigns/{campaign_id}/invites/{invite_id}/regenerate")]
+pub async fn regenerate_invite(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (campaign_id, invite_id) = path.into_inner();
+    let actor = permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::ManageMembers,
+    )
+    .await?;
+    let invite = db::get_campaign_invite(&data.db_conn, campaign_id, invite_id)
+        .await
+        .map_err(not_found)?;
+    check_role(actor, invite.role)?;
+
+    let res = db::regenerate_campaign_invite(&data.db_conn, campaign_id, invite_id)
+        .await
+        .map_err(not_found)?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[delete("/campaigns/{campaign_id}/invites/{invite_id}")]
+pub async fn revoke_invite(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (campaign_id, invite_id) = path.into_inner();
+    let actor = permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::ManageMembers,
+    )
+    .await?;
+    let invite = db::get_campaign_invite(&data.db_conn, campaign_id, invite_id)
+        .await
+        .map_err(not_found)?;
+    check_role(actor, invite.role)?;
+
+    db::delete_campaign_invite(&data.db_conn, campaign_id, invite_id)
+        .await
+        .map_err(not_found)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// The campaign an invite is for, so the client can show it before joining. Anyone logged in can
+// look, they need the code anyway
+#[get("/invites/{invite}")]
+pub async fn preview_invite(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let res = db::get_invite_preview(&data.db_conn, user.id, &path.into_inner())
+        .await
+        .map_err(not_found)?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
@@ -0,0 +1,206 @@
+use actix_web::{delete, get, patch, web, HttpResponse};
+use serde::Deserialize;
+use sqlx::PgConnection;
+
+use crate::{
+    auth::AuthenticatedUser,
+    db::{self, CampaignRole},
+    error::ApiError,
+    hub::{PublishToCampaign, RemoveUser},
+    permissions::{self, can_change_member, Permission},
+    protocol::ServerMessage,
+    rooms::SeenBy,
+    AppState,
+};
+
+#[derive(Deserialize)]
+struct UpdateMemberBody {
+    role: CampaignRole,
+}
+
+#[derive(Deserialize)]
+struct RemoveMemberQuery {
+    // also stops them from joining again
+    #[serde(default)]
+    ban: bool,
+}
+
+fn member_not_found() -> ApiError {
+    ApiError::NotFound(String::from("Member not found"))
+}
+
+// The actor's role as it is now, with both their and the member's rows locked until `tx` is over.
+// A role change that landed after permissions::require is what gets checked
+async fn lock_actor_role(
+    tx: &mut PgConnection,
+    campaign_id: i32,
+    actor_id: i32,
+    member_id: i32,
+) -> Result<CampaignRole, ApiError> {
+    db::lock_campaign_roles(tx, campaign_id, &[actor_id, member_id])
+        .await?
+        .into_iter()
+        .find(|(user_id, _)| *user_id == actor_id)
+        .map(|(_, role)| role)
+        .ok_or_else(|| ApiError::NotFound(String::from("Campaign not found")))
+}
+
+#[get("/campaigns/{campaign_id}/members")]
+pub async fn get_members(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let campaign_id = path.into_inner();
+    permissions::require(&data.db_conn, user.id, campaign_id, Permission::View).await?;
+
+    let res = db::get_campaign_members(&data.db_conn, campaign_id).await?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+// Promotes or demotes a member, see permissions::can_change_member for who can do what
+#[patch("/campaigns/{campaign_id}/members/{user_id}")]
+pub async fn update_member(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+    body: web::Json<UpdateMemberBody>,
+) -> Result<HttpResponse, ApiError> {
+    let (campaign_id, member_id) = path.into_inner();
+    permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::ManageMembers,
+    )
+    .await?;
+
+    let mut tx = data.db_conn.begin().await?;
+    let actor = lock_actor_role(&mut tx, campaign_id, user.id, member_id).await?;
+    if !actor.can(Permission::ManageMembers) {
+        return Err(permissions::forbidden(Permission::ManageMembers));
+    }
+    let mut member = db::lock_campaign_member(&mut tx, campaign_id, member_id)
+        .await?
+        .ok_or_else(member_not_found)?;
+    if !can_change_member(actor, member.role, Some(body.role)) {
+        return Err(ApiError::Forbidden(String::from(
+            "You can't give them that role",
+        )));
+    }
+    db::set_campaign_role(&mut tx, campaign_id, member_id, body.role).await?;
+    tx.commit().await?;
+
+    member.role = body.role;
+    data.hub.do_send(PublishToCampaign {
+        campaign_id,
+        message: ServerMessage::MemberRoleChanged {
+            user: member.discord_id.clone(),
+            role: member.role,
+        },
+        seen_by: SeenBy::Everyone,
+        sender: None,
+    });
+
+    Ok(HttpResponse::Ok().json(member))
+}
+
+// Kicks a member (and bans them with ?ban=true), or leaves the campaign when it's yourself. The
+// owner can't leave, the campaign has to be transferred or deleted instead
+#[delete("/campaigns/{campaign_id}/members/{user_id}")]
+pub async fn remove_member(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+    query: web::Query<RemoveMemberQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (campaign_id, member_id) = path.into_inner();
+    permissions::require(&data.db_conn, user.id, campaign_id, Permission::View).await?;
+
+    let mut tx = data.db_conn.begin().await?;
+    let actor = lock_actor_role(&mut tx, campaign_id, user.id, member_id).await?;
+    let member = db::lock_campaign_member(&mut tx, campaign_id, member_id)
+        .await?
+        .ok_or_else(member_not_found)?;
+    if member_id == user.id {
+        if query.ban {
+            return Err(ApiError::BadRequest(String::from("You can't ban yourself")));
+        }
+        if member.role == CampaignRole::Owner {
+            return Err(ApiError::BadRequest(String::from(
+                "The owner can't leave the campaign",
+            )));
+        }
+    } else if !can_change_member(actor, member.role, None) {
+        return Err(ApiError::Forbidden(String::from(
+            "You can't remove them from the campaign",
+        )));
+    }
+    db::remove_campaign_member(&mut tx, campaign_id, member_id).await?;
+    if query.ban {
+        db::ban_campaign_member(&mut tx, campaign_id, member_id, user.id).await?;
+    }
+    tx.commit().await?;
+
+    data.hub.do_send(PublishToCampaign {
+        campaign_id,
+        message: ServerMessage::MemberRemoved {
+            user: member.discord_id.clone(),
+        },
+        seen_by: SeenBy::Everyone,
+        sender: None,
+    });
+    data.hub.do_send(RemoveUser {
+        campaign_id,
+        user_id: member.discord_id,
+    });
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/campaigns/{campaign_id}/bans")]
+pub async fn get_bans(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let campaign_id = path.into_inner();
+    permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::ManageMembers,
+    )
+    .await?;
+
+    let res = db::get_campaign_bans(&data.db_conn, campaign_id).await?;
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+// They can join again, but have to get a new invite like anyone else
+#[delete("/campaigns/{campaign_id}/bans/{user_id}")]
+pub async fn unban(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (campaign_id, banned_id) = path.into_inner();
+    permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::ManageMembers,
+    )
+    .await?;
+
+    db::unban_campaign_member(&data.db_conn, campaign_id, banned_id)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => ApiError::NotFound(String::from("They aren't banned")),
+            e => e.into(),
+        })?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
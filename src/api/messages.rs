@@ -0,0 +1,77 @@
+use crate::{
+    auth::AuthenticatedUser,
+    db::{self, HistoryCursor},
+    error::ApiError,
+    permissions::{self, Permission},
+    AppState,
+};
+use actix_web::{get, web, HttpResponse};
+
+pub const DEFAULT_HISTORY_LIMIT: i64 = 50;
+const MAX_HISTORY_LIMIT: i64 = 100;
+
+#[derive(serde::Deserialize)]
+struct HistoryQuery {
+    // leave out for the campaign wide room
+    session_id: Option<i32>,
+    before: Option<i64>,
+    after: Option<i64>,
+    limit: Option<i64>,
+}
+
+// Shared with the FetchHistory websocket command
+pub fn history_cursor(
+    before: Option<i64>,
+    after: Option<i64>,
+    limit: Option<i64>,
+) -> Result<(HistoryCursor, i64), ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    if !(1..=MAX_HISTORY_LIMIT).contains(&limit) {
+        return Err(ApiError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_HISTORY_LIMIT
+        )));
+    }
+
+    let cursor = match (before, after) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::BadRequest(String::from(
+                "Only one of before and after can be used",
+            )))
+        }
+        (before, None) => HistoryCursor::Before(before),
+        (None, Some(after)) => HistoryCursor::After(after),
+    };
+
+    Ok((cursor, limit))
+}
+
+#[get("/campaigns/{campaign_id}/messages")]
+pub async fn get_messages(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let campaign_id = path.into_inner();
+    let (cursor, limit) = history_cursor(query.before, query.after, query.limit)?;
+
+    let role = permissions::require(&data.db_conn, user.id, campaign_id, Permission::View).await?;
+    if let Some(session_id) = query.session_id {
+        if !db::dnd_session_in_campaign(&data.db_conn, session_id, campaign_id).await? {
+            return Err(ApiError::NotFound(String::from("Session not found")));
+        }
+    }
+
+    let page = db::get_messages(
+        &data.db_conn,
+        campaign_id,
+        query.session_id,
+        cursor,
+        limit,
+        user.id,
+        role.is_dm(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(page))
+}
@@ -1,2 +1,70 @@
+use actix_web::web;
+
+use crate::error::ApiError;
+
 pub mod campaigns;
+pub mod characters;
+pub mod encounters;
+pub mod invites;
+pub mod members;
+pub mod messages;
 pub mod sessions;
+
+// Mounted under /api/v1 in main.rs
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(
+        web::JsonConfig::default()
+            .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+    )
+    .app_data(
+        web::PathConfig::default()
+            .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+    )
+    .app_data(
+        web::QueryConfig::default()
+            .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+    )
+    .service(campaigns::create_campaign)
+    .service(campaigns::get_campaigns)
+    .service(campaigns::join_campaign)
+    .service(campaigns::get_campaign)
+    .service(campaigns::update_campaign)
+    .service(campaigns::delete_campaign)
+    .service(members::get_members)
+    .service(members::update_member)
+    .service(members::remove_member)
+    .service(members::get_bans)
+    .service(members::unban)
+    .service(invites::get_invites)
+    .service(invites::create_invite)
+    .service(invites::regenerate_invite)
+    .service(invites::revoke_invite)
+    .service(invites::preview_invite)
+    .service(sessions::get_sessions)
+    .service(sessions::create_session)
+    .service(sessions::get_session)
+    .service(sessions::update_session)
+    .service(sessions::delete_session)
+    .service(messages::get_messages)
+    .service(characters::get_characters)
+    .service(characters::create_character)
+    .service(characters::get_character)
+    .service(characters::update_character)
+    .service(characters::delete_character)
+    .service(characters::get_effects)
+    .service(characters::add_effect)
+    .service(characters::remove_effect)
+    .service(encounters::get_active_encounter)
+    .service(encounters::start_encounter)
+    .service(encounters::get_encounter)
+    .service(encounters::end_encounter)
+    .service(encounters::add_combatant)
+    .service(encounters::remove_combatant)
+    .service(encounters::next_turn)
+    .service(encounters::delay)
+    .service(encounters::undelay)
+    .service(encounters::ready)
+    .service(encounters::trigger_readied)
+    .service(encounters::add_effect)
+    .service(encounters::remove_effect);
+}
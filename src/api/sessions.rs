@@ -1,47 +1,124 @@
-use crate::{db, AppState};
-use actix_web::{get, post, web};
+use crate::{
+    auth::AuthenticatedUser,
+    db::{self, DndSession},
+    error::ApiError,
+    permissions::{self, Permission},
+    AppState,
+};
+use actix_web::{delete, get, patch, post, web, HttpResponse};
 
 #[derive(serde::Deserialize)]
-struct GetSessionsQuery {
-    campaign_id: i32,
+struct SessionBody {
+    name: String,
 }
 
-#[get("/api/get/sessions")]
-pub async fn get_sessions(
-    data: web::Data<AppState>,
-    req: actix_web::HttpRequest,
-    query: web::Query<GetSessionsQuery>,
-) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    if let Some(header) = req.headers().get("Authorization") {
-        let access_token = &header.to_str().unwrap()[7..];
-        let result = db::get_dnd_sessions(&data.db_conn, access_token, query.campaign_id)
-            .await
-            .map_err(|_| actix_web::error::ErrorForbidden("Unauthorized"))?;
-        return Ok(actix_web::HttpResponse::Ok().body(serde_json::to_string(&result).unwrap()));
+fn validate_name(name: &str) -> Result<(), ApiError> {
+    if name.trim().is_empty() || name.chars().count() > 256 {
+        return Err(ApiError::BadRequest(String::from(
+            "Session name must be between 1 and 256 characters",
+        )));
     }
+    Ok(())
+}
 
-    Err(actix_web::error::ErrorForbidden("No token"))
+fn not_found(e: sqlx::Error) -> ApiError {
+    match e {
+        sqlx::Error::RowNotFound => ApiError::NotFound(String::from("Session not found")),
+        e => e.into(),
+    }
 }
 
-#[derive(serde::Deserialize)]
-struct CreateSessionBody {
-    campaign_id: i32,
-    name: String,
+// Sessions are run by the DMs, everyone else can only look at them
+async fn get_managed_session(
+    data: &AppState,
+    user_id: i32,
+    session_id: i32,
+) -> Result<DndSession, ApiError> {
+    let session = db::get_dnd_session(&data.db_conn, user_id, session_id)
+        .await
+        .map_err(not_found)?;
+    permissions::require(
+        &data.db_conn,
+        user_id,
+        session.campaign_id,
+        Permission::ManageSessions,
+    )
+    .await?;
+    Ok(session)
 }
 
-#[post("/api/create/session")]
+#[get("/campaigns/{campaign_id}/sessions")]
+pub async fn get_sessions(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let campaign_id = path.into_inner();
+    permissions::require(&data.db_conn, user.id, campaign_id, Permission::View).await?;
+
+    let result = db::get_dnd_sessions(&data.db_conn, user.id, campaign_id).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/campaigns/{campaign_id}/sessions")]
 pub async fn create_session(
     data: web::Data<AppState>,
-    req: actix_web::HttpRequest,
-    body: web::Json<CreateSessionBody>,
-) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    if let Some(header) = req.headers().get("Authorization") {
-        let access_token = &header.to_str().unwrap()[7..];
-        let session =
-            db::create_dnd_session(&data.db_conn, access_token, body.campaign_id, &body.name)
-                .await
-                .map_err(|_| actix_web::error::ErrorForbidden("Unauthorized"))?;
-        return Ok(actix_web::HttpResponse::Ok().body(serde_json::to_string(&session).unwrap()));
-    }
-    Err(actix_web::error::ErrorForbidden("No token"))
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<SessionBody>,
+) -> Result<HttpResponse, ApiError> {
+    validate_name(&body.name)?;
+    let campaign_id = path.into_inner();
+    permissions::require(
+        &data.db_conn,
+        user.id,
+        campaign_id,
+        Permission::ManageSessions,
+    )
+    .await?;
+
+    let session = db::create_dnd_session(&data.db_conn, user.id, campaign_id, &body.name).await?;
+    Ok(HttpResponse::Created().json(session))
+}
+
+#[get("/sessions/{session_id}")]
+pub async fn get_session(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let session = db::get_dnd_session(&data.db_conn, user.id, path.into_inner())
+        .await
+        .map_err(not_found)?;
+    Ok(HttpResponse::Ok().json(session))
+}
+
+#[patch("/sessions/{session_id}")]
+pub async fn update_session(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    body: web::Json<SessionBody>,
+) -> Result<HttpResponse, ApiError> {
+    validate_name(&body.name)?;
+    let session = get_managed_session(&data, user.id, path.into_inner()).await?;
+
+    let session = db::update_dnd_session(&data.db_conn, session.id, &body.name)
+        .await
+        .map_err(not_found)?;
+    Ok(HttpResponse::Ok().json(session))
+}
+
+#[delete("/sessions/{session_id}")]
+pub async fn delete_session(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let session = get_managed_session(&data, user.id, path.into_inner()).await?;
+
+    db::delete_dnd_session(&data.db_conn, session.id)
+        .await
+        .map_err(not_found)?;
+    Ok(HttpResponse::NoContent().finish())
 }
@@ -1,11 +1,88 @@
-use crate::{db, ws, UserSession};
-use crate::{AppState, DiscordUser};
-use actix_web::{get, web, HttpResponse};
-use oauth2::reqwest::async_http_client;
-use oauth2::{AuthorizationCode, TokenResponse};
-use reqwest::StatusCode;
+use crate::error::ApiError;
+use crate::AppState;
+use crate::{db, ws, CurrentSession, UserSession};
+use actix_web::{
+    dev::Payload, get, post, web, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures_util::future::{ready, LocalBoxFuture};
+use oauth2::PkceCodeVerifier;
 use serde::Deserialize;
 
+// Extractor for handlers that need a logged in user, checks the `Authorization: Bearer <token>`
+// header against the db. The result is cached in the request extensions so using it more than
+// once per request (or from middleware) only hits the db once
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub id: i32,
+    pub session_id: i32,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+// How long a session token handed to a client stays valid
+pub const SESSION_LIFETIME: chrono::TimeDelta = chrono::TimeDelta::days(30);
+
+fn bearer_token(req: &HttpRequest) -> Result<String, ApiError> {
+    let header = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .ok_or_else(|| ApiError::Unauthorized(String::from("No token provided")))?;
+
+    let value = header
+        .to_str()
+        .map_err(|_| ApiError::Unauthorized(String::from("Malformed Authorization header")))?;
+
+    match value.trim().split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            let token = token.trim();
+            if token.is_empty() {
+                return Err(ApiError::Unauthorized(String::from("Empty bearer token")));
+            }
+            Ok(token.to_string())
+        }
+        _ => Err(ApiError::Unauthorized(String::from(
+            "Authorization header must use the Bearer scheme",
+        ))),
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+            return Box::pin(ready(Ok(user.clone())));
+        }
+
+        let req = req.clone();
+        Box::pin(async move {
+            let token = bearer_token(&req)?;
+            let data = req
+                .app_data::<web::Data<AppState>>()
+                .ok_or_else(|| ApiError::Internal(String::from("App state missing")))?;
+
+            let token_session =
+                db::get_token_session(&data.db_conn, &token)
+                    .await
+                    .map_err(|e| match e {
+                        sqlx::Error::RowNotFound => {
+                            ApiError::Unauthorized(String::from("Invalid token"))
+                        }
+                        e => e.into(),
+                    })?;
+
+            let user = AuthenticatedUser {
+                id: token_session.user_id,
+                session_id: token_session.session_id,
+                expires_at: token_session.expires_at,
+            };
+            req.extensions_mut().insert(user.clone());
+
+            Ok(user)
+        })
+    }
+}
+
 #[derive(Deserialize)]
 struct TokenState {
     code: String,
@@ -15,19 +92,13 @@ struct TokenState {
 #[get("/session")]
 pub async fn session(
     data: web::Data<AppState>,
-    req: actix_web::HttpRequest,
-) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    if let Some(header) = req.headers().get("Authorization") {
-        let access_token = &header.to_str().unwrap();
-        let session = db::get_session_token(&data.db_conn, access_token)
-            .await
-            .map_err(|_| {
-                actix_web::Error::from(actix_web::error::ErrorForbidden("Failed to get session"))
-            })?;
-        return Ok(HttpResponse::Ok().body(serde_json::to_string(&session).unwrap()));
-    }
-
-    return Err(actix_web::error::ErrorForbidden("Failed to get session"));
+    user: AuthenticatedUser,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let session = db::get_profile(&data.db_conn, user.id).await?;
+    Ok(HttpResponse::Ok().json(CurrentSession {
+        expires_at: user.expires_at,
+        session,
+    }))
 }
 
 #[get("/login")]
@@ -35,101 +106,156 @@ pub async fn discord_token(
     token: web::Query<TokenState>,
     data: web::Data<AppState>,
 ) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    let token_result = data
-        .client
-        .exchange_code(AuthorizationCode::new(token.code.clone()))
-        .request_async(async_http_client)
-        .await;
+    // the state has to match a login started on /ws-login, and it can only be used once
+    let login = data.pending_logins.lock().unwrap().remove(&token.state);
+    let login = match login {
+        Some(login) => login,
+        None => return Err(actix_web::error::ErrorForbidden("Login isn't there")),
+    };
 
-    if let Ok(t) = token_result {
-        let state = token.state.clone();
+    match complete_login(&data, &token.code, login.pkce_verifier, login.device).await {
+        Ok(res) => {
+            let result = login.addr.send(ws::LoginPayload { payload: res }).await;
+            match result {
+                Ok(_) => {}
+                Err(err) => log::error!("Error sending login to actor: {}", err),
+            }
 
-        {
-            let pending_logins = data.pending_logins.lock().unwrap();
-            let addr = pending_logins.get(&state);
+            Ok(HttpResponse::Ok().body("Logged in, return to client"))
+        }
+        Err(err) => {
+            login.addr.do_send(ws::CloseLogin {
+                reason: String::from("Login failed"),
+            });
+            Err(err)
+        }
+    }
+}
 
-            if let None = addr {
-                return Err(actix_web::error::ErrorForbidden("Login isn't there"));
-            }
+async fn complete_login(
+    data: &AppState,
+    code: &str,
+    pkce_verifier: Option<PkceCodeVerifier>,
+    device: Option<String>,
+) -> Result<UserSession, actix_web::Error> {
+    let tokens = data.provider.exchange_code(code, pkce_verifier).await?;
+    let user = data.provider.fetch_profile(&tokens.access_token).await?;
+    // the database failing is an outage, not something wrong with the login
+    let internal = |what: &str, err: sqlx::Error| {
+        log::error!("{} for {}: {}", what, user.id, err);
+        ApiError::Internal(format!("{}, try logging in again", what))
+    };
+    let user_id = match db::get_user_by_discord_id(&data.db_conn, &user.id)
+        .await
+        .map_err(|e| internal("Failed to look up the user", e))?
+    {
+        Some(r) => {
+            db::update_user(&data.db_conn, r.id, &user, &tokens)
+                .await
+                .map_err(|e| internal("Failed to refresh tokens", e))?;
+            r.id
+        }
+        None => {
+            db::add_user(&data.db_conn, &user, &tokens)
+                .await
+                .map_err(|e| internal("Failed to add user", e))?
+                .id
         }
+    };
 
-        let token = t.access_token().secret().to_string().clone();
-        let refresh_token = t.refresh_token().unwrap().secret().to_string().clone();
-
-        let user = get_discord_user(token.to_string()).await?;
-        let res = match db::get_session_id(&data.db_conn, &user.id).await {
-            Ok(r) => {
-                let mut r = r;
-                if r.access_token != token {
-                    let tokens =
-                        db::refresh_tokens(&data.db_conn, &r.access_token, &token, &refresh_token)
-                            .await
-                            .map_err(|_| {
-                                actix_web::error::ErrorForbidden("Failed to refresh tokens")
-                            })?;
-                    r = UserSession {
-                        access_token: tokens.access_token,
-                        refresh_token: tokens.refresh_token,
-                        session: user,
-                    };
-                }
-                r
-            }
-            Err(_) => {
-                let r = db::add_user(&data.db_conn, &user, &token, &refresh_token)
-                    .await
-                    .map_err(|_| {
-                        actix_web::Error::from(actix_web::error::ErrorForbidden(
-                            "Failed to add user",
-                        ))
-                    })?;
-                r
-            }
-        };
+    let session_token =
+        db::create_session_token(&data.db_conn, user_id, device.as_deref(), SESSION_LIFETIME)
+            .await
+            .map_err(|e| internal("Failed to create session", e))?;
 
-        {
-            let mut pending_logins = data.pending_logins.lock().unwrap();
-            let addr = pending_logins.get(&state);
+    Ok(UserSession {
+        token: session_token.token,
+        expires_at: session_token.expires_at,
+        session: user,
+    })
+}
 
-            if let Some(a) = addr {
-                let result = a.send(ws::LoginPayload { payload: res }).await;
-                match result {
-                    Ok(_) => {}
-                    Err(err) => println!("Error sending login to actor: {}", err),
-                }
-            }
+#[derive(Deserialize)]
+struct LogoutQuery {
+    // log out every device instead of only the one making the request
+    #[serde(default)]
+    all: bool,
+}
 
-            pending_logins.remove(&state);
+#[post("/logout")]
+pub async fn logout(
+    data: web::Data<AppState>,
+    user: AuthenticatedUser,
+    query: web::Query<LogoutQuery>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    if query.all {
+        db::delete_user_session_tokens(&data.db_conn, user.id).await?;
+    } else {
+        db::delete_session_token(&data.db_conn, user.session_id).await?;
+    }
+
+    // revoking on discord kills the whole grant, so only do it once no other device is using it
+    if db::count_session_tokens(&data.db_conn, user.id).await? == 0 {
+        let tokens = db::get_discord_tokens(&data.db_conn, user.id).await?;
+        if !tokens.access_token.is_empty() {
+            if let Err(err) = data.provider.revoke_token(&tokens.access_token).await {
+                log::warn!("Failed to revoke discord token for {}: {}", user.id, err);
+            }
         }
 
-        return Ok(HttpResponse::Ok().body("Logged in, return to client"));
+        db::clear_discord_tokens(&data.db_conn, user.id).await?;
     }
 
-    Err(actix_web::error::ErrorForbidden("Failed to log in"))
+    Ok(HttpResponse::NoContent().finish())
 }
 
-pub async fn get_discord_user(token: String) -> Result<DiscordUser, actix_web::Error> {
-    let client = reqwest::Client::new();
-    let resp = client
-        .get("https://discord.com/api/users/@me")
-        .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
-        .send()
-        .await;
-
-    let user = match resp {
-        Ok(r) => {
-            if r.status() == StatusCode::OK {
-                r.json::<DiscordUser>()
-                    .await
-                    .map_err(|_| actix_web::error::ErrorForbidden("Invalid token parsing"))?
-            } else {
-                return Err(actix_web::error::ErrorForbidden("Invaliddd token"));
+// Refresh discord tokens a bit before they actually run out
+const DISCORD_REFRESH_MARGIN: chrono::TimeDelta = chrono::TimeDelta::hours(1);
+const DISCORD_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+// Exchanges the stored refresh token for a new set of discord tokens
+pub async fn refresh_discord_token(data: &AppState, user_id: i32) -> Result<String, ApiError> {
+    let old = db::get_discord_tokens(&data.db_conn, user_id).await?;
+    if old.refresh_token.is_empty() {
+        return Err(ApiError::Unauthorized(String::from(
+            "Discord login was revoked, log in again",
+        )));
+    }
+
+    let tokens = data.provider.refresh_token(&old.refresh_token).await?;
+    db::update_discord_tokens(&data.db_conn, user_id, &tokens).await?;
+
+    Ok(tokens.access_token)
+}
+
+// Background task started from main, keeps the discord tokens of logged in users fresh
+pub async fn refresh_expiring_tokens(data: web::Data<AppState>) {
+    let mut interval = tokio::time::interval(DISCORD_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let before = chrono::Utc::now().naive_utc() + DISCORD_REFRESH_MARGIN;
+        let users = match db::get_users_with_expiring_tokens(&data.db_conn, before).await {
+            Ok(users) => users,
+            Err(err) => {
+                log::error!("Failed to get users with expiring tokens: {}", err);
+                continue;
             }
-        }
-        Err(_) => {
-            return Err(actix_web::error::ErrorForbidden("Invalid token"));
-        }
-    };
+        };
 
-    Ok(user)
+        for user in users {
+            match refresh_discord_token(&data, user.id).await {
+                Ok(_) => {}
+                // discord answered invalid_grant, forget the tokens so they don't get retried
+                // every tick and the user is asked to log in again
+                Err(ApiError::Unauthorized(err)) => {
+                    log::warn!("Discord login of user {} was revoked: {}", user.id, err);
+                    if let Err(err) = db::clear_discord_tokens(&data.db_conn, user.id).await {
+                        log::error!("Failed to clear discord tokens of {}: {}", user.id, err);
+                    }
+                }
+                Err(err) => log::warn!("Failed to refresh discord token for {}: {}", user.id, err),
+            }
+        }
+    }
 }
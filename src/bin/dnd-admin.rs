@@ -0,0 +1,353 @@
+// Command line tool for poking at the data without writing sql by hand. Uses the same config
+// file (and DND__ env overrides) as the server, e.g.
+//
+// dnd-admin users --search bob
+// dnd-admin campaigns players 3 --json
+// dnd-admin campaigns delete-abandoned --days 180 --yes
+// dnd-admin rolls verify 42
+use clap::{Parser, Subcommand};
+use dnd_thing_server::config::Config;
+use dnd_thing_server::db;
+use dnd_thing_server::dice;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+
+#[derive(Parser)]
+#[command(about = "Admin tool for the D&D app database")]
+struct Args {
+    /// Path to the config file, defaults to ./config.toml
+    #[arg(long, short, global = true)]
+    config: Option<String>,
+
+    /// Print json instead of a table
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List users, optionally filtered by username, display name or discord id
+    Users {
+        #[arg(long, short)]
+        search: Option<String>,
+    },
+    #[command(subcommand)]
+    Campaigns(CampaignCommand),
+    #[command(subcommand)]
+    Invites(InviteCommand),
+    #[command(subcommand)]
+    Rolls(RollCommand),
+}
+
+#[derive(Subcommand)]
+enum CampaignCommand {
+    /// List campaigns, optionally filtered by name or owner
+    List {
+        #[arg(long, short)]
+        search: Option<String>,
+        /// Only campaigns owned by this user id
+        #[arg(long)]
+        owner: Option<i32>,
+    },
+    /// List the players of a campaign
+    Players { campaign_id: i32 },
+    /// Make another user the owner of a campaign
+    Transfer { campaign_id: i32, new_owner_id: i32 },
+    /// Delete campaigns that haven't been touched in a while
+    DeleteAbandoned {
+        /// How many days without changes to the campaign or its sessions counts as abandoned
+        #[arg(long, default_value_t = 90)]
+        days: i64,
+        /// Actually delete them, without this the campaigns are only listed
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum InviteCommand {
+    /// List the invites of a campaign
+    List { campaign_id: i32 },
+    /// Set how many more times an invite can be used
+    Reset { invite_id: i32, uses: i32 },
+    /// Replace an invite's code so the old one stops working
+    Regenerate {
+        invite_id: i32,
+        /// Also set the remaining uses, otherwise they're kept
+        #[arg(long)]
+        uses: Option<i32>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RollCommand {
+    /// Roll a saved dice roll again with its seed and check the result matches
+    Verify { roll_id: i64 },
+}
+
+// Prints `rows` as json, or as a table with the given (json key, header) columns
+fn print_rows<T: Serialize>(rows: &[T], columns: &[(&str, &str)], json: bool) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(rows).unwrap());
+        return;
+    }
+
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            let value = serde_json::to_value(row).unwrap();
+            columns
+                .iter()
+                .map(|(key, _)| match &value[key] {
+                    serde_json::Value::Null => String::from("-"),
+                    serde_json::Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                })
+                .collect()
+        })
+        .collect();
+
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, (_, header))| {
+            cells
+                .iter()
+                .map(|row| row[i].chars().count())
+                .chain([header.len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let line = |values: Vec<&str>| {
+        let padded: Vec<String> = values
+            .iter()
+            .zip(&widths)
+            .map(|(v, w)| format!("{:<w$}", v, w = w))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+
+    line(columns.iter().map(|(_, header)| *header).collect());
+    for row in &cells {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+// Prints a single result, a message for the table output or an object for --json
+fn print_result(json: bool, message: &str, value: serde_json::Value) {
+    if json {
+        println!("{}", value);
+    } else {
+        println!("{}", message);
+    }
+}
+
+const CAMPAIGN_COLUMNS: [(&str, &str); 8] = [
+    ("id", "ID"),
+    ("name", "Name"),
+    ("owner_id", "Owner ID"),
+    ("owner", "Owner"),
+    ("players", "Players"),
+    ("sessions", "Sessions"),
+    ("invites", "Invites"),
+    ("last_active", "Last active"),
+];
+
+async fn run(command: Command, conn: &Pool<Postgres>, json: bool) -> Result<(), String> {
+    let not_found = |e: sqlx::Error| match e {
+        sqlx::Error::RowNotFound => String::from("Campaign not found"),
+        e => e.to_string(),
+    };
+    let invite_not_found = |e: sqlx::Error| match e {
+        sqlx::Error::RowNotFound => String::from("Invite not found"),
+        e => e.to_string(),
+    };
+
+    match command {
+        Command::Users { search } => {
+            let users = db::admin_list_users(conn, search.as_deref())
+                .await
+                .map_err(|e| e.to_string())?;
+            print_rows(
+                &users,
+                &[
+                    ("id", "ID"),
+                    ("username", "Username"),
+                    ("global_name", "Display name"),
+                    ("discord_id", "Discord ID"),
+                    ("campaigns", "Campaigns"),
+                    ("created_at", "Created"),
+                ],
+                json,
+            );
+        }
+        Command::Campaigns(CampaignCommand::List { search, owner }) => {
+            let campaigns = db::admin_list_campaigns(conn, search.as_deref(), owner)
+                .await
+                .map_err(|e| e.to_string())?;
+            print_rows(&campaigns, &CAMPAIGN_COLUMNS, json);
+        }
+        Command::Campaigns(CampaignCommand::Players { campaign_id }) => {
+            let players = db::admin_get_campaign_players(conn, campaign_id)
+                .await
+                .map_err(|e| e.to_string())?;
+            print_rows(
+                &players,
+                &[
+                    ("player_id", "User ID"),
+                    ("username", "Username"),
+                    ("role", "Role"),
+                    ("joined_at", "Joined"),
+                ],
+                json,
+            );
+        }
+        Command::Campaigns(CampaignCommand::Transfer {
+            campaign_id,
+            new_owner_id,
+        }) => {
+            db::admin_transfer_campaign(conn, campaign_id, new_owner_id)
+                .await
+                .map_err(|e| match e {
+                    sqlx::Error::Database(ref d) if d.is_foreign_key_violation() => {
+                        format!("User {} doesn't exist", new_owner_id)
+                    }
+                    e => not_found(e),
+                })?;
+            print_result(
+                json,
+                &format!(
+                    "Campaign {} is now owned by user {}",
+                    campaign_id, new_owner_id
+                ),
+                serde_json::json!({ "campaign_id": campaign_id, "owner_id": new_owner_id }),
+            );
+        }
+        Command::Campaigns(CampaignCommand::DeleteAbandoned { days, yes }) => {
+            let before = chrono::Utc::now().naive_utc() - chrono::TimeDelta::days(days);
+            let campaigns = db::admin_get_abandoned_campaigns(conn, before)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            if yes {
+                for campaign in &campaigns {
+                    db::delete_dnd_campaign(conn, campaign.id)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+
+            print_rows(&campaigns, &CAMPAIGN_COLUMNS, json);
+            if !json {
+                if yes {
+                    println!("Deleted {} campaign(s)", campaigns.len());
+                } else if !campaigns.is_empty() {
+                    println!(
+                        "{} campaign(s) would be deleted, run again with --yes to delete them",
+                        campaigns.len()
+                    );
+                }
+            }
+        }
+        Command::Invites(InviteCommand::List { campaign_id }) => {
+            let invites = db::get_campaign_invites(conn, campaign_id)
+                .await
+                .map_err(|e| e.to_string())?;
+            print_rows(
+                &invites,
+                &[
+                    ("id", "ID"),
+                    ("invite", "Invite"),
+                    ("role", "Role"),
+                    ("uses", "Uses"),
+                    ("max_uses", "Max uses"),
+                    ("expires_at", "Expires"),
+                    ("created_by", "Created by"),
+                ],
+                json,
+            );
+        }
+        Command::Invites(InviteCommand::Reset { invite_id, uses }) => {
+            if uses < 0 {
+                return Err(String::from("uses can't be negative"));
+            }
+            db::admin_reset_invite_uses(conn, invite_id, uses)
+                .await
+                .map_err(invite_not_found)?;
+            print_result(
+                json,
+                &format!("Invite {} has {} use(s) left", invite_id, uses),
+                serde_json::json!({ "invite_id": invite_id, "uses": uses }),
+            );
+        }
+        Command::Invites(InviteCommand::Regenerate { invite_id, uses }) => {
+            if uses.is_some_and(|uses| uses < 0) {
+                return Err(String::from("uses can't be negative"));
+            }
+            let invite = db::admin_regenerate_invite(conn, invite_id, uses)
+                .await
+                .map_err(invite_not_found)?;
+            print_result(
+                json,
+                &format!("New code for invite {}: {}", invite_id, invite),
+                serde_json::json!({ "invite_id": invite_id, "invite": invite }),
+            );
+        }
+        Command::Rolls(RollCommand::Verify { roll_id }) => {
+            let roll = db::admin_get_roll(conn, roll_id)
+                .await
+                .map_err(|e| match e {
+                    sqlx::Error::RowNotFound => String::from("Roll not found"),
+                    e => e.to_string(),
+                })?;
+            let expression = dice::parse(&roll.notation).map_err(|e| e.to_string())?;
+            let rerolled = expression.roll_with_seed(roll.seed as u64);
+            if serde_json::to_value(&rerolled).unwrap() != roll.result {
+                return Err(format!(
+                    "Roll {} doesn't match its seed, it was {} but rolling {} again gives {}",
+                    roll_id, roll.total, roll.notation, rerolled.total
+                ));
+            }
+            print_result(
+                json,
+                &format!(
+                    "Roll {} ({} = {}) matches its seed",
+                    roll_id, roll.notation, roll.total
+                ),
+                serde_json::json!({ "roll_id": roll_id, "valid": true, "total": roll.total }),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let config = match Config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprint!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let conn = match db::connect(&config).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            eprintln!("Couldn't connect to the database: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = run(args.command, &conn, args.json).await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,317 @@
+// Stand-in for discord's oauth2 + users/@me endpoints so the whole login flow can run offline.
+// Point the server at it with something like this in config.toml (the default port is 8090):
+//
+// discord_authorize_url = "http://localhost:8090/oauth2/authorize"
+// discord_token_url = "http://localhost:8090/api/oauth2/token"
+// discord_revocation_url = "http://localhost:8090/api/oauth2/token/revoke"
+// discord_profile_url = "http://localhost:8090/api/users/@me"
+//
+// Usage: mock-oauth [bind address, default 127.0.0.1:8090] [token lifetime in seconds, default 3600]
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer};
+use oauth2::{PkceCodeChallenge, PkceCodeVerifier};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Serialize, Clone)]
+struct MockUser {
+    id: &'static str,
+    username: &'static str,
+    discriminator: &'static str,
+    global_name: Option<&'static str>,
+    avatar: Option<&'static str>,
+    accent_color: Option<i32>,
+}
+
+const USERS: [MockUser; 3] = [
+    MockUser {
+        id: "100000000000000001",
+        username: "mock_dm",
+        discriminator: "0",
+        global_name: Some("Mock DM"),
+        avatar: None,
+        accent_color: None,
+    },
+    MockUser {
+        id: "100000000000000002",
+        username: "mock_player",
+        discriminator: "0",
+        global_name: Some("Mock Player"),
+        avatar: None,
+        accent_color: Some(16711680),
+    },
+    MockUser {
+        id: "100000000000000003",
+        username: "mock_spectator",
+        discriminator: "0",
+        global_name: None,
+        avatar: None,
+        accent_color: None,
+    },
+];
+
+struct PendingCode {
+    user_id: &'static str,
+    redirect_uri: String,
+    code_challenge: Option<String>,
+}
+
+struct MockState {
+    codes: Mutex<HashMap<String, PendingCode>>,
+    // token -> user id
+    access_tokens: Mutex<HashMap<String, &'static str>>,
+    refresh_tokens: Mutex<HashMap<String, &'static str>>,
+    expires_in: u64,
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn find_user(id: &str) -> Option<&'static MockUser> {
+    USERS.iter().find(|u| u.id == id)
+}
+
+fn oauth_error(error: &str, description: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "error": error,
+        "error_description": description,
+    }))
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    state: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    // picks the user straight away instead of showing the list, handy for scripts
+    user: Option<String>,
+}
+
+#[get("/oauth2/authorize")]
+async fn authorize(
+    req: HttpRequest,
+    query: web::Query<AuthorizeQuery>,
+    state: web::Data<MockState>,
+) -> HttpResponse {
+    if query.response_type != "code" || query.client_id.is_empty() {
+        return oauth_error("invalid_request", "response_type must be code");
+    }
+    if query.code_challenge.is_some() && query.code_challenge_method.as_deref() != Some("S256") {
+        return oauth_error("invalid_request", "only S256 code challenges are supported");
+    }
+
+    let user = match query.user.as_deref() {
+        Some(id) => match find_user(id) {
+            Some(user) => user,
+            None => return oauth_error("invalid_request", "unknown user"),
+        },
+        None => {
+            // consent screen, every user links back here with `user` set
+            let links: String = USERS
+                .iter()
+                .map(|u| {
+                    format!(
+                        "<li><a href=\"?{}&user={}\">{}</a></li>",
+                        req.query_string(),
+                        u.id,
+                        u.username
+                    )
+                })
+                .collect();
+
+            return HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(format!(
+                "<!DOCTYPE html><html><body><h1>Mock Discord login</h1><ul>{}</ul></body></html>",
+                links
+            ));
+        }
+    };
+
+    let code = random_string(30);
+    state.codes.lock().unwrap().insert(
+        code.clone(),
+        PendingCode {
+            user_id: user.id,
+            redirect_uri: query.redirect_uri.clone(),
+            code_challenge: query.code_challenge.clone(),
+        },
+    );
+
+    let mut location = format!("{}?code={}", query.redirect_uri, code);
+    if let Some(s) = &query.state {
+        location.push_str(&format!("&state={}", s));
+    }
+
+    HttpResponse::Found()
+        .insert_header(("Location", location))
+        .finish()
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+}
+
+fn issue_tokens(state: &MockState, user_id: &'static str) -> HttpResponse {
+    let access_token = random_string(30);
+    let refresh_token = random_string(30);
+    state
+        .access_tokens
+        .lock()
+        .unwrap()
+        .insert(access_token.clone(), user_id);
+    state
+        .refresh_tokens
+        .lock()
+        .unwrap()
+        .insert(refresh_token.clone(), user_id);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": state.expires_in,
+        "refresh_token": refresh_token,
+        "scope": "identify",
+    }))
+}
+
+#[post("/api/oauth2/token")]
+async fn exchange_token(form: web::Form<TokenForm>, state: web::Data<MockState>) -> HttpResponse {
+    match form.grant_type.as_str() {
+        "authorization_code" => {
+            let code = form.code.clone().unwrap_or_default();
+            let pending = match state.codes.lock().unwrap().remove(&code) {
+                Some(pending) => pending,
+                None => return oauth_error("invalid_grant", "unknown or used code"),
+            };
+
+            if form.redirect_uri.as_deref() != Some(pending.redirect_uri.as_str()) {
+                return oauth_error("invalid_grant", "redirect_uri doesn't match");
+            }
+
+            if let Some(challenge) = pending.code_challenge {
+                let verifier = match &form.code_verifier {
+                    Some(v) => PkceCodeVerifier::new(v.clone()),
+                    None => return oauth_error("invalid_grant", "missing code_verifier"),
+                };
+                let computed = PkceCodeChallenge::from_code_verifier_sha256(&verifier);
+                if computed.as_str() != challenge {
+                    return oauth_error("invalid_grant", "code_verifier doesn't match");
+                }
+            }
+
+            issue_tokens(&state, pending.user_id)
+        }
+        "refresh_token" => {
+            let refresh_token = form.refresh_token.clone().unwrap_or_default();
+            let user_id = match state.refresh_tokens.lock().unwrap().remove(&refresh_token) {
+                Some(user_id) => user_id,
+                None => return oauth_error("invalid_grant", "unknown refresh token"),
+            };
+
+            issue_tokens(&state, user_id)
+        }
+        _ => oauth_error("unsupported_grant_type", "unsupported grant_type"),
+    }
+}
+
+#[derive(Deserialize)]
+struct RevokeForm {
+    token: String,
+}
+
+#[post("/api/oauth2/token/revoke")]
+async fn revoke(form: web::Form<RevokeForm>, state: web::Data<MockState>) -> HttpResponse {
+    // like discord, revoking either token ends the whole grant for that user
+    let user_id = state
+        .access_tokens
+        .lock()
+        .unwrap()
+        .get(&form.token)
+        .copied()
+        .or_else(|| {
+            state
+                .refresh_tokens
+                .lock()
+                .unwrap()
+                .get(&form.token)
+                .copied()
+        });
+
+    if let Some(user_id) = user_id {
+        state
+            .access_tokens
+            .lock()
+            .unwrap()
+            .retain(|_, u| *u != user_id);
+        state
+            .refresh_tokens
+            .lock()
+            .unwrap()
+            .retain(|_, u| *u != user_id);
+    }
+
+    HttpResponse::Ok().finish()
+}
+
+#[get("/api/users/@me")]
+async fn me(req: HttpRequest, state: web::Data<MockState>) -> HttpResponse {
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    let user_id = state.access_tokens.lock().unwrap().get(token).copied();
+    match user_id.and_then(find_user) {
+        Some(user) => HttpResponse::Ok().json(user),
+        None => HttpResponse::Unauthorized().json(serde_json::json!({
+            "message": "401: Unauthorized",
+            "code": 0,
+        })),
+    }
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let bind = args
+        .next()
+        .unwrap_or_else(|| String::from("127.0.0.1:8090"));
+    let expires_in = args.next().and_then(|s| s.parse().ok()).unwrap_or(3600);
+
+    let state = web::Data::new(MockState {
+        codes: Mutex::new(HashMap::new()),
+        access_tokens: Mutex::new(HashMap::new()),
+        refresh_tokens: Mutex::new(HashMap::new()),
+        expires_in,
+    });
+
+    println!("Mock oauth server listening on {}", bind);
+    HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .service(authorize)
+            .service(exchange_token)
+            .service(revoke)
+            .service(me)
+    })
+    .bind(bind)?
+    .run()
+    .await
+}
@@ -0,0 +1,781 @@
+// Turn order for the combat tracker. Everything here works on an encounter loaded from the
+// database, the caller saves it again afterwards.
+//
+// Combatants go in order of initiative, highest first, and whoever was added first wins a tie.
+// Delayed combatants are skipped until they step back in, which puts them right before whoever's
+// turn it is. A readied action stays readied until it's triggered or the combatant's next turn.
+//
+// An effect lasting N rounds ends at the start of the turn of whoever's turn it was when it
+// started, N rounds later, or at the end of that turn for effects like "until the end of your next
+// turn". Effects that started before the first turn end at the start of round N + 1 instead.
+// Whatever changes whose turn it is returns the effects that ran out.
+use std::{collections::HashSet, fmt, mem};
+
+use serde::Deserialize;
+
+use crate::db::{Combatant, Effect, Encounter};
+
+// Rounds in a minute, for effect durations
+pub const ROUNDS_PER_MINUTE: i32 = 10;
+
+// How long an effect lasts, e.g. { "rounds": 10 } or { "minutes": 1 }
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Duration {
+    Rounds(i32),
+    Minutes(i32),
+}
+
+impl Duration {
+    pub fn rounds(self) -> i32 {
+        match self {
+            Duration::Rounds(rounds) => rounds,
+            Duration::Minutes(minutes) => minutes * ROUNDS_PER_MINUTE,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CombatError(String);
+
+impl fmt::Display for CombatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CombatError {}
+
+fn error(message: impl Into<String>) -> CombatError {
+    CombatError(message.into())
+}
+
+fn renumber(encounter: &mut Encounter) {
+    for (i, combatant) in encounter.combatants.iter_mut().enumerate() {
+        combatant.position = i as i32;
+    }
+}
+
+fn index_of(encounter: &Encounter, combatant_id: i32) -> Result<usize, CombatError> {
+    encounter
+        .combatants
+        .iter()
+        .position(|c| c.id == combatant_id)
+        .ok_or_else(|| error("That combatant isn't in the encounter"))
+}
+
+// Whether the effect is over at the start of `current`'s turn
+fn is_over(effect: &Effect, round: i32, current: i32, present: &HashSet<i32>) -> bool {
+    let Some(ends_round) = effect.ends_round else {
+        return false;
+    };
+    match effect.ends_on_turn_of {
+        // also if that combatant was delaying and didn't have a turn in the round
+        Some(id) if present.contains(&id) => {
+            round > ends_round
+                || (round == ends_round && id == current && !effect.ends_at_end_of_turn)
+        }
+        _ => round >= ends_round,
+    }
+}
+
+// Takes the effects that are over off every combatant and returns them
+fn expire(encounter: &mut Encounter, over: impl Fn(&Effect) -> bool) -> Vec<Effect> {
+    let mut expired = Vec::new();
+    for combatant in &mut encounter.combatants {
+        let (ended, left): (Vec<Effect>, Vec<Effect>) = mem::take(&mut combatant.effects)
+            .into_iter()
+            .partition(|effect| over(effect));
+        combatant.effects = left;
+        expired.extend(ended);
+    }
+    expired
+}
+
+// Makes it the `i`th combatant's turn, returns the effects that ran out
+fn start_turn(encounter: &mut Encounter, i: usize) -> Vec<Effect> {
+    let combatant = &mut encounter.combatants[i];
+    combatant.readied = None;
+    let current = combatant.id;
+    encounter.current_combatant_id = Some(current);
+
+    let round = encounter.round;
+    let present: HashSet<i32> = encounter.combatants.iter().map(|c| c.id).collect();
+    expire(encounter, |effect| {
+        is_over(effect, round, current, &present)
+    })
+}
+
+// The current combatant's turn is over, returns the effects that ended with it
+fn end_turn(encounter: &mut Encounter) -> Vec<Effect> {
+    let Some(current) = encounter.current_combatant_id else {
+        return Vec::new();
+    };
+    let round = encounter.round;
+    expire(encounter, |effect| {
+        effect.ends_at_end_of_turn
+            && effect.ends_on_turn_of == Some(current)
+            && effect
+                .ends_round
+                .is_some_and(|ends_round| round >= ends_round)
+    })
+}
+
+fn check_current(encounter: &Encounter, combatant_id: i32) -> Result<usize, CombatError> {
+    let i = index_of(encounter, combatant_id)?;
+    if encounter.current_combatant_id != Some(combatant_id) {
+        return Err(error(format!(
+            "It isn't {}'s turn",
+            encounter.combatants[i].name
+        )));
+    }
+    Ok(i)
+}
+
+// Adds the combatant after everyone with the same or a higher initiative. Joining after the first
+// turn is fine, it just acts when its place in the order comes up
+pub fn add(encounter: &mut Encounter, combatant: Combatant) {
+    let i = encounter
+        .combatants
+        .iter()
+        .position(|c| c.initiative < combatant.initiative)
+        .unwrap_or(encounter.combatants.len());
+    encounter.combatants.insert(i, combatant);
+    renumber(encounter);
+}
+
+// If it's the combatant's turn the turn passes on first, unless nobody else is left
+pub fn remove(encounter: &mut Encounter, combatant_id: i32) -> Result<Vec<Effect>, CombatError> {
+    let i = index_of(encounter, combatant_id)?;
+    let mut expired = Vec::new();
+    if encounter.current_combatant_id == Some(combatant_id) {
+        let others = encounter
+            .combatants
+            .iter()
+            .any(|c| c.id != combatant_id && !c.delayed);
+        if others {
+            expired = next_turn(encounter)?;
+        } else {
+            encounter.current_combatant_id = None;
+        }
+    }
+    encounter.combatants.remove(i);
+    renumber(encounter);
+    Ok(expired)
+}
+
+// Starts the next turn, the first one starts round 1. Wrapping around to the top of the order
+// starts a new round
+pub fn next_turn(encounter: &mut Encounter) -> Result<Vec<Effect>, CombatError> {
+    let count = encounter.combatants.len();
+    let start = match encounter.current_combatant_id {
+        Some(id) => index_of(encounter, id)? + 1,
+        None => 0,
+    };
+
+    let next = (start..start + count)
+        .find(|i| !encounter.combatants[i % count].delayed)
+        .ok_or_else(|| error("Nobody can take a turn"))?;
+
+    let mut expired = end_turn(encounter);
+    if encounter.current_combatant_id.is_none() || next >= count {
+        encounter.round += 1;
+    }
+    expired.extend(start_turn(encounter, next % count));
+    Ok(expired)
+}
+
+// Gives up the turn for now, the combatant can step back in later with `undelay`
+pub fn delay(encounter: &mut Encounter, combatant_id: i32) -> Result<Vec<Effect>, CombatError> {
+    let i = check_current(encounter, combatant_id)?;
+    encounter.combatants[i].delayed = true;
+    next_turn(encounter)
+}
+
+// A delayed combatant takes its turn now, before whoever's turn it was. That combatant then gets
+// its turn again afterwards
+pub fn undelay(encounter: &mut Encounter, combatant_id: i32) -> Result<Vec<Effect>, CombatError> {
+    let i = index_of(encounter, combatant_id)?;
+    if !encounter.combatants[i].delayed {
+        return Err(error(format!(
+            "{} isn't delaying",
+            encounter.combatants[i].name
+        )));
+    }
+    let Some(current_id) = encounter.current_combatant_id else {
+        return Err(error("The encounter hasn't started yet"));
+    };
+
+    let mut combatant = encounter.combatants.remove(i);
+    let current = index_of(encounter, current_id)?;
+    combatant.delayed = false;
+    combatant.initiative = encounter.combatants[current].initiative;
+    encounter.combatants.insert(current, combatant);
+    renumber(encounter);
+    Ok(start_turn(encounter, current))
+}
+
+// Ends the turn with an action held back until `trigger` happens
+pub fn ready(
+    encounter: &mut Encounter,
+    combatant_id: i32,
+    trigger: String,
+) -> Result<Vec<Effect>, CombatError> {
+    let i = check_current(encounter, combatant_id)?;
+    encounter.combatants[i].readied = Some(trigger);
+    next_turn(encounter)
+}
+
+// The readied action went off, doesn't change whose turn it is
+pub fn trigger(encounter: &mut Encounter, combatant_id: i32) -> Result<(), CombatError> {
+    let i = index_of(encounter, combatant_id)?;
+    if encounter.combatants[i].readied.take().is_none() {
+        return Err(error(format!(
+            "{} doesn't have an action readied",
+            encounter.combatants[i].name
+        )));
+    }
+    Ok(())
+}
+
+// Where an effect lasting `rounds` that starts now ends, as (ends_round, ends_on_turn_of)
+pub fn effect_end(encounter: &Encounter, rounds: i32) -> (i32, Option<i32>) {
+    match encounter.current_combatant_id {
+        Some(current) => (encounter.round + rounds, Some(current)),
+        None => (rounds + 1, None),
+    }
+}
+
+// Whose effects to remind everyone of after a change, if it started a new turn. `turn` is the
+// (round, current combatant) from before the change
+pub fn reminder(encounter: &Encounter, turn: (i32, Option<i32>)) -> Option<&Combatant> {
+    if turn == (encounter.round, encounter.current_combatant_id) {
+        return None;
+    }
+    encounter
+        .combatants
+        .iter()
+        .find(|c| Some(c.id) == encounter.current_combatant_id)
+        .filter(|c| !c.effects.is_empty())
+}
+
+pub fn add_effect(encounter: &mut Encounter, effect: Effect) -> Result<(), CombatError> {
+    let Some(combatant_id) = effect.combatant_id else {
+        return Err(error("That effect isn't on a combatant"));
+    };
+    let i = index_of(encounter, combatant_id)?;
+    encounter.combatants[i].effects.push(effect);
+    Ok(())
+}
+
+pub fn remove_effect(
+    encounter: &mut Encounter,
+    combatant_id: i32,
+    effect_id: i32,
+) -> Result<Effect, CombatError> {
+    let i = index_of(encounter, combatant_id)?;
+    let effects = &mut encounter.combatants[i].effects;
+    let j = effects
+        .iter()
+        .position(|effect| effect.id == effect_id)
+        .ok_or_else(|| error("That effect isn't on the combatant"))?;
+    Ok(effects.remove(j))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Combatants get ids 1, 2, 3... in the order they're added
+    fn encounter(initiatives: &[i32]) -> Encounter {
+        let now = chrono::Utc::now().naive_utc();
+        let mut encounter = Encounter {
+            id: 1,
+            session_id: 1,
+            campaign_id: 1,
+            name: String::new(),
+            round: 0,
+            current_combatant_id: None,
+            created_at: now,
+            last_updated: now,
+            ended_at: None,
+            combatants: Vec::new(),
+        };
+        for (i, &initiative) in initiatives.iter().enumerate() {
+            let id = i as i32 + 1;
+            add(
+                &mut encounter,
+                Combatant {
+                    id,
+                    character_id: None,
+                    owner_id: None,
+                    name: format!("c{}", id),
+                    initiative,
+                    initiative_roll: None,
+                    position: 0,
+                    delayed: false,
+                    readied: None,
+                    effects: Vec::new(),
+                },
+            );
+        }
+        encounter
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    enum Step {
+        Next,
+        Delay(i32),
+        Undelay(i32),
+        Ready(i32),
+        Trigger(i32),
+        Remove(i32),
+    }
+    use Step::*;
+
+    fn run(encounter: &mut Encounter, step: Step) -> Result<Vec<Effect>, CombatError> {
+        match step {
+            Next => next_turn(encounter),
+            Delay(id) => delay(encounter, id),
+            Undelay(id) => undelay(encounter, id),
+            Ready(id) => ready(encounter, id, String::from("the door opens")),
+            Trigger(id) => trigger(encounter, id).map(|_| Vec::new()),
+            Remove(id) => remove(encounter, id),
+        }
+    }
+
+    fn ids(encounter: &Encounter, filter: fn(&Combatant) -> bool) -> Vec<i32> {
+        encounter
+            .combatants
+            .iter()
+            .filter(|c| filter(c))
+            .map(|c| c.id)
+            .collect()
+    }
+
+    struct Expected {
+        round: i32,
+        current: Option<i32>,
+        order: Vec<i32>,
+        delayed: Vec<i32>,
+        readied: Vec<i32>,
+    }
+
+    #[test]
+    fn turn_order() {
+        let cases = [
+            (
+                "added in initiative order, ties go to whoever was added first",
+                vec![10, 20, 10, 15],
+                vec![],
+                Expected {
+                    round: 0,
+                    current: None,
+                    order: vec![2, 4, 1, 3],
+                    delayed: vec![],
+                    readied: vec![],
+                },
+            ),
+            (
+                "the first turn starts round 1",
+                vec![20, 15, 10],
+                vec![Next],
+                Expected {
+                    round: 1,
+                    current: Some(1),
+                    order: vec![1, 2, 3],
+                    delayed: vec![],
+                    readied: vec![],
+                },
+            ),
+            (
+                "wrapping to the top starts the next round",
+                vec![20, 15, 10],
+                vec![Next, Next, Next, Next],
+                Expected {
+                    round: 2,
+                    current: Some(1),
+                    order: vec![1, 2, 3],
+                    delayed: vec![],
+                    readied: vec![],
+                },
+            ),
+            (
+                "delayed combatants are skipped",
+                vec![20, 15, 10],
+                vec![Next, Delay(1), Next, Next],
+                Expected {
+                    round: 2,
+                    current: Some(2),
+                    order: vec![1, 2, 3],
+                    delayed: vec![1],
+                    readied: vec![],
+                },
+            ),
+            (
+                "undelay goes right before whoever's turn it is",
+                vec![20, 15, 10],
+                vec![Next, Delay(1), Next, Undelay(1)],
+                Expected {
+                    round: 1,
+                    current: Some(1),
+                    order: vec![2, 1, 3],
+                    delayed: vec![],
+                    readied: vec![],
+                },
+            ),
+            (
+                "whoever got interrupted by an undelay goes next",
+                vec![20, 15, 10],
+                vec![Next, Delay(1), Next, Undelay(1), Next],
+                Expected {
+                    round: 1,
+                    current: Some(3),
+                    order: vec![2, 1, 3],
+                    delayed: vec![],
+                    readied: vec![],
+                },
+            ),
+            (
+                "readying ends the turn",
+                vec![20, 15, 10],
+                vec![Next, Ready(1)],
+                Expected {
+                    round: 1,
+                    current: Some(2),
+                    order: vec![1, 2, 3],
+                    delayed: vec![],
+                    readied: vec![1],
+                },
+            ),
+            (
+                "triggering uses up the readied action",
+                vec![20, 15, 10],
+                vec![Next, Ready(1), Trigger(1)],
+                Expected {
+                    round: 1,
+                    current: Some(2),
+                    order: vec![1, 2, 3],
+                    delayed: vec![],
+                    readied: vec![],
+                },
+            ),
+            (
+                "a readied action is gone on the combatant's next turn",
+                vec![20, 15, 10],
+                vec![Next, Ready(1), Next, Next],
+                Expected {
+                    round: 2,
+                    current: Some(1),
+                    order: vec![1, 2, 3],
+                    delayed: vec![],
+                    readied: vec![],
+                },
+            ),
+            (
+                "removing the current combatant hands the turn to the next one",
+                vec![20, 15, 10],
+                vec![Next, Next, Remove(2)],
+                Expected {
+                    round: 1,
+                    current: Some(3),
+                    order: vec![1, 3],
+                    delayed: vec![],
+                    readied: vec![],
+                },
+            ),
+            (
+                "removing the last one in the order wraps to the next round",
+                vec![20, 15, 10],
+                vec![Next, Next, Next, Remove(3)],
+                Expected {
+                    round: 2,
+                    current: Some(1),
+                    order: vec![1, 2],
+                    delayed: vec![],
+                    readied: vec![],
+                },
+            ),
+            (
+                "removing the current combatant when only delayed ones are left",
+                vec![20, 15, 10],
+                vec![Next, Delay(1), Delay(2), Remove(3)],
+                Expected {
+                    round: 1,
+                    current: None,
+                    order: vec![1, 2],
+                    delayed: vec![1, 2],
+                    readied: vec![],
+                },
+            ),
+            (
+                "removing someone else keeps the turn",
+                vec![20, 15, 10],
+                vec![Next, Remove(3), Remove(2)],
+                Expected {
+                    round: 1,
+                    current: Some(1),
+                    order: vec![1],
+                    delayed: vec![],
+                    readied: vec![],
+                },
+            ),
+        ];
+
+        for (name, initiatives, steps, expected) in cases {
+            let mut encounter = encounter(&initiatives);
+            for step in steps {
+                run(&mut encounter, step).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            }
+            assert_eq!(encounter.round, expected.round, "{}", name);
+            assert_eq!(encounter.current_combatant_id, expected.current, "{}", name);
+            assert_eq!(ids(&encounter, |_| true), expected.order, "{}", name);
+            assert_eq!(ids(&encounter, |c| c.delayed), expected.delayed, "{}", name);
+            assert_eq!(
+                ids(&encounter, |c| c.readied.is_some()),
+                expected.readied,
+                "{}",
+                name
+            );
+            let positions: Vec<i32> = encounter.combatants.iter().map(|c| c.position).collect();
+            assert_eq!(
+                positions,
+                (0..encounter.combatants.len() as i32).collect::<Vec<_>>(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn turn_errors() {
+        let cases = [
+            (
+                "nobody in the encounter",
+                vec![],
+                vec![],
+                Next,
+                "Nobody can take a turn",
+            ),
+            (
+                "everyone delaying",
+                vec![20, 15],
+                vec![Next, Delay(1)],
+                Delay(2),
+                "Nobody can take a turn",
+            ),
+            (
+                "delaying on someone else's turn",
+                vec![20, 15],
+                vec![Next],
+                Delay(2),
+                "It isn't c2's turn",
+            ),
+            (
+                "readying on someone else's turn",
+                vec![20, 15],
+                vec![Next],
+                Ready(2),
+                "It isn't c2's turn",
+            ),
+            (
+                "undelaying someone that isn't delaying",
+                vec![20, 15],
+                vec![Next],
+                Undelay(2),
+                "c2 isn't delaying",
+            ),
+            (
+                "triggering without a readied action",
+                vec![20, 15],
+                vec![Next],
+                Trigger(2),
+                "c2 doesn't have an action readied",
+            ),
+            (
+                "removing someone that isn't there",
+                vec![20, 15],
+                vec![],
+                Remove(3),
+                "That combatant isn't in the encounter",
+            ),
+        ];
+
+        for (name, initiatives, steps, failing, message) in cases {
+            let mut encounter = encounter(&initiatives);
+            for step in steps {
+                run(&mut encounter, step).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            }
+            match run(&mut encounter, failing) {
+                Ok(_) => panic!("{}: {:?} should fail", name, failing),
+                Err(e) => assert_eq!(e.to_string(), message, "{}", name),
+            }
+        }
+    }
+
+    // Puts an effect that starts now on `combatant_id`, like api::encounters::add_effect
+    fn add_effect_now(
+        encounter: &mut Encounter,
+        combatant_id: i32,
+        name: &str,
+        rounds: i32,
+        ends_at_end_of_turn: bool,
+    ) {
+        let (ends_round, ends_on_turn_of) = effect_end(encounter, rounds);
+        let id = encounter
+            .combatants
+            .iter()
+            .map(|c| c.effects.len() as i32)
+            .sum::<i32>()
+            + 1;
+        add_effect(
+            encounter,
+            Effect {
+                id,
+                combatant_id: Some(combatant_id),
+                character_id: None,
+                name: name.to_string(),
+                source: None,
+                source_combatant_id: ends_on_turn_of,
+                duration_rounds: Some(rounds),
+                ends_round: Some(ends_round),
+                ends_on_turn_of,
+                ends_at_end_of_turn: ends_at_end_of_turn && ends_on_turn_of.is_some(),
+            },
+        )
+        .unwrap();
+    }
+
+    // Takes turns until `name` runs out, returns the (round, current combatant) it happened at
+    fn expires_at(encounter: &mut Encounter, name: &str) -> (i32, Option<i32>) {
+        for _ in 0..100 {
+            let expired = next_turn(encounter).unwrap();
+            if expired.iter().any(|effect| effect.name == name) {
+                return (encounter.round, encounter.current_combatant_id);
+            }
+        }
+        panic!("{} never ran out", name);
+    }
+
+    #[test]
+    fn effect_expiry() {
+        // (name, effect added on whose turn (0 for before the first turn), rounds, ends at the end
+        // of the turn, when it runs out)
+        let cases = [
+            (
+                "ends at the start of the source's next turn",
+                2,
+                1,
+                false,
+                (2, Some(2)),
+            ),
+            (
+                "ends at the end of the source's next turn",
+                2,
+                1,
+                true,
+                (2, Some(3)),
+            ),
+            (
+                "ends at the end of the source's turn on the last one in the order",
+                3,
+                1,
+                true,
+                (3, Some(1)),
+            ),
+            ("bless for 10 rounds", 1, 10, false, (11, Some(1))),
+            (
+                "1 minute is 10 rounds",
+                1,
+                Duration::Minutes(1).rounds(),
+                false,
+                (11, Some(1)),
+            ),
+            (
+                "before the first turn it ends when the round starts",
+                0,
+                2,
+                false,
+                (3, Some(1)),
+            ),
+            (
+                "ending at the end of the turn doesn't mean anything before the first turn",
+                0,
+                1,
+                true,
+                (2, Some(1)),
+            ),
+        ];
+
+        for (name, added_on, rounds, at_end, expected) in cases {
+            let mut encounter = encounter(&[20, 15, 10]);
+            for _ in 0..added_on {
+                next_turn(&mut encounter).unwrap();
+            }
+            add_effect_now(&mut encounter, 3, "effect", rounds, at_end);
+            assert_eq!(expires_at(&mut encounter, "effect"), expected, "{}", name);
+            assert!(encounter.combatants[2].effects.is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(Duration::Rounds(3).rounds(), 3);
+        assert_eq!(Duration::Minutes(1).rounds(), 10);
+        assert_eq!(Duration::Minutes(10).rounds(), 100);
+    }
+
+    #[test]
+    fn effect_ends_even_if_the_source_skips_its_turn() {
+        let mut encounter = encounter(&[20, 15, 10]);
+        next_turn(&mut encounter).unwrap();
+        next_turn(&mut encounter).unwrap();
+        add_effect_now(&mut encounter, 3, "effect", 1, false);
+        next_turn(&mut encounter).unwrap();
+        next_turn(&mut encounter).unwrap();
+        // 2 delays through round 2, so it ends when round 3 starts
+        encounter.combatants[1].delayed = true;
+        assert_eq!(expires_at(&mut encounter, "effect"), (3, Some(1)));
+
+        // the source is gone, so it ends when the round starts
+        let mut removed = self::encounter(&[20, 15, 10]);
+        next_turn(&mut removed).unwrap();
+        next_turn(&mut removed).unwrap();
+        add_effect_now(&mut removed, 3, "effect", 5, true);
+        remove(&mut removed, 2).unwrap();
+        assert_eq!(expires_at(&mut removed, "effect"), (6, Some(1)));
+    }
+
+    #[test]
+    fn reminders_fire_once_per_turn() {
+        let mut encounter = encounter(&[20, 15]);
+        next_turn(&mut encounter).unwrap();
+        add_effect_now(&mut encounter, 2, "poisoned", 3, false);
+
+        let mut reminders = Vec::new();
+        let steps = [
+            Next,
+            Trigger(1),
+            Next,
+            Ready(1),
+            Trigger(1),
+            Next,
+            Next,
+            Next,
+        ];
+        for step in steps {
+            let turn = (encounter.round, encounter.current_combatant_id);
+            // a failed step doesn't change anything, so it can't remind of anything either
+            let _ = run(&mut encounter, step);
+            if let Some(combatant) = reminder(&encounter, turn) {
+                reminders.push((encounter.round, combatant.id));
+            }
+            // asking again without another change doesn't remind again
+            let turn = (encounter.round, encounter.current_combatant_id);
+            assert!(reminder(&encounter, turn).is_none());
+        }
+        // 2's turns in rounds 1, 2 and 3, it's gone before its turn in round 4
+        assert_eq!(reminders, vec![(1, 2), (2, 2), (3, 2)]);
+        assert!(encounter.combatants[1].effects.is_empty());
+    }
+}
@@ -1,47 +1,557 @@
-use lazy_static::lazy_static;
 use serde_derive::Deserialize;
-use std::fs;
-use toml;
+use std::{env, fmt, fs, path::Path};
 
-lazy_static! {
-    pub static ref config: Config = Config::new("./config.toml");
-}
-
-// this is the basic config, you can later on access the values with config.global.discord_client
-#[derive(Deserialize)]
+// Loaded once in main and handed to handlers as web::Data<Config>. Values come from the toml file
+// first, then any DND__<SECTION>__<KEY> environment variables on top of that, e.g.
+// DND__GLOBAL__DATABASE_URL=postgres://... or DND__SERVER__PORT=9000
+#[derive(Deserialize, Clone)]
 pub struct Config {
     pub global: Global,
+    #[serde(default)]
+    pub server: Server,
+    #[serde(default)]
+    pub database: Database,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct Global {
+    #[serde(default)]
     pub discord_client: String,
+    #[serde(default)]
     pub discord_secret: String,
     pub database_url: String,
+    // oauth endpoints, these default to discord's but can point at the mock-oauth binary
+    #[serde(default = "default_authorize_url")]
+    pub discord_authorize_url: String,
+    #[serde(default = "default_token_url")]
+    pub discord_token_url: String,
+    #[serde(default = "default_revocation_url")]
+    pub discord_revocation_url: String,
+    #[serde(default = "default_profile_url")]
+    pub discord_profile_url: String,
+    // where the provider sends the browser back to after logging in, defaults to public_url + /login
+    pub oauth_redirect_url: Option<String>,
+    // which IdentityProvider users log in with, "discord" (default) or "dev"
+    #[serde(default)]
+    pub identity_provider: IdentityProviderKind,
+    // users the dev provider can log in as, set with [[global.dev_users]]
+    #[serde(default)]
+    pub dev_users: Vec<DevUser>,
 }
 
-fn open_config(path: &str) -> Config {
-    let contents = match fs::read_to_string(path) {
-        Ok(c) => c,
-        Err(e) => {
-            panic!("Couldn't open config file due to {e}");
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Server {
+    pub bind_address: String,
+    pub port: u16,
+    // defaults to one per cpu core, same as actix
+    pub workers: Option<usize>,
+    // env_logger filter, so "info" or something like "info,sqlx=warn" both work
+    pub log_level: String,
+    // origins allowed to make cross origin requests, empty means only same origin
+    pub cors_origins: Vec<String>,
+    // where this server can be reached from outside, used when building links back to it
+    pub public_url: String,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            bind_address: String::from("127.0.0.1"),
+            port: 8080,
+            workers: None,
+            log_level: String::from("info"),
+            cors_origins: Vec::new(),
+            public_url: String::from("http://localhost:8080"),
         }
-    };
+    }
+}
+
+// Connection pool settings, the url itself is global.database_url
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Database {
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout_secs: u64,
+    pub idle_timeout_secs: u64,
+    // apply the embedded migrations before the server starts, otherwise use `migrate run`
+    pub migrate_on_startup: bool,
+}
 
-    match toml::from_str(&contents) {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("{e}");
-            panic!("Unable to load data from {}", path);
+impl Default for Database {
+    fn default() -> Self {
+        Database {
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout_secs: 30,
+            idle_timeout_secs: 600,
+            migrate_on_startup: true,
         }
     }
 }
 
+fn default_authorize_url() -> String {
+    String::from("https://discord.com/oauth2/authorize")
+}
+
+fn default_token_url() -> String {
+    String::from("https://discord.com/api/oauth2/token")
+}
+
+fn default_revocation_url() -> String {
+    String::from("https://discord.com/api/oauth2/token/revoke")
+}
+
+fn default_profile_url() -> String {
+    String::from("https://discord.com/api/users/@me")
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityProviderKind {
+    #[default]
+    Discord,
+    Dev,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DevUser {
+    pub id: String,
+    pub username: String,
+    pub global_name: Option<String>,
+}
+
+pub const DEFAULT_PATH: &str = "./config.toml";
+const ENV_PREFIX: &str = "DND__";
+
+// Everything that's wrong with the config, printed as a list on startup
+#[derive(Debug)]
+pub struct ConfigError {
+    pub source: String,
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid config ({}):", self.source)?;
+        for problem in &self.problems {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
 impl Config {
-    pub fn new(path: &str) -> Self {
-        let data = open_config(path);
-        Config {
-            global: data.global,
+    // `path` is the --config argument, without it ./config.toml is used if it exists so the whole
+    // config can also come from the environment
+    pub fn load(path: Option<&str>) -> Result<Config, ConfigError> {
+        Config::load_with(path, false)
+    }
+
+    // load plus the settings only the server needs, so `migrate` and dnd-admin work without oauth
+    // credentials
+    pub fn load_server(path: Option<&str>) -> Result<Config, ConfigError> {
+        Config::load_with(path, true)
+    }
+
+    fn load_with(path: Option<&str>, server: bool) -> Result<Config, ConfigError> {
+        let file = path.unwrap_or(DEFAULT_PATH);
+        let source = format!("{} + environment", file);
+        let error = |problems: Vec<String>| ConfigError {
+            source: source.clone(),
+            problems,
+        };
+
+        let mut table = if path.is_none() && !Path::new(file).exists() {
+            toml::Table::new()
+        } else {
+            let contents = fs::read_to_string(file)
+                .map_err(|e| error(vec![format!("couldn't read {}: {}", file, e)]))?;
+            contents
+                .parse::<toml::Table>()
+                .map_err(|e| error(vec![format!("couldn't parse {}: {}", file, e)]))?
+        };
+
+        apply_env_overrides(&mut table, env::vars());
+
+        let config: Config = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e: toml::de::Error| error(vec![e.message().to_string()]))?;
+
+        let mut problems = config.validate();
+        if server {
+            problems.extend(config.validate_server());
         }
+        if !problems.is_empty() {
+            return Err(error(problems));
+        }
+
+        Ok(config)
+    }
+
+    pub fn oauth_redirect_url(&self) -> String {
+        match &self.global.oauth_redirect_url {
+            Some(url) => url.clone(),
+            None => format!("{}/login", self.server.public_url.trim_end_matches('/')),
+        }
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let global = &self.global;
+        let server = &self.server;
+
+        if global.database_url.trim().is_empty() {
+            problems.push(String::from("global.database_url can't be empty"));
+        }
+
+        if server.port == 0 {
+            problems.push(String::from("server.port can't be 0"));
+        }
+        if server.workers == Some(0) {
+            problems.push(String::from("server.workers has to be at least 1"));
+        }
+
+        let database = &self.database;
+        if database.max_connections == 0 {
+            problems.push(String::from(
+                "database.max_connections has to be at least 1",
+            ));
+        }
+        if database.min_connections > database.max_connections {
+            problems.push(String::from(
+                "database.min_connections can't be more than database.max_connections",
+            ));
+        }
+        if database.acquire_timeout_secs == 0 {
+            problems.push(String::from("database.acquire_timeout_secs can't be 0"));
+        }
+
+        // the same parser env_logger uses, so anything it accepts is fine here too
+        if let Err(e) = env_filter::Builder::new().try_parse(&server.log_level) {
+            problems.push(format!("server.log_level is invalid: {}", e));
+        }
+
+        for origin in &server.cors_origins {
+            if origin != "*" && oauth2::url::Url::parse(origin).is_err() {
+                problems.push(format!(
+                    "server.cors_origins entry \"{}\" isn't a valid origin",
+                    origin
+                ));
+            }
+        }
+
+        problems
+    }
+
+    // identity provider settings, only checked when starting the server
+    pub fn validate_server(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let global = &self.global;
+        let server = &self.server;
+
+        match global.identity_provider {
+            IdentityProviderKind::Discord => {
+                if global.discord_client.is_empty() || global.discord_secret.is_empty() {
+                    problems.push(String::from(
+                        "global.discord_client and global.discord_secret are required for the discord identity provider",
+                    ));
+                }
+            }
+            IdentityProviderKind::Dev => {
+                if global.dev_users.is_empty() {
+                    problems.push(String::from(
+                        "global.dev_users needs at least one user for the dev identity provider",
+                    ));
+                }
+            }
+        }
+
+        let urls = [
+            (
+                "global.discord_authorize_url",
+                &global.discord_authorize_url,
+            ),
+            ("global.discord_token_url", &global.discord_token_url),
+            (
+                "global.discord_revocation_url",
+                &global.discord_revocation_url,
+            ),
+            ("global.discord_profile_url", &global.discord_profile_url),
+            ("server.public_url", &server.public_url),
+        ];
+        for (name, value) in urls {
+            if let Err(e) = oauth2::url::Url::parse(value) {
+                problems.push(format!("{} ({}) isn't a valid url: {}", name, value, e));
+            }
+        }
+        if let Err(e) = oauth2::url::Url::parse(&self.oauth_redirect_url()) {
+            problems.push(format!(
+                "global.oauth_redirect_url isn't a valid url: {}",
+                e
+            ));
+        }
+
+        problems
+    }
+}
+
+// Settings that aren't strings, their env values are parsed as toml (9000, true, ["a"]). Everything
+// else is used as is, so an all-digit discord_client or password doesn't turn into an integer
+const TOML_KEYS: &[&str] = &[
+    "global.dev_users",
+    "server.port",
+    "server.workers",
+    "server.cors_origins",
+    "database.max_connections",
+    "database.min_connections",
+    "database.acquire_timeout_secs",
+    "database.idle_timeout_secs",
+    "database.migrate_on_startup",
+];
+
+// DND__SERVER__PORT=9000 ends up as [server] port = 9000. Values of TOML_KEYS are parsed as toml
+// when possible and everything else is a plain string
+fn apply_env_overrides(table: &mut toml::Table, vars: impl Iterator<Item = (String, String)>) {
+    for (key, raw) in vars {
+        let Some(path) = key.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+
+        let parts: Vec<String> = path.split("__").map(|p| p.to_lowercase()).collect();
+        if parts.iter().any(|p| p.is_empty()) {
+            continue;
+        }
+
+        let value = if TOML_KEYS.contains(&parts.join(".").as_str()) {
+            format!("value = {}", raw)
+                .parse::<toml::Table>()
+                .ok()
+                .and_then(|mut t| t.remove("value"))
+                .unwrap_or(toml::Value::String(raw))
+        } else {
+            toml::Value::String(raw)
+        };
+
+        let (last, sections) = parts.split_last().unwrap();
+        let mut current = &mut *table;
+        for section in sections {
+            let entry = current
+                .entry(section.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::Table::new());
+            }
+            current = entry.as_table_mut().unwrap();
+        }
+        current.insert(last.clone(), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(vars: &[(&str, &str)]) -> toml::Table {
+        let mut table = toml::Table::new();
+        apply_env_overrides(
+            &mut table,
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+        table
+    }
+
+    // a working discord config with `vars` applied on top, the same way load does it
+    fn config(vars: &[(&str, &str)]) -> Config {
+        let mut table: toml::Table = "[global]\ndatabase_url = \"postgres://localhost/dnd\"\ndiscord_client = \"id\"\ndiscord_secret = \"secret\""
+            .parse()
+            .unwrap();
+        apply_env_overrides(
+            &mut table,
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+        toml::Value::Table(table).try_into().unwrap()
+    }
+
+    #[test]
+    fn env_values_are_parsed_as_toml() {
+        let table = overrides(&[
+            ("DND__SERVER__PORT", "9000"),
+            ("DND__SERVER__CORS_ORIGINS", "[\"a\"]"),
+            ("DND__DATABASE__MIGRATE_ON_STARTUP", "false"),
+        ]);
+        assert_eq!(table["server"]["port"], toml::Value::Integer(9000));
+        assert_eq!(
+            table["server"]["cors_origins"],
+            toml::Value::Array(vec![toml::Value::String(String::from("a"))])
+        );
+        assert_eq!(
+            table["database"]["migrate_on_startup"],
+            toml::Value::Boolean(false)
+        );
+    }
+
+    #[test]
+    fn unparseable_env_values_are_strings() {
+        let table = overrides(&[
+            ("DND__GLOBAL__DATABASE_URL", "postgres://user@host/dnd"),
+            ("DND__SERVER__LOG_LEVEL", "info,sqlx=warn"),
+            ("DND__SERVER__PUBLIC_URL", "[not an array"),
+        ]);
+        let string = |s: &str| toml::Value::String(String::from(s));
+        assert_eq!(
+            table["global"]["database_url"],
+            string("postgres://user@host/dnd")
+        );
+        assert_eq!(table["server"]["log_level"], string("info,sqlx=warn"));
+        assert_eq!(table["server"]["public_url"], string("[not an array"));
+    }
+
+    #[test]
+    fn string_settings_stay_strings() {
+        let config = config(&[
+            ("DND__GLOBAL__DISCORD_CLIENT", "1234567890123456789"),
+            ("DND__GLOBAL__DISCORD_SECRET", "12345"),
+            ("DND__SERVER__LOG_LEVEL", "true"),
+        ]);
+        assert_eq!(config.global.discord_client, "1234567890123456789");
+        assert_eq!(config.global.discord_secret, "12345");
+        assert_eq!(config.server.log_level, "true");
+    }
+
+    #[test]
+    fn env_overrides_keep_the_rest_of_the_file() {
+        let mut table: toml::Table =
+            "[server]\nport = 8080\nbind_address = \"0.0.0.0\"\nworkers = 2"
+                .parse()
+                .unwrap();
+        apply_env_overrides(
+            &mut table,
+            [
+                (String::from("DND__SERVER__PORT"), String::from("9000")),
+                (String::from("OTHER__SERVER__WORKERS"), String::from("4")),
+                (String::from("DND__SERVER____WORKERS"), String::from("4")),
+                (String::from("DND__SERVER__WORKERS__X"), String::from("4")),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(table["server"]["port"], toml::Value::Integer(9000));
+        assert_eq!(
+            table["server"]["bind_address"],
+            toml::Value::String(String::from("0.0.0.0"))
+        );
+        // a scalar in the way gets replaced by a table
+        assert!(table["server"]["workers"].is_table());
+    }
+
+    // everything load_server checks
+    fn server_problems(config: &Config) -> Vec<String> {
+        let mut problems = config.validate();
+        problems.extend(config.validate_server());
+        problems
+    }
+
+    #[test]
+    fn valid_config_has_no_problems() {
+        assert!(server_problems(&config(&[])).is_empty());
+        let dev = config(&[
+            ("DND__GLOBAL__IDENTITY_PROVIDER", "dev"),
+            (
+                "DND__GLOBAL__DEV_USERS",
+                "[{ id = \"1\", username = \"a\" }]",
+            ),
+        ]);
+        assert!(server_problems(&dev).is_empty());
+    }
+
+    #[test]
+    fn only_the_server_needs_credentials() {
+        let config = config(&[
+            ("DND__GLOBAL__DISCORD_CLIENT", ""),
+            ("DND__GLOBAL__DISCORD_SECRET", ""),
+        ]);
+        assert!(config.validate().is_empty());
+        assert!(!config.validate_server().is_empty());
+    }
+
+    #[test]
+    fn log_level_takes_anything_env_logger_does() {
+        for level in [
+            "debug",
+            "dnd_thing_server",
+            "dnd_thing_server=debug",
+            "info,sqlx=warn,dnd_thing_server::ws",
+            "warn/some regex",
+        ] {
+            let problems = config(&[("DND__SERVER__LOG_LEVEL", level)]).validate();
+            assert!(problems.is_empty(), "{}: {:?}", level, problems);
+        }
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let cases = [
+            ("DND__GLOBAL__DATABASE_URL", " ", "global.database_url"),
+            ("DND__GLOBAL__DISCORD_CLIENT", "", "global.discord_client"),
+            ("DND__GLOBAL__IDENTITY_PROVIDER", "dev", "global.dev_users"),
+            (
+                "DND__GLOBAL__DISCORD_TOKEN_URL",
+                "nope",
+                "global.discord_token_url",
+            ),
+            (
+                "DND__GLOBAL__OAUTH_REDIRECT_URL",
+                "nope",
+                "global.oauth_redirect_url",
+            ),
+            ("DND__SERVER__PUBLIC_URL", "nope", "server.public_url"),
+            ("DND__SERVER__PORT", "0", "server.port"),
+            ("DND__SERVER__WORKERS", "0", "server.workers"),
+            (
+                "DND__SERVER__LOG_LEVEL",
+                "info,sqlx=loud",
+                "server.log_level",
+            ),
+            (
+                "DND__SERVER__CORS_ORIGINS",
+                "[\"nope\"]",
+                "server.cors_origins",
+            ),
+            (
+                "DND__DATABASE__MAX_CONNECTIONS",
+                "0",
+                "database.max_connections",
+            ),
+            (
+                "DND__DATABASE__MIN_CONNECTIONS",
+                "20",
+                "database.min_connections",
+            ),
+            (
+                "DND__DATABASE__ACQUIRE_TIMEOUT_SECS",
+                "0",
+                "database.acquire_timeout_secs",
+            ),
+        ];
+        for (key, value, field) in cases {
+            let problems = server_problems(&config(&[(key, value)]));
+            assert!(
+                problems.iter().any(|p| p.starts_with(field)),
+                "{}={}: expected a problem with {}, got {:?}",
+                key,
+                value,
+                field,
+                problems
+            );
+        }
+
+        let problems = server_problems(&config(&[
+            ("DND__SERVER__PORT", "0"),
+            ("DND__SERVER__WORKERS", "0"),
+        ]));
+        assert_eq!(problems.len(), 2, "{:?}", problems);
     }
 }
@@ -1,131 +1,292 @@
 use rand::{distributions::Alphanumeric, Rng};
-use serde::Serialize;
-// make a table to store dnd sessions (needs to be something other than sessions obv)
-// the name can be autogenerated but it needs to have a unique id
-// messages will be stored in a json file, see if its possible to put a function reference in a mutex on the app state (to theoretically make sure everything goes in order)
-use sqlx::{Error, Pool, Postgres};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPoolOptions, Error, PgConnection, Pool, Postgres};
+use std::{fmt, time::Duration};
 
-use crate::{DiscordUser, UserSession};
+use sha2::{Digest, Sha256};
 
+use crate::{config::Config, dice::RollResult, DiscordUser};
+
+pub async fn connect(config: &Config) -> Result<Pool<Postgres>, Error> {
+    let database = &config.database;
+    PgPoolOptions::new()
+        .max_connections(database.max_connections)
+        .min_connections(database.min_connections)
+        .acquire_timeout(Duration::from_secs(database.acquire_timeout_secs))
+        .idle_timeout(Duration::from_secs(database.idle_timeout_secs))
+        .connect(&config.global.database_url)
+        .await
+}
+
+pub struct UserId {
+    pub id: i32,
+}
+
+// Creates the user for a discord account logging in for the first time. If another login for the
+// same account got there first this uses (and updates) that user instead
 pub async fn add_user(
     conn: &Pool<Postgres>,
     user: &DiscordUser,
-    access_token: &str,
-    refresh_token: &str,
-) -> Result<UserSession, Error> {
-    let user_response = sqlx::query!(
-        "INSERT INTO users (username, access_token, refresh_token) VALUES ($1, $2, $3) RETURNING id",
-        user.username,access_token,refresh_token
+    tokens: &AccessTokens,
+) -> Result<UserId, Error> {
+    let mut tx = conn.begin().await?;
+
+    let new_id = sqlx::query_scalar!(
+        "INSERT INTO users (username, discord_access_token, discord_refresh_token, discord_token_expires_at) VALUES ($1, $2, $3, $4) RETURNING id",
+        user.username,tokens.access_token,tokens.refresh_token,tokens.expires_at
     )
-    .fetch_one(conn)
+    .fetch_one(&mut *tx)
     .await?;
 
-    match sqlx::query!("INSERT INTO session (user_id, discord_id, username, discriminator, global_name, avatar, accent_color) VALUES ($1, $2, $3, $4, $5, $6, $7)", user_response.id, user.id, user.username, user.discriminator, user.global_name.as_ref().unwrap_or(&user.username), user.avatar, user.accent_color).execute(conn).await {
-            Ok(_) => {
-                return Ok(UserSession{access_token: access_token.to_string(), refresh_token: refresh_token.to_string(), session: user.clone()});
-            }
-            Err(err) => {
-                dbg!(&err);
-                return Err(err);
-            }
-        };
-}
+    // waits for a concurrent insert of the same account and then returns its user
+    let user_id = sqlx::query_scalar!(
+        "
+            INSERT INTO session (user_id, discord_id, username, discriminator, global_name, avatar, accent_color) VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (discord_id) DO UPDATE SET username = EXCLUDED.username, discriminator = EXCLUDED.discriminator,
+                global_name = EXCLUDED.global_name, avatar = EXCLUDED.avatar, accent_color = EXCLUDED.accent_color
+            RETURNING user_id
+        ",
+        new_id,
+        user.id,
+        user.username,
+        user.discriminator,
+        user.global_name.as_ref().unwrap_or(&user.username),
+        user.avatar.as_deref().unwrap_or_default(),
+        user.accent_color
+    )
+    .fetch_one(&mut *tx)
+    .await?;
 
-pub struct AccessTokens {
-    pub access_token: String,
-    pub refresh_token: String,
+    if user_id == new_id {
+        tx.commit().await?;
+    } else {
+        // drops the users row made above
+        tx.rollback().await?;
+        update_user(conn, user_id, user, tokens).await?;
+    }
+
+    Ok(UserId { id: user_id })
 }
 
-pub async fn get_session_token(
+// Called when an existing user logs in again, stores the new discord tokens and profile
+pub async fn update_user(
     conn: &Pool<Postgres>,
-    access_token: &str,
-) -> Result<UserSession, Error> {
-    let tokens = sqlx::query_as!(
-        AccessTokens,
-        "SELECT access_token, refresh_token FROM users WHERE access_token = $1",
-        access_token
+    user_id: i32,
+    user: &DiscordUser,
+    tokens: &AccessTokens,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "UPDATE users SET username = $2 WHERE id = $1",
+        user_id,
+        user.username
     )
-    .fetch_one(conn)
+    .execute(conn)
     .await?;
 
-    let session = sqlx::query_as!(
-        DiscordUser,
-        "
-            SELECT discord_id AS id, username, discriminator, global_name, avatar, accent_color FROM session WHERE user_id = (SELECT id FROM users WHERE access_token = $1)
-        ",
-        access_token
+    update_discord_tokens(conn, user_id, tokens).await?;
+
+    sqlx::query!(
+        "UPDATE session SET username = $2, discriminator = $3, global_name = $4, avatar = $5, accent_color = $6 WHERE user_id = $1",
+        user_id,
+        user.username,
+        user.discriminator,
+        user.global_name.as_ref().unwrap_or(&user.username),
+        user.avatar.as_deref().unwrap_or_default(),
+        user.accent_color
     )
-    .fetch_one(conn)
+    .execute(conn)
     .await?;
 
-    return Ok(UserSession {
-        access_token: tokens.access_token,
-        refresh_token: tokens.refresh_token,
-        session,
-    });
+    Ok(())
+}
+
+// None if the account hasn't logged in before
+pub async fn get_user_by_discord_id(
+    conn: &Pool<Postgres>,
+    discord_id: &str,
+) -> Result<Option<UserId>, Error> {
+    sqlx::query_as!(
+        UserId,
+        "SELECT user_id AS id FROM session WHERE discord_id = $1",
+        discord_id
+    )
+    .fetch_optional(conn)
+    .await
 }
 
-pub async fn get_session_id(conn: &Pool<Postgres>, id: &str) -> Result<UserSession, Error> {
-    let session = sqlx::query_as!(
+pub async fn get_profile(conn: &Pool<Postgres>, user_id: i32) -> Result<DiscordUser, Error> {
+    sqlx::query_as!(
         DiscordUser,
         "
-            SELECT discord_id AS id, username, discriminator, global_name, avatar, accent_color FROM session WHERE discord_id = $1
+            SELECT discord_id AS id, username, discriminator, global_name, avatar, accent_color FROM session WHERE user_id = $1
         ",
-        id
+        user_id
     )
     .fetch_one(conn)
-    .await?;
+    .await
+}
+
+// Discord's tokens, these never leave the server
+pub struct AccessTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+}
 
-    let tokens = sqlx::query_as!(
+pub async fn get_discord_tokens(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+) -> Result<AccessTokens, Error> {
+    sqlx::query_as!(
         AccessTokens,
-        "SELECT access_token, refresh_token FROM users WHERE EXISTS (SELECT user_id FROM session WHERE discord_id = $1)",
-        id
+        "SELECT discord_access_token AS access_token, discord_refresh_token AS refresh_token, discord_token_expires_at AS expires_at FROM users WHERE id = $1",
+        user_id
     )
     .fetch_one(conn)
+    .await
+}
+
+pub async fn update_discord_tokens(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+    tokens: &AccessTokens,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "UPDATE users SET discord_access_token = $2, discord_refresh_token = $3, discord_token_expires_at = $4, refreshed_at = CURRENT_TIMESTAMP WHERE id = $1",
+        user_id,
+        tokens.access_token,
+        tokens.refresh_token,
+        tokens.expires_at
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+// After the grant is revoked on discord's side the old tokens are useless, they get replaced on the next login
+pub async fn clear_discord_tokens(conn: &Pool<Postgres>, user_id: i32) -> Result<(), Error> {
+    sqlx::query!(
+        "UPDATE users SET discord_access_token = '', discord_refresh_token = '', discord_token_expires_at = NULL WHERE id = $1",
+        user_id
+    )
+    .execute(conn)
     .await?;
 
-    return Ok(UserSession {
-        access_token: tokens.access_token,
-        refresh_token: tokens.refresh_token,
-        session,
-    });
+    Ok(())
 }
 
-pub async fn refresh_tokens(
+// Users that are still logged in somewhere and whose discord token expires before `before`
+pub async fn get_users_with_expiring_tokens(
     conn: &Pool<Postgres>,
-    access_token: &str,
-    new_access_token: &str,
-    new_refresh_token: &str,
-) -> Result<AccessTokens, Error> {
+    before: chrono::NaiveDateTime,
+) -> Result<Vec<UserId>, Error> {
+    sqlx::query_as!(
+        UserId,
+        "
+            SELECT id FROM users
+            WHERE discord_refresh_token <> '' AND (discord_token_expires_at IS NULL OR discord_token_expires_at < $1)
+            AND EXISTS (SELECT 1 FROM session_tokens WHERE user_id = users.id AND expires_at > (now() AT TIME ZONE 'utc'))
+        ",
+        before
+    )
+    .fetch_all(conn)
+    .await
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub struct NewSessionToken {
+    pub token: String,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+// Makes a new token for one device, the plain token is only returned here and never stored
+pub async fn create_session_token(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+    device: Option<&str>,
+    lifetime: chrono::Duration,
+) -> Result<NewSessionToken, Error> {
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect();
+    let expires_at = chrono::Utc::now().naive_utc() + lifetime;
+
     sqlx::query!(
-        "UPDATE users SET access_token = $1, refresh_token = $2 WHERE access_token = $3",
-        new_access_token,
-        new_refresh_token,
-        access_token
+        "INSERT INTO session_tokens (user_id, token_hash, device, expires_at) VALUES ($1, $2, $3, $4)",
+        user_id,
+        hash_token(&token),
+        device,
+        expires_at
     )
     .execute(conn)
     .await?;
 
-    Ok(AccessTokens {
-        access_token: new_access_token.to_string(),
-        refresh_token: new_refresh_token.to_string(),
-    })
+    Ok(NewSessionToken { token, expires_at })
 }
 
-pub struct UserId {
-    id: i32,
+pub struct TokenSession {
+    pub user_id: i32,
+    pub session_id: i32,
+    pub expires_at: chrono::NaiveDateTime,
 }
 
-pub async fn get_user_id(conn: &Pool<Postgres>, access_token: &str) -> Result<UserId, Error> {
-    let user = sqlx::query_as!(
-        UserId,
-        "SELECT id FROM users WHERE access_token = $1",
-        access_token
+// Looks up the user a client token belongs to, expired tokens are treated as missing
+pub async fn get_token_session(conn: &Pool<Postgres>, token: &str) -> Result<TokenSession, Error> {
+    sqlx::query_as!(
+        TokenSession,
+        "
+            UPDATE session_tokens SET last_used_at = CURRENT_TIMESTAMP
+            WHERE token_hash = $1 AND expires_at > (now() AT TIME ZONE 'utc')
+            RETURNING user_id, id AS session_id, expires_at
+        ",
+        hash_token(token)
+    )
+    .fetch_one(conn)
+    .await
+}
+
+pub async fn delete_session_token(conn: &Pool<Postgres>, session_id: i32) -> Result<(), Error> {
+    sqlx::query!("DELETE FROM session_tokens WHERE id = $1", session_id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_user_session_tokens(conn: &Pool<Postgres>, user_id: i32) -> Result<(), Error> {
+    sqlx::query!("DELETE FROM session_tokens WHERE user_id = $1", user_id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn count_session_tokens(conn: &Pool<Postgres>, user_id: i32) -> Result<i64, Error> {
+    let res = sqlx::query!(
+        "SELECT COUNT(*) AS \"count!\" FROM session_tokens WHERE user_id = $1 AND expires_at > (now() AT TIME ZONE 'utc')",
+        user_id
     )
     .fetch_one(conn)
     .await?;
 
-    Ok(user)
+    Ok(res.count)
+}
+
+// A member's role in a campaign, see permissions.rs for what each one can do
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[sqlx(type_name = "campaign_role", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum CampaignRole {
+    Owner,
+    CoDm,
+    Player,
+    Spectator,
 }
 
 #[derive(Serialize)]
@@ -140,133 +301,377 @@ pub struct DndCampaign {
 
 #[derive(Serialize)]
 pub struct DndSession {
-    id: i32,
+    pub id: i32,
     user_id: i32,
-    campaign_id: i32,
+    pub campaign_id: i32,
     name: String,
     created_at: Option<chrono::NaiveDateTime>,
     last_updated: Option<chrono::NaiveDateTime>,
 }
 
+fn generate_invite_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
+
 pub async fn create_dnd_campaign(
     conn: &Pool<Postgres>,
-    access_token: &str,
+    user_id: i32,
     name: &str,
 ) -> Result<DndCampaign, Error> {
-    let user_id = get_user_id(conn, access_token).await?;
+    // a campaign without its owner in campaign_players would be stuck
+    let mut tx = conn.begin().await?;
 
     let res = sqlx::query_as!(
         DndCampaign,
         "INSERT INTO campaign (user_id, name) VALUES ($1, $2) RETURNING *",
-        user_id.id,
+        user_id,
         name
     )
-    .fetch_one(conn)
+    .fetch_one(&mut *tx)
     .await?;
 
-    let invite_code: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(16)
-        .map(char::from)
-        .collect();
-
     sqlx::query!(
-        "INSERT INTO campaign_invites (campaign_id, invite) VALUES ($1, $2)",
+        "INSERT INTO campaign_invites (campaign_id, invite, created_by) VALUES ($1, $2, $3)",
         res.id,
-        invite_code
+        generate_invite_code(),
+        user_id
     )
-    .execute(conn)
+    .execute(&mut *tx)
     .await?;
 
     sqlx::query!(
         "INSERT INTO campaign_players (campaign_id, player_id, role) VALUES ($1, $2, $3)",
         res.id,
-        user_id.id,
-        "dm"
+        user_id,
+        CampaignRole::Owner as CampaignRole
     )
-    .execute(conn)
+    .execute(&mut *tx)
     .await?;
 
+    tx.commit().await?;
     Ok(res)
 }
 
+#[derive(Serialize)]
+pub struct CampaignInvite {
+    pub id: i32,
+    pub campaign_id: i32,
+    pub invite: String,
+    // what whoever joins with it becomes
+    pub role: CampaignRole,
+    pub uses: i32,
+    // None for no limit
+    pub max_uses: Option<i32>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub created_by: Option<i32>,
+    pub created_at: Option<chrono::NaiveDateTime>,
+}
+
+// Why joining with an invite didn't work
+#[derive(Debug)]
+pub enum JoinError {
+    InvalidInvite,
+    Expired,
+    Exhausted,
+    AlreadyMember,
+    Banned,
+    Database(Error),
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::InvalidInvite => f.write_str("Invite not found"),
+            JoinError::Expired => f.write_str("Invite has expired"),
+            JoinError::Exhausted => f.write_str("Invite has no more uses"),
+            JoinError::AlreadyMember => f.write_str("You're already in this campaign"),
+            JoinError::Banned => f.write_str("You're banned from this campaign"),
+            JoinError::Database(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+impl From<Error> for JoinError {
+    fn from(err: Error) -> Self {
+        JoinError::Database(err)
+    }
+}
+
 struct DndCampaignInvite {
+    id: i32,
     campaign_id: i32,
-    invite: String,
-    created_at: Option<chrono::NaiveDateTime>,
-    uses: i32,
+    role: CampaignRole,
+    expired: bool,
+    exhausted: bool,
+}
+
+#[derive(Serialize)]
+pub struct JoinedCampaign {
+    pub campaign_id: i32,
+    pub role: CampaignRole,
 }
 
-// This needs to check for if the user is already in the campaign before adding them
-// (see if it's possible to combine that into one of the existing queries)
+// All in one transaction with the invite locked, so concurrent joins can't use it more times than
+// it allows. Someone already in the campaign gets AlreadyMember and doesn't use it up
 pub async fn join_dnd_campaign(
     conn: &Pool<Postgres>,
-    access_token: &str,
+    user_id: i32,
     invite_code: &str,
-) -> Result<bool, String> {
-    let user_id = get_user_id(conn, access_token)
-        .await
-        .map_err(|e| e.to_string())?;
+) -> Result<JoinedCampaign, JoinError> {
+    let mut tx = conn.begin().await?;
 
-    let campaign_invite = sqlx::query_as!(
+    let invite = sqlx::query_as!(
         DndCampaignInvite,
-        "SELECT * FROM campaign_invites WHERE invite = $1",
+        r#"
+            SELECT id, campaign_id, role AS "role: CampaignRole",
+                COALESCE(expires_at <= (now() AT TIME ZONE 'utc'), false) AS "expired!",
+                COALESCE(uses >= max_uses, false) AS "exhausted!"
+            FROM campaign_invites WHERE invite = $1
+            FOR UPDATE
+        "#,
         invite_code
     )
-    .fetch_one(conn)
-    .await
-    .map_err(|e| e.to_string())?;
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(JoinError::InvalidInvite)?;
+
+    let banned = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM campaign_bans WHERE campaign_id = $1 AND user_id = $2) AS "banned!""#,
+        invite.campaign_id,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if banned {
+        return Err(JoinError::Banned);
+    }
+    let member = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM campaign_players WHERE campaign_id = $1 AND player_id = $2) AS "member!""#,
+        invite.campaign_id,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if member {
+        return Err(JoinError::AlreadyMember);
+    }
+    if invite.expired {
+        return Err(JoinError::Expired);
+    }
+    if invite.exhausted {
+        return Err(JoinError::Exhausted);
+    }
 
-    if campaign_invite.uses == 0 {
-        return Err("Invite has no more uses".to_string());
+    // joining with another invite at the same time
+    let inserted = sqlx::query!(
+        "INSERT INTO campaign_players (campaign_id, player_id, role) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+        invite.campaign_id,
+        user_id,
+        invite.role as CampaignRole
+    )
+    .execute(&mut *tx)
+    .await?;
+    if inserted.rows_affected() == 0 {
+        return Err(JoinError::AlreadyMember);
     }
 
     sqlx::query!(
-        "UPDATE campaign_invites SET uses = $1 WHERE invite = $2",
-        campaign_invite.uses - 1,
-        invite_code
+        "UPDATE campaign_invites SET uses = uses + 1 WHERE id = $1",
+        invite.id
     )
-    .execute(conn)
-    .await
-    .map_err(|e| e.to_string())?;
+    .execute(&mut *tx)
+    .await?;
 
-    sqlx::query!(
-        "INSERT INTO campaign_players (campaign_id, player_id) VALUES ($1, $2)",
-        campaign_invite.campaign_id,
-        user_id.id
+    tx.commit().await?;
+
+    Ok(JoinedCampaign {
+        campaign_id: invite.campaign_id,
+        role: invite.role,
+    })
+}
+
+pub async fn get_campaign_invites(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+) -> Result<Vec<CampaignInvite>, Error> {
+    let res = sqlx::query_as!(
+        CampaignInvite,
+        r#"
+            SELECT id, campaign_id, invite, role AS "role: CampaignRole", uses, max_uses, expires_at, created_by, created_at
+            FROM campaign_invites WHERE campaign_id = $1
+            ORDER BY id
+        "#,
+        campaign_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res)
+}
+
+pub async fn get_campaign_invite(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    invite_id: i32,
+) -> Result<CampaignInvite, Error> {
+    let res = sqlx::query_as!(
+        CampaignInvite,
+        r#"
+            SELECT id, campaign_id, invite, role AS "role: CampaignRole", uses, max_uses, expires_at, created_by, created_at
+            FROM campaign_invites WHERE campaign_id = $1 AND id = $2
+        "#,
+        campaign_id,
+        invite_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Doesn't check who's asking, see permissions::can_invite_as
+pub async fn create_campaign_invite(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    created_by: i32,
+    role: CampaignRole,
+    max_uses: Option<i32>,
+    expires_at: Option<chrono::NaiveDateTime>,
+) -> Result<CampaignInvite, Error> {
+    let res = sqlx::query_as!(
+        CampaignInvite,
+        r#"
+            INSERT INTO campaign_invites (campaign_id, invite, role, max_uses, expires_at, created_by)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, campaign_id, invite, role AS "role: CampaignRole", uses, max_uses, expires_at, created_by, created_at
+        "#,
+        campaign_id,
+        generate_invite_code(),
+        role as CampaignRole,
+        max_uses,
+        expires_at,
+        created_by
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Gives the invite a new code so the old one stops working, everything else stays the same
+pub async fn regenerate_campaign_invite(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    invite_id: i32,
+) -> Result<CampaignInvite, Error> {
+    let res = sqlx::query_as!(
+        CampaignInvite,
+        r#"
+            UPDATE campaign_invites SET invite = $3, created_at = CURRENT_TIMESTAMP
+            WHERE campaign_id = $1 AND id = $2
+            RETURNING id, campaign_id, invite, role AS "role: CampaignRole", uses, max_uses, expires_at, created_by, created_at
+        "#,
+        campaign_id,
+        invite_id,
+        generate_invite_code()
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+pub async fn delete_campaign_invite(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    invite_id: i32,
+) -> Result<(), Error> {
+    let res = sqlx::query!(
+        "DELETE FROM campaign_invites WHERE campaign_id = $1 AND id = $2",
+        campaign_id,
+        invite_id
     )
     .execute(conn)
-    .await
-    .map_err(|e| e.to_string())?;
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+// What someone about to join with an invite gets to see, they aren't in the campaign yet
+#[derive(Serialize)]
+pub struct InvitePreview {
+    pub campaign_id: i32,
+    pub campaign_name: String,
+    pub image_link: Option<String>,
+    pub owner: String,
+    pub members: i64,
+    pub role: CampaignRole,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub expired: bool,
+    // None for no limit
+    pub uses_left: Option<i32>,
+    pub already_member: bool,
+    pub banned: bool,
+}
+
+pub async fn get_invite_preview(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+    invite_code: &str,
+) -> Result<InvitePreview, Error> {
+    let res = sqlx::query_as!(
+        InvitePreview,
+        r#"
+            SELECT c.id AS campaign_id, c.name AS campaign_name, c.image_link, u.username AS owner,
+                (SELECT COUNT(*) FROM campaign_players WHERE campaign_id = c.id) AS "members!",
+                i.role AS "role: CampaignRole", i.expires_at,
+                COALESCE(i.expires_at <= (now() AT TIME ZONE 'utc'), false) AS "expired!",
+                CASE WHEN i.max_uses IS NOT NULL THEN GREATEST(i.max_uses - i.uses, 0) END AS uses_left,
+                EXISTS (SELECT 1 FROM campaign_players WHERE campaign_id = c.id AND player_id = $2) AS "already_member!",
+                EXISTS (SELECT 1 FROM campaign_bans WHERE campaign_id = c.id AND user_id = $2) AS "banned!"
+            FROM campaign_invites i
+            JOIN campaign c ON c.id = i.campaign_id
+            JOIN users u ON u.id = c.user_id
+            WHERE i.invite = $1
+        "#,
+        invite_code,
+        user_id
+    )
+    .fetch_one(conn)
+    .await?;
 
-    Ok(true)
+    Ok(res)
 }
 
 pub async fn get_dnd_campaigns(
     conn: &Pool<Postgres>,
-    access_token: &str,
+    user_id: i32,
 ) -> Result<Vec<DndCampaign>, Error> {
-    let user_id = get_user_id(conn, access_token).await?;
-
-    let res = sqlx::query_as!(DndCampaign, "SELECT * FROM campaign WHERE id IN (SELECT campaign_id FROM campaign_players WHERE player_id = $1)", user_id.id).fetch_all(conn).await?;
+    let res = sqlx::query_as!(DndCampaign, "SELECT * FROM campaign WHERE id IN (SELECT campaign_id FROM campaign_players WHERE player_id = $1)", user_id).fetch_all(conn).await?;
 
     Ok(res)
 }
 
-pub async fn create_dnd_session(
+pub async fn get_dnd_campaign(
     conn: &Pool<Postgres>,
-    access_token: &str,
+    user_id: i32,
     campaign_id: i32,
-    name: &str,
-) -> Result<DndSession, Error> {
-    let user_id = get_user_id(conn, access_token).await?;
-
+) -> Result<DndCampaign, Error> {
     let res = sqlx::query_as!(
-        DndSession,
-        "INSERT INTO dnd_session (user_id, campaign_id, name) VALUES ($1, $2, $3) RETURNING *",
-        user_id.id,
+        DndCampaign,
+        "SELECT * FROM campaign WHERE id = $1 AND id IN (SELECT campaign_id FROM campaign_players WHERE player_id = $2)",
         campaign_id,
-        name
+        user_id
     )
     .fetch_one(conn)
     .await?;
@@ -274,20 +679,2065 @@ pub async fn create_dnd_session(
     Ok(res)
 }
 
-pub async fn get_dnd_sessions(
+// Doesn't check who's asking, see Permission::EditCampaign. None leaves a field alone, and
+// Some(None) removes the image
+pub async fn update_dnd_campaign(
     conn: &Pool<Postgres>,
-    access_token: &str,
     campaign_id: i32,
-) -> Result<Vec<DndSession>, Error> {
-    let user_id = get_user_id(conn, access_token).await?;
+    name: Option<&str>,
+    image_link: Option<Option<&str>>,
+) -> Result<DndCampaign, Error> {
     let res = sqlx::query_as!(
-        DndSession,
-        "SELECT * FROM dnd_session WHERE campaign_id = $1 AND campaign_id IN (SELECT campaign_id FROM campaign_players WHERE player_id = $2)",
+        DndCampaign,
+        "
+            UPDATE campaign SET name = COALESCE($2, name), image_link = CASE WHEN $4 THEN $3 ELSE image_link END, last_updated = CURRENT_TIMESTAMP
+            WHERE id = $1
+            RETURNING *
+        ",
         campaign_id,
-        user_id.id
+        name,
+        image_link.flatten(),
+        image_link.is_some()
     )
-    .fetch_all(conn)
+    .fetch_one(conn)
     .await?;
 
     Ok(res)
 }
+
+// Sessions/players/invites are removed by the cascade. Doesn't check who's asking, see
+// Permission::DeleteCampaign
+pub async fn delete_dnd_campaign(conn: &Pool<Postgres>, campaign_id: i32) -> Result<(), Error> {
+    let res = sqlx::query!("DELETE FROM campaign WHERE id = $1", campaign_id)
+        .execute(conn)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+// None if the user isn't in the campaign
+pub async fn get_campaign_role(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+    campaign_id: i32,
+) -> Result<Option<CampaignRole>, Error> {
+    let res = sqlx::query_scalar!(
+        r#"SELECT role AS "role: CampaignRole" FROM campaign_players WHERE campaign_id = $1 AND player_id = $2"#,
+        campaign_id,
+        user_id
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(res)
+}
+
+#[derive(Serialize)]
+pub struct CampaignMember {
+    pub user_id: i32,
+    pub discord_id: String,
+    pub username: String,
+    pub role: CampaignRole,
+    pub joined_at: Option<chrono::NaiveDateTime>,
+}
+
+pub async fn get_campaign_members(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+) -> Result<Vec<CampaignMember>, Error> {
+    let res = sqlx::query_as!(
+        CampaignMember,
+        r#"
+            SELECT p.player_id AS user_id, s.discord_id AS "discord_id!", u.username, p.role AS "role: CampaignRole", p.joined_at
+            FROM campaign_players p JOIN users u ON u.id = p.player_id JOIN session s ON s.user_id = p.player_id
+            WHERE p.campaign_id = $1
+            ORDER BY p.joined_at, p.player_id
+        "#,
+        campaign_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Locked until the transaction is over, None if they aren't in the campaign
+pub async fn lock_campaign_member(
+    conn: &mut PgConnection,
+    campaign_id: i32,
+    user_id: i32,
+) -> Result<Option<CampaignMember>, Error> {
+    let res = sqlx::query_as!(
+        CampaignMember,
+        r#"
+            SELECT p.player_id AS user_id, s.discord_id AS "discord_id!", u.username, p.role AS "role: CampaignRole", p.joined_at
+            FROM campaign_players p JOIN users u ON u.id = p.player_id JOIN session s ON s.user_id = p.player_id
+            WHERE p.campaign_id = $1 AND p.player_id = $2
+            FOR UPDATE OF p
+        "#,
+        campaign_id,
+        user_id
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Locks these players' rows until the transaction is over and returns their roles, for checking
+// what someone can do while nobody else changes it. Always locked lowest id first so two requests
+// locking the same players can't deadlock
+pub async fn lock_campaign_roles(
+    conn: &mut PgConnection,
+    campaign_id: i32,
+    user_ids: &[i32],
+) -> Result<Vec<(i32, CampaignRole)>, Error> {
+    let res = sqlx::query!(
+        r#"
+            SELECT player_id, role AS "role: CampaignRole" FROM campaign_players
+            WHERE campaign_id = $1 AND player_id = ANY($2)
+            ORDER BY player_id
+            FOR UPDATE
+        "#,
+        campaign_id,
+        user_ids
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res.into_iter().map(|r| (r.player_id, r.role)).collect())
+}
+
+// Doesn't check who's asking, see permissions::can_change_member
+pub async fn set_campaign_role(
+    conn: &mut PgConnection,
+    campaign_id: i32,
+    user_id: i32,
+    role: CampaignRole,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "UPDATE campaign_players SET role = $3 WHERE campaign_id = $1 AND player_id = $2",
+        campaign_id,
+        user_id,
+        role as CampaignRole
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+// Their characters stay in the campaign for the DMs to deal with
+pub async fn remove_campaign_member(
+    conn: &mut PgConnection,
+    campaign_id: i32,
+    user_id: i32,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "DELETE FROM campaign_players WHERE campaign_id = $1 AND player_id = $2",
+        campaign_id,
+        user_id
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+// Stops them from joining again, see join_dnd_campaign
+pub async fn ban_campaign_member(
+    conn: &mut PgConnection,
+    campaign_id: i32,
+    user_id: i32,
+    banned_by: i32,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT INTO campaign_bans (campaign_id, user_id, banned_by) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+        campaign_id,
+        user_id,
+        banned_by
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn unban_campaign_member(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    user_id: i32,
+) -> Result<(), Error> {
+    let res = sqlx::query!(
+        "DELETE FROM campaign_bans WHERE campaign_id = $1 AND user_id = $2",
+        campaign_id,
+        user_id
+    )
+    .execute(conn)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct CampaignBan {
+    pub user_id: i32,
+    pub username: String,
+    pub banned_by: Option<i32>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+pub async fn get_campaign_bans(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+) -> Result<Vec<CampaignBan>, Error> {
+    let res = sqlx::query_as!(
+        CampaignBan,
+        "
+            SELECT b.user_id, u.username, b.banned_by, b.created_at
+            FROM campaign_bans b JOIN users u ON u.id = b.user_id
+            WHERE b.campaign_id = $1
+            ORDER BY b.created_at
+        ",
+        campaign_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Discord ids of the campaign's DMs
+pub async fn get_campaign_dms(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+) -> Result<Vec<String>, Error> {
+    let res = sqlx::query_scalar!(
+        r#"
+            SELECT s.discord_id AS "discord_id!"
+            FROM campaign_players p JOIN session s ON s.user_id = p.player_id
+            WHERE p.campaign_id = $1 AND p.role IN ('owner', 'co_dm')
+        "#,
+        campaign_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Looks up campaign members by discord id, returns (user id, discord id) for the ones that are in
+// the campaign
+pub async fn get_campaign_members_by_discord_id(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    discord_ids: &[String],
+) -> Result<Vec<(i32, String)>, Error> {
+    let res = sqlx::query!(
+        r#"
+            SELECT p.player_id, s.discord_id AS "discord_id!"
+            FROM campaign_players p JOIN session s ON s.user_id = p.player_id
+            WHERE p.campaign_id = $1 AND s.discord_id = ANY($2)
+        "#,
+        campaign_id,
+        discord_ids
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res
+        .into_iter()
+        .map(|r| (r.player_id, r.discord_id))
+        .collect())
+}
+
+pub async fn dnd_session_in_campaign(
+    conn: &Pool<Postgres>,
+    session_id: i32,
+    campaign_id: i32,
+) -> Result<bool, Error> {
+    let res = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM dnd_session WHERE id = $1 AND campaign_id = $2) AS "exists!""#,
+        session_id,
+        campaign_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+pub async fn create_dnd_session(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+    campaign_id: i32,
+    name: &str,
+) -> Result<DndSession, Error> {
+    let res = sqlx::query_as!(
+        DndSession,
+        "INSERT INTO dnd_session (user_id, campaign_id, name) VALUES ($1, $2, $3) RETURNING *",
+        user_id,
+        campaign_id,
+        name
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+pub async fn get_dnd_sessions(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+    campaign_id: i32,
+) -> Result<Vec<DndSession>, Error> {
+    let res = sqlx::query_as!(
+        DndSession,
+        "SELECT * FROM dnd_session WHERE campaign_id = $1 AND campaign_id IN (SELECT campaign_id FROM campaign_players WHERE player_id = $2)",
+        campaign_id,
+        user_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res)
+}
+
+pub async fn get_dnd_session(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+    session_id: i32,
+) -> Result<DndSession, Error> {
+    let res = sqlx::query_as!(
+        DndSession,
+        "SELECT * FROM dnd_session WHERE id = $1 AND campaign_id IN (SELECT campaign_id FROM campaign_players WHERE player_id = $2)",
+        session_id,
+        user_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Doesn't check who's asking, see Permission::ManageSessions
+pub async fn update_dnd_session(
+    conn: &Pool<Postgres>,
+    session_id: i32,
+    name: &str,
+) -> Result<DndSession, Error> {
+    let res = sqlx::query_as!(
+        DndSession,
+        "UPDATE dnd_session SET name = $2, last_updated = CURRENT_TIMESTAMP WHERE id = $1 RETURNING *",
+        session_id,
+        name
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Doesn't check who's asking, see Permission::ManageSessions
+pub async fn delete_dnd_session(conn: &Pool<Postgres>, session_id: i32) -> Result<(), Error> {
+    let res = sqlx::query!("DELETE FROM dnd_session WHERE id = $1", session_id)
+        .execute(conn)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+// A character sheet. Only the owner and the campaign's DMs get the whole thing, everyone else in
+// the campaign gets a CharacterSummary
+#[derive(Serialize, Clone, Debug)]
+pub struct Character {
+    pub id: i32,
+    pub campaign_id: i32,
+    pub owner_id: i32,
+    pub name: String,
+    pub class: String,
+    pub level: i32,
+    pub strength: i32,
+    pub dexterity: i32,
+    pub constitution: i32,
+    pub intelligence: i32,
+    pub wisdom: i32,
+    pub charisma: i32,
+    pub max_hp: i32,
+    pub current_hp: i32,
+    pub temp_hp: i32,
+    pub armor_class: i32,
+    pub skills: Vec<String>,
+    pub proficiencies: Vec<String>,
+    // a list of InventoryItem
+    pub inventory: serde_json::Value,
+    pub notes: String,
+    // system specific fields, always an object
+    pub extra: serde_json::Value,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_updated: chrono::NaiveDateTime,
+    // goes up by one on every change
+    pub version: i32,
+    // whether the user that asked for it can edit it
+    #[serde(skip)]
+    pub editable: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CharacterSummary {
+    pub id: i32,
+    pub campaign_id: i32,
+    pub owner_id: i32,
+    pub name: String,
+    pub class: String,
+    pub level: i32,
+}
+
+impl From<&Character> for CharacterSummary {
+    fn from(character: &Character) -> Self {
+        CharacterSummary {
+            id: character.id,
+            campaign_id: character.campaign_id,
+            owner_id: character.owner_id,
+            name: character.name.clone(),
+            class: character.class.clone(),
+            level: character.level,
+        }
+    }
+}
+
+// The whole sheet for whoever can edit it, the summary for everyone else
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum CharacterView {
+    Full(Box<Character>),
+    Summary(CharacterSummary),
+}
+
+impl From<Character> for CharacterView {
+    fn from(character: Character) -> Self {
+        if character.editable {
+            CharacterView::Full(Box::new(character))
+        } else {
+            CharacterView::Summary(CharacterSummary::from(&character))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InventoryItem {
+    pub name: String,
+    #[serde(default = "one")]
+    pub quantity: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+fn one() -> i32 {
+    1
+}
+
+// The fields of a character that can be set, None leaves a field alone (or at its default when
+// creating). Checked by api::characters::validate
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct CharacterFields {
+    pub name: Option<String>,
+    pub class: Option<String>,
+    pub level: Option<i32>,
+    pub strength: Option<i32>,
+    pub dexterity: Option<i32>,
+    pub constitution: Option<i32>,
+    pub intelligence: Option<i32>,
+    pub wisdom: Option<i32>,
+    pub charisma: Option<i32>,
+    pub max_hp: Option<i32>,
+    pub current_hp: Option<i32>,
+    pub temp_hp: Option<i32>,
+    pub armor_class: Option<i32>,
+    pub skills: Option<Vec<String>>,
+    pub proficiencies: Option<Vec<String>>,
+    pub inventory: Option<Vec<InventoryItem>>,
+    pub notes: Option<String>,
+    pub extra: Option<serde_json::Value>,
+}
+
+// Every field set, what a websocket patch gets applied to. Fails if the stored inventory isn't a
+// list of items
+impl TryFrom<&Character> for CharacterFields {
+    type Error = serde_json::Error;
+
+    fn try_from(character: &Character) -> Result<Self, Self::Error> {
+        Ok(CharacterFields {
+            name: Some(character.name.clone()),
+            class: Some(character.class.clone()),
+            level: Some(character.level),
+            strength: Some(character.strength),
+            dexterity: Some(character.dexterity),
+            constitution: Some(character.constitution),
+            intelligence: Some(character.intelligence),
+            wisdom: Some(character.wisdom),
+            charisma: Some(character.charisma),
+            max_hp: Some(character.max_hp),
+            current_hp: Some(character.current_hp),
+            temp_hp: Some(character.temp_hp),
+            armor_class: Some(character.armor_class),
+            skills: Some(character.skills.clone()),
+            proficiencies: Some(character.proficiencies.clone()),
+            inventory: Some(serde_json::from_value(character.inventory.clone())?),
+            notes: Some(character.notes.clone()),
+            extra: Some(character.extra.clone()),
+        })
+    }
+}
+
+// With `version` set nothing changes unless the character is still at that version, and it's a
+// RowNotFound
+async fn set_character_fields(
+    conn: &mut PgConnection,
+    character_id: i32,
+    fields: &CharacterFields,
+    version: Option<i32>,
+) -> Result<Character, Error> {
+    let inventory = fields
+        .inventory
+        .as_ref()
+        .map(|inventory| serde_json::to_value(inventory).unwrap());
+
+    let res = sqlx::query_as!(
+        Character,
+        r#"
+            UPDATE characters SET
+                name = COALESCE($2, name), class = COALESCE($3, class), level = COALESCE($4, level),
+                strength = COALESCE($5, strength), dexterity = COALESCE($6, dexterity),
+                constitution = COALESCE($7, constitution), intelligence = COALESCE($8, intelligence),
+                wisdom = COALESCE($9, wisdom), charisma = COALESCE($10, charisma),
+                max_hp = COALESCE($11, max_hp), current_hp = COALESCE($12, current_hp),
+                temp_hp = COALESCE($13, temp_hp), armor_class = COALESCE($14, armor_class),
+                skills = COALESCE($15, skills), proficiencies = COALESCE($16, proficiencies),
+                inventory = COALESCE($17, inventory), notes = COALESCE($18, notes),
+                extra = COALESCE($19, extra), last_updated = CURRENT_TIMESTAMP, version = version + 1
+            WHERE id = $1 AND ($20::integer IS NULL OR version = $20)
+            RETURNING *, TRUE AS "editable!"
+        "#,
+        character_id,
+        fields.name,
+        fields.class,
+        fields.level,
+        fields.strength,
+        fields.dexterity,
+        fields.constitution,
+        fields.intelligence,
+        fields.wisdom,
+        fields.charisma,
+        fields.max_hp,
+        fields.current_hp,
+        fields.temp_hp,
+        fields.armor_class,
+        fields.skills.as_deref(),
+        fields.proficiencies.as_deref(),
+        inventory,
+        fields.notes,
+        fields.extra,
+        version
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// `fields.name` has to be set, the caller checks the user is in the campaign
+pub async fn create_character(
+    conn: &Pool<Postgres>,
+    owner_id: i32,
+    campaign_id: i32,
+    fields: &CharacterFields,
+) -> Result<Character, Error> {
+    let mut tx = conn.begin().await?;
+    // version 0 since setting the rest of the fields bumps it to 1
+    let id = sqlx::query_scalar!(
+        "INSERT INTO characters (campaign_id, owner_id, name, version) VALUES ($1, $2, $3, 0) RETURNING id",
+        campaign_id,
+        owner_id,
+        fields.name
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let res = set_character_fields(&mut tx, id, fields, None).await?;
+    tx.commit().await?;
+
+    Ok(res)
+}
+
+pub async fn get_characters(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+    campaign_id: i32,
+) -> Result<Vec<Character>, Error> {
+    let res = sqlx::query_as!(
+        Character,
+        r#"
+            SELECT c.*, (c.owner_id = $2 OR EXISTS (SELECT 1 FROM campaign_players WHERE campaign_id = c.campaign_id AND player_id = $2 AND role IN ('owner', 'co_dm'))) AS "editable!"
+            FROM characters c
+            WHERE c.campaign_id = $1 AND EXISTS (SELECT 1 FROM campaign_players WHERE campaign_id = $1 AND player_id = $2)
+            ORDER BY c.id
+        "#,
+        campaign_id,
+        user_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// RowNotFound if the user isn't in the character's campaign
+pub async fn get_character(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+    character_id: i32,
+) -> Result<Character, Error> {
+    let res = sqlx::query_as!(
+        Character,
+        r#"
+            SELECT c.*, (c.owner_id = $2 OR EXISTS (SELECT 1 FROM campaign_players WHERE campaign_id = c.campaign_id AND player_id = $2 AND role IN ('owner', 'co_dm'))) AS "editable!"
+            FROM characters c
+            WHERE c.id = $1 AND EXISTS (SELECT 1 FROM campaign_players WHERE campaign_id = c.campaign_id AND player_id = $2)
+        "#,
+        character_id,
+        user_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Doesn't check who's asking, use get_character's `editable` first
+pub async fn update_character(
+    conn: &Pool<Postgres>,
+    character_id: i32,
+    fields: &CharacterFields,
+    version: Option<i32>,
+) -> Result<Character, Error> {
+    let mut conn = conn.acquire().await?;
+    set_character_fields(&mut conn, character_id, fields, version).await
+}
+
+// Discord ids of everyone that can edit the character, its owner and the campaign's DMs
+pub async fn get_character_editors(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    owner_id: i32,
+) -> Result<Vec<String>, Error> {
+    let res = sqlx::query_scalar!(
+        r#"
+            SELECT discord_id AS "discord_id!" FROM session
+            WHERE user_id = $2 OR user_id IN (SELECT player_id FROM campaign_players WHERE campaign_id = $1 AND role IN ('owner', 'co_dm'))
+        "#,
+        campaign_id,
+        owner_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res)
+}
+
+pub async fn delete_character(conn: &Pool<Postgres>, character_id: i32) -> Result<(), Error> {
+    let res = sqlx::query!("DELETE FROM characters WHERE id = $1", character_id)
+        .execute(conn)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+// Who can see a chat message or dice roll
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[sqlx(type_name = "visibility", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Public,
+    // the sender and whoever it's whispered to
+    Whisper,
+    // the sender and the DMs
+    Dm,
+    // only the sender
+    #[sqlx(rename = "self")]
+    #[serde(rename = "self")]
+    OnlySelf,
+    // only the DMs, for rolls the player isn't supposed to see the result of
+    Blind,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Audience {
+    pub visibility: Visibility,
+    // user ids a whisper goes to
+    pub recipients: Vec<i32>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChatMessage {
+    pub id: i64,
+    // discord id of the author, same as the ids in ConnectedUsers
+    pub author: String,
+    pub content: String,
+    pub visibility: Visibility,
+    // discord ids of who it was whispered to
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+pub async fn add_message(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    session_id: Option<i32>,
+    author_id: i32,
+    content: &str,
+    audience: &Audience,
+) -> Result<ChatMessage, Error> {
+    let res = sqlx::query_as!(
+        ChatMessage,
+        r#"
+            WITH inserted AS (
+                INSERT INTO messages (campaign_id, session_id, author_id, content, visibility, recipients)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                RETURNING id, author_id, content, visibility, recipients, created_at
+            )
+            SELECT i.id, s.discord_id AS author, i.content, i.visibility AS "visibility: Visibility",
+                ARRAY(SELECT discord_id FROM session WHERE user_id = ANY(i.recipients)) AS "recipients!",
+                i.created_at
+            FROM inserted i JOIN session s ON s.user_id = i.author_id
+        "#,
+        campaign_id,
+        session_id,
+        author_id,
+        content,
+        audience.visibility as Visibility,
+        &audience.recipients
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Where to start a page of history, both are message ids and aren't included in the page
+#[derive(Clone, Copy, Debug)]
+pub enum HistoryCursor {
+    // the newest messages, or the ones right before `Before`
+    Before(Option<i64>),
+    // the oldest messages after this one, for catching up after a reconnect
+    After(i64),
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MessagePage {
+    // always oldest first
+    pub messages: Vec<ChatMessage>,
+    // whether there's more in the direction that was asked for
+    pub has_more: bool,
+}
+
+// Only has the messages `viewer_id` is allowed to see, `dm` is whether they're a DM of the campaign
+pub async fn get_messages(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    session_id: Option<i32>,
+    cursor: HistoryCursor,
+    limit: i64,
+    viewer_id: i32,
+    dm: bool,
+) -> Result<MessagePage, Error> {
+    // one extra row to know if there's another page
+    let mut messages = match cursor {
+        HistoryCursor::Before(before) => {
+            sqlx::query_as!(
+                ChatMessage,
+                r#"
+                    SELECT m.id, s.discord_id AS author, m.content, m.visibility AS "visibility: Visibility",
+                        ARRAY(SELECT discord_id FROM session WHERE user_id = ANY(m.recipients)) AS "recipients!",
+                        m.created_at
+                    FROM messages m JOIN session s ON s.user_id = m.author_id
+                    WHERE m.campaign_id = $1 AND m.session_id IS NOT DISTINCT FROM $2 AND ($3::bigint IS NULL OR m.id < $3)
+                        AND (m.visibility = 'public'
+                            OR (m.author_id = $5 AND m.visibility <> 'blind')
+                            OR (m.visibility = 'whisper' AND $5 = ANY(m.recipients))
+                            OR (m.visibility IN ('dm', 'blind') AND $6))
+                    ORDER BY m.id DESC LIMIT $4
+                "#,
+                campaign_id,
+                session_id,
+                before,
+                limit + 1,
+                viewer_id,
+                dm
+            )
+            .fetch_all(conn)
+            .await?
+        }
+        HistoryCursor::After(after) => {
+            sqlx::query_as!(
+                ChatMessage,
+                r#"
+                    SELECT m.id, s.discord_id AS author, m.content, m.visibility AS "visibility: Visibility",
+                        ARRAY(SELECT discord_id FROM session WHERE user_id = ANY(m.recipients)) AS "recipients!",
+                        m.created_at
+                    FROM messages m JOIN session s ON s.user_id = m.author_id
+                    WHERE m.campaign_id = $1 AND m.session_id IS NOT DISTINCT FROM $2 AND m.id > $3
+                        AND (m.visibility = 'public'
+                            OR (m.author_id = $5 AND m.visibility <> 'blind')
+                            OR (m.visibility = 'whisper' AND $5 = ANY(m.recipients))
+                            OR (m.visibility IN ('dm', 'blind') AND $6))
+                    ORDER BY m.id LIMIT $4
+                "#,
+                campaign_id,
+                session_id,
+                after,
+                limit + 1,
+                viewer_id,
+                dm
+            )
+            .fetch_all(conn)
+            .await?
+        }
+    };
+
+    let has_more = messages.len() as i64 > limit;
+    messages.truncate(limit as usize);
+    if let HistoryCursor::Before(_) = cursor {
+        messages.reverse();
+    }
+
+    Ok(MessagePage { messages, has_more })
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DiceRoll {
+    pub id: i64,
+    // discord id of whoever rolled
+    pub roller: String,
+    pub label: Option<String>,
+    pub visibility: Visibility,
+    // discord ids of who it was whispered to
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+    #[serde(flatten)]
+    pub result: RollResult,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+pub async fn add_roll(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    session_id: Option<i32>,
+    roller_id: i32,
+    label: Option<&str>,
+    audience: &Audience,
+    result: RollResult,
+) -> Result<DiceRoll, Error> {
+    let res = sqlx::query!(
+        r#"
+            WITH inserted AS (
+                INSERT INTO dice_rolls (campaign_id, session_id, roller_id, notation, label, seed, result, total, visibility, recipients)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                RETURNING id, roller_id, label, recipients, created_at
+            )
+            SELECT i.id, s.discord_id AS roller, i.label,
+                ARRAY(SELECT discord_id FROM session WHERE user_id = ANY(i.recipients)) AS "recipients!",
+                i.created_at
+            FROM inserted i JOIN session s ON s.user_id = i.roller_id
+        "#,
+        campaign_id,
+        session_id,
+        roller_id,
+        result.notation,
+        label,
+        result.seed as i64,
+        serde_json::to_value(&result).unwrap(),
+        result.total,
+        audience.visibility as Visibility,
+        &audience.recipients
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(DiceRoll {
+        id: res.id,
+        roller: res.roller,
+        label: res.label,
+        visibility: audience.visibility,
+        recipients: res.recipients,
+        result,
+        created_at: res.created_at,
+    })
+}
+
+// A condition or effect on a combatant, see combat.rs for when it ends. Effects on a character's
+// combatant also have its character_id, when the encounter ends the ones without a duration go back
+// to the character and stay on it (with no combatant) until they're removed
+#[derive(Serialize, Clone, Debug)]
+pub struct Effect {
+    pub id: i32,
+    pub combatant_id: Option<i32>,
+    pub character_id: Option<i32>,
+    pub name: String,
+    pub source: Option<String>,
+    pub source_combatant_id: Option<i32>,
+    // None if it lasts until it's removed
+    pub duration_rounds: Option<i32>,
+    pub ends_round: Option<i32>,
+    pub ends_on_turn_of: Option<i32>,
+    // ends when that turn is over instead of when it starts
+    pub ends_at_end_of_turn: bool,
+}
+
+// An effect that's about to be added, the end comes from combat::effect_end. Outside of combat it
+// only has a character_id
+pub struct NewEffect {
+    pub combatant_id: Option<i32>,
+    pub character_id: Option<i32>,
+    pub name: String,
+    pub source: Option<String>,
+    pub source_combatant_id: Option<i32>,
+    pub duration_rounds: Option<i32>,
+    pub ends_round: Option<i32>,
+    pub ends_on_turn_of: Option<i32>,
+    pub ends_at_end_of_turn: bool,
+}
+
+struct CombatantRow {
+    id: i32,
+    character_id: Option<i32>,
+    owner_id: Option<i32>,
+    name: String,
+    initiative: i32,
+    initiative_roll: Option<serde_json::Value>,
+    position: i32,
+    delayed: bool,
+    readied: Option<String>,
+}
+
+impl CombatantRow {
+    fn with_effects(self, effects: Vec<Effect>) -> Combatant {
+        Combatant {
+            id: self.id,
+            character_id: self.character_id,
+            owner_id: self.owner_id,
+            name: self.name,
+            initiative: self.initiative,
+            initiative_roll: self.initiative_roll,
+            position: self.position,
+            delayed: self.delayed,
+            readied: self.readied,
+            effects,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Combatant {
+    pub id: i32,
+    // None for monsters, or if the character was deleted
+    pub character_id: Option<i32>,
+    // who controls it besides the DMs, None for monsters
+    pub owner_id: Option<i32>,
+    pub name: String,
+    pub initiative: i32,
+    // the roll it came from, None if it was set by hand
+    pub initiative_roll: Option<serde_json::Value>,
+    pub position: i32,
+    pub delayed: bool,
+    // the trigger of a readied action
+    pub readied: Option<String>,
+    pub effects: Vec<Effect>,
+}
+
+// A fight in a dnd session, with its combatants in turn order
+#[derive(Serialize, Clone, Debug)]
+pub struct Encounter {
+    pub id: i32,
+    pub session_id: i32,
+    pub campaign_id: i32,
+    pub name: String,
+    // 0 before the first turn
+    pub round: i32,
+    pub current_combatant_id: Option<i32>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_updated: chrono::NaiveDateTime,
+    pub ended_at: Option<chrono::NaiveDateTime>,
+    pub combatants: Vec<Combatant>,
+}
+
+// A combatant that's about to be added, `position` gets set when the encounter is saved
+pub struct NewCombatant {
+    pub character_id: Option<i32>,
+    pub owner_id: Option<i32>,
+    pub name: String,
+    pub initiative: i32,
+    pub initiative_roll: Option<RollResult>,
+}
+
+pub async fn create_encounter(
+    conn: &Pool<Postgres>,
+    session_id: i32,
+    name: &str,
+) -> Result<Encounter, Error> {
+    let id = sqlx::query_scalar!(
+        "INSERT INTO encounters (session_id, name) VALUES ($1, $2) RETURNING id",
+        session_id,
+        name
+    )
+    .fetch_one(conn)
+    .await?;
+
+    get_encounter(&mut *conn.acquire().await?, id).await
+}
+
+// Doesn't check who's asking, RowNotFound if there's no such encounter
+pub async fn get_encounter(conn: &mut PgConnection, encounter_id: i32) -> Result<Encounter, Error> {
+    let res = sqlx::query!(
+        r#"
+            SELECT e.id, e.session_id, s.campaign_id, e.name, e.round, e.current_combatant_id,
+                e.created_at, e.last_updated, e.ended_at
+            FROM encounters e JOIN dnd_session s ON s.id = e.session_id
+            WHERE e.id = $1
+        "#,
+        encounter_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let rows = sqlx::query_as!(
+        CombatantRow,
+        r#"
+            SELECT id, character_id, owner_id, name, initiative, initiative_roll, position, delayed, readied
+            FROM combatants WHERE encounter_id = $1
+            ORDER BY position, id
+        "#,
+        encounter_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut effects = sqlx::query_as!(
+        Effect,
+        r#"
+            SELECT f.id, f.combatant_id, f.character_id, f.name, f.source, f.source_combatant_id, f.duration_rounds, f.ends_round, f.ends_on_turn_of, f.ends_at_end_of_turn
+            FROM effects f JOIN combatants c ON c.id = f.combatant_id
+            WHERE c.encounter_id = $1
+            ORDER BY f.id
+        "#,
+        encounter_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let combatants = rows
+        .into_iter()
+        .map(|row| {
+            let (own, rest) = effects
+                .drain(..)
+                .partition(|effect| effect.combatant_id == Some(row.id));
+            effects = rest;
+            row.with_effects(own)
+        })
+        .collect();
+
+    Ok(Encounter {
+        id: res.id,
+        session_id: res.session_id,
+        campaign_id: res.campaign_id,
+        name: res.name,
+        round: res.round,
+        current_combatant_id: res.current_combatant_id,
+        created_at: res.created_at,
+        last_updated: res.last_updated,
+        ended_at: res.ended_at,
+        combatants,
+    })
+}
+
+// Same as get_encounter, but nobody else can change the encounter until the transaction is over
+pub async fn lock_encounter(
+    conn: &mut PgConnection,
+    encounter_id: i32,
+) -> Result<Encounter, Error> {
+    sqlx::query!(
+        "SELECT id FROM encounters WHERE id = $1 FOR UPDATE",
+        encounter_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    get_encounter(conn, encounter_id).await
+}
+
+// The session's encounter that hasn't ended yet
+pub async fn get_active_encounter_id(
+    conn: &Pool<Postgres>,
+    session_id: i32,
+) -> Result<Option<i32>, Error> {
+    let res = sqlx::query_scalar!(
+        "SELECT id FROM encounters WHERE session_id = $1 AND ended_at IS NULL",
+        session_id
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(res)
+}
+
+pub async fn add_combatant(
+    conn: &mut PgConnection,
+    encounter_id: i32,
+    combatant: NewCombatant,
+) -> Result<Combatant, Error> {
+    let initiative_roll = combatant
+        .initiative_roll
+        .map(|roll| serde_json::to_value(roll).unwrap());
+    let res = sqlx::query_as!(
+        CombatantRow,
+        r#"
+            INSERT INTO combatants (encounter_id, character_id, owner_id, name, initiative, initiative_roll, position)
+            VALUES ($1, $2, $3, $4, $5, $6, 0)
+            RETURNING id, character_id, owner_id, name, initiative, initiative_roll, position, delayed, readied
+        "#,
+        encounter_id,
+        combatant.character_id,
+        combatant.owner_id,
+        combatant.name,
+        combatant.initiative,
+        initiative_roll
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    // the character brings the effects it already has into the fight
+    let effects = sqlx::query_as!(
+        Effect,
+        r#"
+            UPDATE effects SET combatant_id = $2
+            WHERE character_id = $1 AND combatant_id IS NULL
+            RETURNING id, combatant_id, character_id, name, source, source_combatant_id, duration_rounds, ends_round, ends_on_turn_of, ends_at_end_of_turn
+        "#,
+        combatant.character_id,
+        res.id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(res.with_effects(effects))
+}
+
+pub async fn add_effect(conn: &mut PgConnection, effect: NewEffect) -> Result<Effect, Error> {
+    let res = sqlx::query_as!(
+        Effect,
+        r#"
+            INSERT INTO effects (combatant_id, character_id, name, source, source_combatant_id, duration_rounds, ends_round, ends_on_turn_of, ends_at_end_of_turn)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id, combatant_id, character_id, name, source, source_combatant_id, duration_rounds, ends_round, ends_on_turn_of, ends_at_end_of_turn
+        "#,
+        effect.combatant_id,
+        effect.character_id,
+        effect.name,
+        effect.source,
+        effect.source_combatant_id,
+        effect.duration_rounds,
+        effect.ends_round,
+        effect.ends_on_turn_of,
+        effect.ends_at_end_of_turn
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// The effects without a duration on these combatants' characters go back to the characters, the
+// rest end with the combatants. Returns the ids of the characters that got effects back
+pub async fn release_character_effects(
+    conn: &mut PgConnection,
+    combatant_ids: &[i32],
+) -> Result<Vec<i32>, Error> {
+    let res = sqlx::query_scalar!(
+        r#"
+            UPDATE effects SET combatant_id = NULL, source_combatant_id = NULL
+            WHERE combatant_id = ANY($1) AND character_id IS NOT NULL AND duration_rounds IS NULL
+            RETURNING character_id AS "character_id!"
+        "#,
+        combatant_ids
+    )
+    .fetch_all(conn)
+    .await?;
+
+    let mut character_ids = res;
+    character_ids.sort_unstable();
+    character_ids.dedup();
+    Ok(character_ids)
+}
+
+// Effects on the character while it isn't in a fight
+pub async fn get_character_effects(
+    conn: &Pool<Postgres>,
+    character_id: i32,
+) -> Result<Vec<Effect>, Error> {
+    sqlx::query_as!(
+        Effect,
+        r#"
+            SELECT id, combatant_id, character_id, name, source, source_combatant_id, duration_rounds, ends_round, ends_on_turn_of, ends_at_end_of_turn
+            FROM effects WHERE character_id = $1 AND combatant_id IS NULL
+            ORDER BY id
+        "#,
+        character_id
+    )
+    .fetch_all(conn)
+    .await
+}
+
+// RowNotFound if it isn't on the character, or it's on its combatant right now
+pub async fn delete_character_effect(
+    conn: &Pool<Postgres>,
+    character_id: i32,
+    effect_id: i32,
+) -> Result<(), Error> {
+    let res = sqlx::query!(
+        "DELETE FROM effects WHERE id = $1 AND character_id = $2 AND combatant_id IS NULL",
+        effect_id,
+        character_id
+    )
+    .execute(conn)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+// Whether the character is a combatant in an encounter that's still going on
+pub async fn character_in_encounter(
+    conn: &Pool<Postgres>,
+    character_id: i32,
+) -> Result<bool, Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT EXISTS (
+                SELECT 1 FROM combatants c JOIN encounters e ON e.id = c.encounter_id
+                WHERE c.character_id = $1 AND e.ended_at IS NULL
+            ) AS "in_encounter!"
+        "#,
+        character_id
+    )
+    .fetch_one(conn)
+    .await
+}
+
+pub async fn delete_combatant(conn: &mut PgConnection, combatant_id: i32) -> Result<(), Error> {
+    sqlx::query!("DELETE FROM combatants WHERE id = $1", combatant_id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+// Writes back the round, whose turn it is and the order, and deletes the effects that aren't on
+// any combatant anymore. Returns the encounter as saved
+pub async fn save_encounter(
+    conn: &mut PgConnection,
+    encounter: &Encounter,
+) -> Result<Encounter, Error> {
+    sqlx::query!(
+        r#"
+            UPDATE encounters SET round = $2, current_combatant_id = $3, last_updated = CURRENT_TIMESTAMP
+            WHERE id = $1
+        "#,
+        encounter.id,
+        encounter.round,
+        encounter.current_combatant_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let combatants = &encounter.combatants;
+    sqlx::query!(
+        r#"
+            UPDATE combatants c
+            SET position = u.position, initiative = u.initiative, delayed = u.delayed, readied = u.readied
+            FROM UNNEST($2::integer[], $3::integer[], $4::integer[], $5::boolean[], $6::text[])
+                AS u(id, position, initiative, delayed, readied)
+            WHERE c.id = u.id AND c.encounter_id = $1
+        "#,
+        encounter.id,
+        &combatants.iter().map(|c| c.id).collect::<Vec<_>>(),
+        &combatants.iter().map(|c| c.position).collect::<Vec<_>>(),
+        &combatants.iter().map(|c| c.initiative).collect::<Vec<_>>(),
+        &combatants.iter().map(|c| c.delayed).collect::<Vec<_>>(),
+        &combatants
+            .iter()
+            .map(|c| c.readied.clone())
+            .collect::<Vec<_>>() as &[Option<String>]
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let effect_ids: Vec<i32> = combatants
+        .iter()
+        .flat_map(|c| c.effects.iter().map(|effect| effect.id))
+        .collect();
+    sqlx::query!(
+        r#"
+            DELETE FROM effects
+            WHERE combatant_id IN (SELECT id FROM combatants WHERE encounter_id = $1) AND id <> ALL($2)
+        "#,
+        encounter.id,
+        &effect_ids
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    get_encounter(conn, encounter.id).await
+}
+
+pub async fn end_encounter(conn: &mut PgConnection, encounter_id: i32) -> Result<Encounter, Error> {
+    sqlx::query!(
+        r#"
+            UPDATE encounters SET ended_at = CURRENT_TIMESTAMP, last_updated = CURRENT_TIMESTAMP
+            WHERE id = $1
+        "#,
+        encounter_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    get_encounter(conn, encounter_id).await
+}
+
+// Everything below is for the dnd-admin tool, none of it checks who's asking
+
+#[derive(Serialize)]
+pub struct AdminUser {
+    pub id: i32,
+    pub username: String,
+    pub global_name: Option<String>,
+    pub discord_id: Option<String>,
+    pub created_at: Option<chrono::NaiveDateTime>,
+    pub campaigns: i64,
+}
+
+// `search` matches the username, display name or discord id
+pub async fn admin_list_users(
+    conn: &Pool<Postgres>,
+    search: Option<&str>,
+) -> Result<Vec<AdminUser>, Error> {
+    let res = sqlx::query_as!(
+        AdminUser,
+        r#"
+            SELECT u.id, u.username, s.global_name AS "global_name?", s.discord_id AS "discord_id?", u.created_at,
+                (SELECT COUNT(*) FROM campaign_players WHERE player_id = u.id) AS "campaigns!"
+            FROM users u LEFT JOIN session s ON s.user_id = u.id
+            WHERE $1::text IS NULL OR u.username ILIKE '%' || $1 || '%' OR s.global_name ILIKE '%' || $1 || '%' OR s.discord_id = $1
+            ORDER BY u.id
+        "#,
+        search
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res)
+}
+
+#[derive(Serialize)]
+pub struct AdminCampaign {
+    pub id: i32,
+    pub name: String,
+    pub owner_id: i32,
+    pub owner: String,
+    pub players: i64,
+    pub sessions: i64,
+    // invites that can still be used
+    pub invites: i64,
+    pub created_at: Option<chrono::NaiveDateTime>,
+    // newest of the campaign's and its sessions' last_updated
+    pub last_active: Option<chrono::NaiveDateTime>,
+}
+
+// `search` matches the campaign name, `owner` only lists campaigns owned by that user
+pub async fn admin_list_campaigns(
+    conn: &Pool<Postgres>,
+    search: Option<&str>,
+    owner: Option<i32>,
+) -> Result<Vec<AdminCampaign>, Error> {
+    let res = sqlx::query_as!(
+        AdminCampaign,
+        r#"
+            SELECT c.id, c.name, c.user_id AS owner_id, u.username AS owner,
+                (SELECT COUNT(*) FROM campaign_players WHERE campaign_id = c.id) AS "players!",
+                (SELECT COUNT(*) FROM dnd_session WHERE campaign_id = c.id) AS "sessions!",
+                (
+                    SELECT COUNT(*) FROM campaign_invites
+                    WHERE campaign_id = c.id AND (expires_at IS NULL OR expires_at > (now() AT TIME ZONE 'utc')) AND (max_uses IS NULL OR uses < max_uses)
+                ) AS "invites!",
+                c.created_at,
+                GREATEST(c.last_updated, (SELECT MAX(last_updated) FROM dnd_session WHERE campaign_id = c.id)) AS last_active
+            FROM campaign c
+            JOIN users u ON u.id = c.user_id
+            WHERE ($1::text IS NULL OR c.name ILIKE '%' || $1 || '%') AND ($2::int IS NULL OR c.user_id = $2)
+            ORDER BY c.id
+        "#,
+        search,
+        owner
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res)
+}
+
+#[derive(Serialize)]
+pub struct CampaignPlayer {
+    pub player_id: i32,
+    pub username: String,
+    pub role: CampaignRole,
+    pub joined_at: Option<chrono::NaiveDateTime>,
+}
+
+pub async fn admin_get_campaign_players(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+) -> Result<Vec<CampaignPlayer>, Error> {
+    let res = sqlx::query_as!(
+        CampaignPlayer,
+        r#"
+            SELECT p.player_id, u.username, p.role AS "role: CampaignRole", p.joined_at
+            FROM campaign_players p JOIN users u ON u.id = p.player_id
+            WHERE p.campaign_id = $1
+            ORDER BY p.joined_at
+        "#,
+        campaign_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Makes `new_owner` the owner of the campaign, adding them as a player if they weren't
+// one already. The old owner stays in the campaign as a regular player
+pub async fn admin_transfer_campaign(
+    conn: &Pool<Postgres>,
+    campaign_id: i32,
+    new_owner: i32,
+) -> Result<(), Error> {
+    let mut tx = conn.begin().await?;
+
+    let old_owner = sqlx::query_scalar!(
+        "SELECT user_id FROM campaign WHERE id = $1 FOR UPDATE",
+        campaign_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE campaign SET user_id = $2, last_updated = CURRENT_TIMESTAMP WHERE id = $1",
+        campaign_id,
+        new_owner
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "
+            INSERT INTO campaign_players (campaign_id, player_id, role) VALUES ($1, $2, 'owner')
+            ON CONFLICT (campaign_id, player_id) DO UPDATE SET role = 'owner'
+        ",
+        campaign_id,
+        new_owner
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if old_owner != new_owner {
+        sqlx::query!(
+            "UPDATE campaign_players SET role = 'player' WHERE campaign_id = $1 AND player_id = $2",
+            campaign_id,
+            old_owner
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+// Sets how many more times the invite can be used
+pub async fn admin_reset_invite_uses(
+    conn: &Pool<Postgres>,
+    invite_id: i32,
+    uses: i32,
+) -> Result<(), Error> {
+    let res = sqlx::query!(
+        "UPDATE campaign_invites SET max_uses = uses + $2 WHERE id = $1",
+        invite_id,
+        uses
+    )
+    .execute(conn)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+// Replaces the invite code so the old one stops working, returns the new code. `uses` also sets
+// how many more times it can be used
+pub async fn admin_regenerate_invite(
+    conn: &Pool<Postgres>,
+    invite_id: i32,
+    uses: Option<i32>,
+) -> Result<String, Error> {
+    let res = sqlx::query_scalar!(
+        "
+            UPDATE campaign_invites
+                SET invite = $2, created_at = CURRENT_TIMESTAMP, max_uses = COALESCE(uses + $3, max_uses)
+            WHERE id = $1
+            RETURNING invite
+        ",
+        invite_id,
+        generate_invite_code(),
+        uses
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+// Campaigns where neither the campaign nor any of its sessions changed since `before`
+pub async fn admin_get_abandoned_campaigns(
+    conn: &Pool<Postgres>,
+    before: chrono::NaiveDateTime,
+) -> Result<Vec<AdminCampaign>, Error> {
+    let campaigns = admin_list_campaigns(conn, None, None).await?;
+
+    Ok(campaigns
+        .into_iter()
+        .filter(|c| c.last_active.is_none_or(|last_active| last_active < before))
+        .collect())
+}
+
+#[derive(Serialize)]
+pub struct AdminRoll {
+    pub id: i64,
+    pub campaign_id: i32,
+    pub roller_id: i32,
+    pub notation: String,
+    pub seed: i64,
+    pub result: serde_json::Value,
+    pub total: i64,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+pub async fn admin_get_roll(conn: &Pool<Postgres>, roll_id: i64) -> Result<AdminRoll, Error> {
+    let res = sqlx::query_as!(
+        AdminRoll,
+        "
+            SELECT id, campaign_id, roller_id, notation, seed, result, total, created_at
+            FROM dice_rolls WHERE id = $1
+        ",
+        roll_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeDelta, Utc};
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+    use sqlx::Executor;
+
+    async fn user(conn: &Pool<Postgres>, name: &str) -> i32 {
+        let user = DiscordUser {
+            id: name.to_string(),
+            username: name.to_string(),
+            discriminator: String::from("0"),
+            global_name: None,
+            avatar: None,
+            accent_color: None,
+        };
+        let tokens = AccessTokens {
+            access_token: String::new(),
+            refresh_token: String::new(),
+            expires_at: None,
+        };
+        add_user(conn, &user, &tokens).await.unwrap().id
+    }
+
+    // (owner, campaign id)
+    async fn campaign(conn: &Pool<Postgres>) -> (i32, i32) {
+        let owner = user(conn, "dm").await;
+        let campaign = create_dnd_campaign(conn, owner, "Campaign").await.unwrap();
+        (owner, campaign.id)
+    }
+
+    async fn invite(
+        conn: &Pool<Postgres>,
+        (owner, campaign_id): (i32, i32),
+        max_uses: Option<i32>,
+        expires_at: Option<chrono::NaiveDateTime>,
+    ) -> CampaignInvite {
+        create_campaign_invite(
+            conn,
+            campaign_id,
+            owner,
+            CampaignRole::Player,
+            max_uses,
+            expires_at,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn uses(conn: &Pool<Postgres>, invite: &CampaignInvite) -> (i32, Option<i32>) {
+        let invite = get_campaign_invite(conn, invite.campaign_id, invite.id)
+            .await
+            .unwrap();
+        (invite.uses, invite.max_uses)
+    }
+
+    // sqlx always asks for UTC when connecting, so this changes it afterwards like a database
+    // with another default timezone (or a pgbouncer in front of one) would
+    async fn connect_in(options: &PgConnectOptions, timezone: &'static str) -> Pool<Postgres> {
+        PgPoolOptions::new()
+            .after_connect(move |conn, _| {
+                Box::pin(async move {
+                    conn.execute(format!("SET TimeZone = '{}'", timezone).as_str())
+                        .await?;
+                    Ok(())
+                })
+            })
+            .connect_with(options.clone())
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn invites_run_out_of_uses(conn: Pool<Postgres>) {
+        let campaign = campaign(&conn).await;
+        let invite = invite(&conn, campaign, Some(2), None).await;
+
+        for name in ["a", "b"] {
+            let player = user(&conn, name).await;
+            join_dnd_campaign(&conn, player, &invite.invite)
+                .await
+                .unwrap();
+        }
+        let player = user(&conn, "c").await;
+        assert!(matches!(
+            join_dnd_campaign(&conn, player, &invite.invite).await,
+            Err(JoinError::Exhausted)
+        ));
+        assert_eq!(uses(&conn, &invite).await, (2, Some(2)));
+    }
+
+    // expires_at is naive utc, which has to hold whatever timezone the database is in
+    #[sqlx::test]
+    async fn invites_expire_in_utc(_: PgPoolOptions, options: PgConnectOptions) {
+        for timezone in ["UTC", "Asia/Tokyo", "America/New_York"] {
+            let conn = connect_in(&options, timezone).await;
+            let campaign = campaign(&conn).await;
+            let now = Utc::now().naive_utc();
+
+            let expired = invite(&conn, campaign, None, Some(now - TimeDelta::minutes(1))).await;
+            let player = user(&conn, "player").await;
+            assert!(
+                matches!(
+                    join_dnd_campaign(&conn, player, &expired.invite).await,
+                    Err(JoinError::Expired)
+                ),
+                "{}",
+                timezone
+            );
+            let preview = get_invite_preview(&conn, player, &expired.invite)
+                .await
+                .unwrap();
+            assert!(preview.expired, "{}", timezone);
+
+            let valid = invite(&conn, campaign, None, Some(now + TimeDelta::hours(1))).await;
+            let preview = get_invite_preview(&conn, player, &valid.invite)
+                .await
+                .unwrap();
+            assert!(!preview.expired, "{}", timezone);
+            join_dnd_campaign(&conn, player, &valid.invite)
+                .await
+                .unwrap_or_else(|e| panic!("{}: {}", timezone, e));
+        }
+    }
+
+    #[sqlx::test]
+    async fn previews_show_uses_left(conn: Pool<Postgres>) {
+        let campaign = campaign(&conn).await;
+        let unlimited = invite(&conn, campaign, None, None).await;
+        let limited = invite(&conn, campaign, Some(3), None).await;
+        let player = user(&conn, "player").await;
+        join_dnd_campaign(&conn, player, &limited.invite)
+            .await
+            .unwrap();
+
+        let other = user(&conn, "other").await;
+        let preview = get_invite_preview(&conn, other, &unlimited.invite)
+            .await
+            .unwrap();
+        assert_eq!(preview.uses_left, None);
+        let preview = get_invite_preview(&conn, other, &limited.invite)
+            .await
+            .unwrap();
+        assert_eq!(preview.uses_left, Some(2));
+        assert!(!preview.already_member);
+    }
+
+    #[sqlx::test]
+    async fn regenerating_keeps_or_resets_the_limit(conn: Pool<Postgres>) {
+        let campaign = campaign(&conn).await;
+        let limited = invite(&conn, campaign, Some(1), None).await;
+        let unlimited = invite(&conn, campaign, None, None).await;
+        let player = user(&conn, "player").await;
+        join_dnd_campaign(&conn, player, &limited.invite)
+            .await
+            .unwrap();
+
+        // without uses the limit stays, with them it's that many more than used so far
+        let code = admin_regenerate_invite(&conn, limited.id, None)
+            .await
+            .unwrap();
+        assert_ne!(code, limited.invite);
+        assert_eq!(uses(&conn, &limited).await, (1, Some(1)));
+        admin_regenerate_invite(&conn, limited.id, Some(3))
+            .await
+            .unwrap();
+        assert_eq!(uses(&conn, &limited).await, (1, Some(4)));
+        admin_regenerate_invite(&conn, unlimited.id, None)
+            .await
+            .unwrap();
+        assert_eq!(uses(&conn, &unlimited).await, (0, None));
+
+        admin_reset_invite_uses(&conn, limited.id, 2).await.unwrap();
+        assert_eq!(uses(&conn, &limited).await, (1, Some(3)));
+
+        // the old code stops working
+        let other = user(&conn, "other").await;
+        assert!(matches!(
+            join_dnd_campaign(&conn, other, &limited.invite).await,
+            Err(JoinError::InvalidInvite)
+        ));
+    }
+
+    #[sqlx::test]
+    async fn unknown_invites_cant_be_used(conn: Pool<Postgres>) {
+        let player = user(&conn, "player").await;
+        assert!(matches!(
+            join_dnd_campaign(&conn, player, "nope").await,
+            Err(JoinError::InvalidInvite)
+        ));
+    }
+
+    #[sqlx::test]
+    async fn banned_users_cant_join(conn: Pool<Postgres>) {
+        let (owner, campaign_id) = campaign(&conn).await;
+        let invite = invite(&conn, (owner, campaign_id), None, None).await;
+        let player = user(&conn, "player").await;
+        join_dnd_campaign(&conn, player, &invite.invite)
+            .await
+            .unwrap();
+
+        let mut c = conn.acquire().await.unwrap();
+        remove_campaign_member(&mut c, campaign_id, player)
+            .await
+            .unwrap();
+        ban_campaign_member(&mut c, campaign_id, player, owner)
+            .await
+            .unwrap();
+        assert!(matches!(
+            join_dnd_campaign(&conn, player, &invite.invite).await,
+            Err(JoinError::Banned)
+        ));
+
+        unban_campaign_member(&conn, campaign_id, player)
+            .await
+            .unwrap();
+        join_dnd_campaign(&conn, player, &invite.invite)
+            .await
+            .unwrap();
+        assert_eq!(uses(&conn, &invite).await, (2, None));
+    }
+
+    // joining again doesn't use the invite up, and being a member wins over it being expired or used up
+    #[sqlx::test]
+    async fn rejoining_is_a_no_op(conn: Pool<Postgres>) {
+        let (owner, campaign_id) = campaign(&conn).await;
+        let invite = invite(&conn, (owner, campaign_id), Some(1), None).await;
+        let player = user(&conn, "player").await;
+        let joined = join_dnd_campaign(&conn, player, &invite.invite)
+            .await
+            .unwrap();
+        assert_eq!(joined.campaign_id, campaign_id);
+        assert_eq!(joined.role, CampaignRole::Player);
+
+        for who in [player, owner] {
+            assert!(matches!(
+                join_dnd_campaign(&conn, who, &invite.invite).await,
+                Err(JoinError::AlreadyMember)
+            ));
+        }
+        assert_eq!(uses(&conn, &invite).await, (1, Some(1)));
+
+        sqlx::query!(
+            "UPDATE campaign_invites SET expires_at = $2 WHERE id = $1",
+            invite.id,
+            Utc::now().naive_utc() - TimeDelta::minutes(1)
+        )
+        .execute(&conn)
+        .await
+        .unwrap();
+        assert!(matches!(
+            join_dnd_campaign(&conn, player, &invite.invite).await,
+            Err(JoinError::AlreadyMember)
+        ));
+        assert_eq!(
+            get_campaign_members(&conn, campaign_id)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    // with two invites at once both can get past the member check, then the insert's ON CONFLICT
+    // has to catch the second one
+    #[sqlx::test]
+    async fn concurrent_joins_only_join_once(conn: Pool<Postgres>) {
+        let campaign = campaign(&conn).await;
+        let invites = [
+            invite(&conn, campaign, None, None).await,
+            invite(&conn, campaign, None, None).await,
+        ];
+        let player = user(&conn, "player").await;
+
+        let (a, b) = tokio::join!(
+            join_dnd_campaign(&conn, player, &invites[0].invite),
+            join_dnd_campaign(&conn, player, &invites[1].invite)
+        );
+        let results = [a, b];
+        assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .any(|res| matches!(res, Err(JoinError::AlreadyMember))));
+        let uses = uses(&conn, &invites[0]).await.0 + uses(&conn, &invites[1]).await.0;
+        assert_eq!(uses, 1);
+    }
+
+    #[sqlx::test]
+    async fn first_logins_share_one_user(conn: Pool<Postgres>) {
+        let (a, b) = tokio::join!(user(&conn, "vex"), user(&conn, "vex"));
+        assert_eq!(a, b);
+        // and logging in again later finds the same one
+        assert_eq!(user(&conn, "vex").await, a);
+
+        let users = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM users"#)
+            .fetch_one(&conn)
+            .await
+            .unwrap();
+        assert_eq!(users, 1);
+        assert_eq!(
+            get_user_by_discord_id(&conn, "vex")
+                .await
+                .unwrap()
+                .map(|u| u.id),
+            Some(a)
+        );
+        assert!(get_user_by_discord_id(&conn, "vax")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[sqlx::test]
+    async fn locked_roles_cant_change_underneath(conn: Pool<Postgres>) {
+        let campaign = campaign(&conn).await;
+        let (owner, campaign_id) = campaign;
+        let co_dm = user(&conn, "co-dm").await;
+        join_dnd_campaign(
+            &conn,
+            co_dm,
+            &invite(&conn, campaign, None, None).await.invite,
+        )
+        .await
+        .unwrap();
+        set_campaign_role(
+            &mut conn.acquire().await.unwrap(),
+            campaign_id,
+            co_dm,
+            CampaignRole::CoDm,
+        )
+        .await
+        .unwrap();
+
+        let mut tx = conn.begin().await.unwrap();
+        let roles = lock_campaign_roles(&mut tx, campaign_id, &[co_dm, owner, -1])
+            .await
+            .unwrap();
+        assert_eq!(
+            roles,
+            vec![(owner, CampaignRole::Owner), (co_dm, CampaignRole::CoDm)]
+        );
+
+        // the owner demoting them waits until whatever the co-DM is doing is done
+        let pool = conn.clone();
+        let demote = tokio::spawn(async move {
+            let mut conn = pool.acquire().await.unwrap();
+            set_campaign_role(&mut conn, campaign_id, co_dm, CampaignRole::Player).await
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!demote.is_finished());
+
+        tx.commit().await.unwrap();
+        demote.await.unwrap().unwrap();
+        assert_eq!(
+            get_campaign_role(&conn, co_dm, campaign_id).await.unwrap(),
+            Some(CampaignRole::Player)
+        );
+    }
+
+    fn effect(name: &str, character_id: i32, duration_rounds: Option<i32>) -> NewEffect {
+        NewEffect {
+            combatant_id: None,
+            character_id: Some(character_id),
+            name: name.to_string(),
+            source: None,
+            source_combatant_id: None,
+            duration_rounds,
+            ends_round: duration_rounds,
+            ends_on_turn_of: None,
+            ends_at_end_of_turn: false,
+        }
+    }
+
+    #[sqlx::test]
+    async fn character_effects_outlast_encounters(conn: Pool<Postgres>) {
+        let (owner, campaign_id) = campaign(&conn).await;
+        let character = create_character(
+            &conn,
+            owner,
+            campaign_id,
+            &CharacterFields {
+                name: Some(String::from("Vex")),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let session = create_dnd_session(&conn, owner, campaign_id, "Session")
+            .await
+            .unwrap();
+        let mut c = conn.acquire().await.unwrap();
+        add_effect(&mut c, effect("cursed", character.id, None))
+            .await
+            .unwrap();
+
+        // the character's effects move onto its combatant
+        let encounter = create_encounter(&conn, session.id, "Fight").await.unwrap();
+        let combatant = add_combatant(
+            &mut c,
+            encounter.id,
+            NewCombatant {
+                character_id: Some(character.id),
+                owner_id: Some(owner),
+                name: String::from("Vex"),
+                initiative: 10,
+                initiative_roll: None,
+            },
+        )
+        .await
+        .unwrap();
+        let names = |effects: &[Effect]| -> Vec<String> {
+            effects.iter().map(|effect| effect.name.clone()).collect()
+        };
+        assert_eq!(names(&combatant.effects), vec!["cursed"]);
+        assert!(character_in_encounter(&conn, character.id).await.unwrap());
+        assert!(get_character_effects(&conn, character.id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // only the ones without a duration come back after the fight
+        let mut bless = effect("bless", character.id, Some(10));
+        bless.combatant_id = Some(combatant.id);
+        add_effect(&mut c, bless).await.unwrap();
+        let released = release_character_effects(&mut c, &[combatant.id])
+            .await
+            .unwrap();
+        assert_eq!(released, vec![character.id]);
+        end_encounter(&mut c, encounter.id).await.unwrap();
+        assert!(!character_in_encounter(&conn, character.id).await.unwrap());
+        let effects = get_character_effects(&conn, character.id).await.unwrap();
+        assert_eq!(names(&effects), vec!["cursed"]);
+
+        delete_character_effect(&conn, character.id, effects[0].id)
+            .await
+            .unwrap();
+        assert!(matches!(
+            delete_character_effect(&conn, character.id, effects[0].id).await,
+            Err(Error::RowNotFound)
+        ));
+    }
+
+    #[sqlx::test]
+    async fn image_links_can_be_cleared(conn: Pool<Postgres>) {
+        let (_, campaign_id) = campaign(&conn).await;
+        let link = "https://example.com/map.png";
+
+        let campaign = update_dnd_campaign(&conn, campaign_id, None, Some(Some(link)))
+            .await
+            .unwrap();
+        assert_eq!(campaign.image_link.as_deref(), Some(link));
+
+        // only renaming keeps the image
+        let campaign = update_dnd_campaign(&conn, campaign_id, Some("Renamed"), None)
+            .await
+            .unwrap();
+        assert_eq!(campaign.image_link.as_deref(), Some(link));
+
+        let campaign = update_dnd_campaign(&conn, campaign_id, None, Some(None))
+            .await
+            .unwrap();
+        assert_eq!(campaign.image_link, None);
+        assert_eq!(campaign.name, "Renamed");
+    }
+}
@@ -0,0 +1,711 @@
+// Dice notation and rolling. An expression is dice and numbers added or subtracted, like
+// `4d6kh3+2` or `1d20+1d4-1`. A dice term is `[count]d<sides>` (`d%` is a d100) followed by any
+// of these modifiers, at most one of each:
+//
+// !      explode, roll another die when a die rolls its max (or matches, `!>5`)
+// r<2    reroll dice that match until they don't, `ro<2` only rerolls once
+// kh3    keep the 3 highest, also kl (lowest) and k (same as kh), the number defaults to 1
+// dl1    drop the lowest, also dh (highest)
+// >5     count successes instead of adding up the dice, `f1` also subtracts one per failure
+//
+// Comparisons are like roll20's: `>5` means 5 or more, `<2` 2 or less and a bare number (or `=3`)
+// exactly that.
+//
+// Every roll gets its own random seed that's sent along with the result. Rolling the same notation
+// with that seed gives exactly the same dice, so anyone can check a result wasn't made up.
+use std::fmt;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Serialize, Serializer};
+
+const MAX_NOTATION_LENGTH: usize = 100;
+const MAX_TERMS: usize = 20;
+const MAX_COUNT: u32 = 100;
+const MAX_SIDES: i64 = 1000;
+const MAX_CONSTANT: i64 = 100_000;
+// exploding stops adding dice to a term after this many
+const MAX_DICE_PER_TERM: usize = 500;
+const MAX_REROLLS: usize = 100;
+
+#[derive(Debug)]
+pub struct DiceError(String);
+
+impl fmt::Display for DiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DiceError {}
+
+fn error(message: impl Into<String>) -> DiceError {
+    DiceError(message.into())
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Compare {
+    AtLeast(i64),
+    AtMost(i64),
+    Exactly(i64),
+}
+
+impl Compare {
+    fn matches(self, value: i64) -> bool {
+        match self {
+            Compare::AtLeast(n) => value >= n,
+            Compare::AtMost(n) => value <= n,
+            Compare::Exactly(n) => value == n,
+        }
+    }
+
+    // Whether every face of the die matches, exploding or rerolling on that would never stop
+    fn matches_all(self, sides: i64) -> bool {
+        (1..=sides).all(|value| self.matches(value))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Select {
+    KeepHighest(usize),
+    KeepLowest(usize),
+    DropHighest(usize),
+    DropLowest(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Reroll {
+    compare: Compare,
+    once: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Dice {
+    count: u32,
+    sides: i64,
+    explode: Option<Compare>,
+    reroll: Option<Reroll>,
+    select: Option<Select>,
+    success: Option<Compare>,
+    failure: Option<Compare>,
+}
+
+#[derive(Clone, Debug)]
+enum TermKind {
+    Dice(Dice),
+    Constant(i64),
+}
+
+#[derive(Clone, Debug)]
+struct Term {
+    subtract: bool,
+    // how it was written, without the sign
+    notation: String,
+    kind: TermKind,
+}
+
+// A parsed dice expression, roll it with `roll`
+#[derive(Clone, Debug)]
+pub struct Expression {
+    notation: String,
+    terms: Vec<Term>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DieResult {
+    pub value: i64,
+    // what the die showed before being rerolled, oldest first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rerolled: Vec<i64>,
+    // this die exploded, the next one in the list is the extra die it added
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub exploded: bool,
+    // dropped by keep/drop and not part of the total
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dropped: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TermResult {
+    pub notation: String,
+    pub subtract: bool,
+    // empty for plain numbers
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dice: Vec<DieResult>,
+    // the sum of the kept dice, or successes minus failures. Before `subtract` is applied
+    pub total: i64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RollResult {
+    pub notation: String,
+    // a string since it doesn't fit in a javascript number
+    #[serde(serialize_with = "as_string")]
+    pub seed: u64,
+    pub terms: Vec<TermResult>,
+    pub total: i64,
+}
+
+fn as_string<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number(&mut self) -> Result<Option<i64>, DiceError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        self.text[start..self.pos]
+            .parse()
+            .map(Some)
+            .map_err(|_| error(format!("{} is too big", &self.text[start..self.pos])))
+    }
+
+    fn expect_number(&mut self, after: &str) -> Result<i64, DiceError> {
+        self.number()?
+            .ok_or_else(|| error(format!("Expected a number after {}", after)))
+    }
+
+    // `>5`, `<2`, `=3`, and a bare `3` if `bare` is set
+    fn compare(&mut self, bare: bool) -> Result<Option<Compare>, DiceError> {
+        let compare = if self.eat('>') {
+            Compare::AtLeast(self.expect_number(">")?)
+        } else if self.eat('<') {
+            Compare::AtMost(self.expect_number("<")?)
+        } else if self.eat('=') {
+            Compare::Exactly(self.expect_number("=")?)
+        } else if bare {
+            match self.number()? {
+                Some(n) => Compare::Exactly(n),
+                None => return Ok(None),
+            }
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(compare))
+    }
+
+    fn term(&mut self) -> Result<TermKind, DiceError> {
+        let count = self.number()?;
+        if !self.eat('d') {
+            return match count {
+                Some(n) if n <= MAX_CONSTANT => Ok(TermKind::Constant(n)),
+                Some(_) => Err(error(format!("Numbers can be at most {}", MAX_CONSTANT))),
+                None => Err(self.unexpected()),
+            };
+        }
+
+        let count = count.unwrap_or(1);
+        if !(1..=MAX_COUNT as i64).contains(&count) {
+            return Err(error(format!(
+                "Can roll between 1 and {} dice at once",
+                MAX_COUNT
+            )));
+        }
+        let sides = if self.eat('%') {
+            100
+        } else {
+            self.expect_number("d")?
+        };
+        if !(1..=MAX_SIDES).contains(&sides) {
+            return Err(error(format!("Dice can have 1 to {} sides", MAX_SIDES)));
+        }
+
+        let mut dice = Dice {
+            count: count as u32,
+            sides,
+            explode: None,
+            reroll: None,
+            select: None,
+            success: None,
+            failure: None,
+        };
+        loop {
+            let start = self.pos;
+            let modifier = match self.peek() {
+                Some('!') => "!",
+                Some('r') => "r",
+                Some('k') => "k",
+                Some('d') => "d",
+                Some('f') => "f",
+                Some('>' | '<' | '=') => ">",
+                _ => break,
+            };
+
+            let duplicate = match modifier {
+                "!" => {
+                    self.pos += 1;
+                    let compare = self.compare(true)?.unwrap_or(Compare::Exactly(sides));
+                    dice.explode.replace(compare).is_some()
+                }
+                "r" => {
+                    self.pos += 1;
+                    let once = self.eat('o');
+                    let compare = self
+                        .compare(true)?
+                        .ok_or_else(|| error("Expected what to reroll after r"))?;
+                    dice.reroll.replace(Reroll { compare, once }).is_some()
+                }
+                "k" | "d" => {
+                    self.pos += 1;
+                    let highest = if self.eat('h') {
+                        true
+                    } else if self.eat('l') {
+                        false
+                    } else if modifier == "k" {
+                        true
+                    } else {
+                        return Err(error("Expected dh or dl"));
+                    };
+                    let n = self.number()?.unwrap_or(1).min(MAX_DICE_PER_TERM as i64) as usize;
+                    let select = match (modifier, highest) {
+                        ("k", true) => Select::KeepHighest(n),
+                        ("k", false) => Select::KeepLowest(n),
+                        (_, true) => Select::DropHighest(n),
+                        (_, false) => Select::DropLowest(n),
+                    };
+                    dice.select.replace(select).is_some()
+                }
+                "f" => {
+                    self.pos += 1;
+                    let compare = self
+                        .compare(true)?
+                        .ok_or_else(|| error("Expected what counts as a failure after f"))?;
+                    dice.failure.replace(compare).is_some()
+                }
+                _ => {
+                    let compare = self.compare(false)?.unwrap();
+                    dice.success.replace(compare).is_some()
+                }
+            };
+            if duplicate {
+                return Err(error(format!(
+                    "{} is used twice",
+                    &self.text[start..self.pos]
+                )));
+            }
+        }
+
+        if dice.explode.is_some_and(|c| c.matches_all(sides)) {
+            return Err(error("Every roll would explode"));
+        }
+        if dice
+            .reroll
+            .is_some_and(|r| !r.once && r.compare.matches_all(sides))
+        {
+            return Err(error("Every roll would be rerolled"));
+        }
+        if dice.failure.is_some() && dice.success.is_none() {
+            return Err(error("Failures can only be counted along with successes"));
+        }
+        Ok(TermKind::Dice(dice))
+    }
+
+    fn unexpected(&self) -> DiceError {
+        match self.peek() {
+            Some(c) => error(format!("Unexpected '{}'", c)),
+            None => error("Unexpected end of roll"),
+        }
+    }
+}
+
+pub fn parse(notation: &str) -> Result<Expression, DiceError> {
+    let text: String = notation
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    if text.is_empty() {
+        return Err(error("Nothing to roll"));
+    }
+    if text.len() > MAX_NOTATION_LENGTH {
+        return Err(error(format!(
+            "Rolls can be at most {} characters",
+            MAX_NOTATION_LENGTH
+        )));
+    }
+
+    let mut parser = Parser {
+        text: &text,
+        pos: 0,
+    };
+    let mut terms = Vec::new();
+    let mut subtract = parser.eat('-');
+    if !subtract {
+        parser.eat('+');
+    }
+    loop {
+        let start = parser.pos;
+        let kind = parser.term()?;
+        terms.push(Term {
+            subtract,
+            notation: text[start..parser.pos].to_string(),
+            kind,
+        });
+        if terms.len() > MAX_TERMS {
+            return Err(error(format!("Rolls can have at most {} parts", MAX_TERMS)));
+        }
+
+        if parser.eat('+') {
+            subtract = false;
+        } else if parser.eat('-') {
+            subtract = true;
+        } else if parser.peek().is_none() {
+            break;
+        } else {
+            return Err(parser.unexpected());
+        }
+    }
+
+    Ok(Expression {
+        notation: text,
+        terms,
+    })
+}
+
+impl Dice {
+    fn roll_die(&self, rng: &mut ChaCha20Rng) -> DieResult {
+        let mut die = DieResult {
+            value: rng.gen_range(1..=self.sides),
+            rerolled: Vec::new(),
+            exploded: false,
+            dropped: false,
+            outcome: None,
+        };
+        if let Some(reroll) = self.reroll {
+            while reroll.compare.matches(die.value) && die.rerolled.len() < MAX_REROLLS {
+                die.rerolled.push(die.value);
+                die.value = rng.gen_range(1..=self.sides);
+                if reroll.once {
+                    break;
+                }
+            }
+        }
+        die
+    }
+
+    fn roll(&self, rng: &mut ChaCha20Rng) -> (Vec<DieResult>, i64) {
+        let mut dice: Vec<DieResult> = Vec::new();
+        for _ in 0..self.count {
+            loop {
+                let mut die = self.roll_die(rng);
+                die.exploded = self.explode.is_some_and(|c| c.matches(die.value))
+                    && dice.len() + 1 < MAX_DICE_PER_TERM;
+                let exploded = die.exploded;
+                dice.push(die);
+                if !exploded {
+                    break;
+                }
+            }
+        }
+
+        if let Some(select) = self.select {
+            // indexes from the lowest to the highest value
+            let mut order: Vec<usize> = (0..dice.len()).collect();
+            order.sort_by_key(|&i| dice[i].value);
+            let len = order.len();
+            let dropped = match select {
+                Select::KeepHighest(n) => &order[..len - n.min(len)],
+                Select::KeepLowest(n) => &order[n.min(len)..],
+                Select::DropHighest(n) => &order[len - n.min(len)..],
+                Select::DropLowest(n) => &order[..n.min(len)],
+            };
+            for &i in dropped {
+                dice[i].dropped = true;
+            }
+        }
+
+        let kept = dice.iter_mut().filter(|die| !die.dropped);
+        let total = match self.success {
+            Some(success) => kept
+                .map(|die| {
+                    if success.matches(die.value) {
+                        die.outcome = Some(Outcome::Success);
+                        1
+                    } else if self.failure.is_some_and(|f| f.matches(die.value)) {
+                        die.outcome = Some(Outcome::Failure);
+                        -1
+                    } else {
+                        0
+                    }
+                })
+                .sum(),
+            None => kept.map(|die| die.value).sum(),
+        };
+        (dice, total)
+    }
+}
+
+impl Expression {
+    // Rolls with a fresh random seed
+    pub fn roll(&self) -> RollResult {
+        self.roll_with_seed(rand::thread_rng().gen())
+    }
+
+    // Always gives the same result for the same seed, for checking a roll
+    pub fn roll_with_seed(&self, seed: u64) -> RollResult {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let terms: Vec<TermResult> = self
+            .terms
+            .iter()
+            .map(|term| {
+                let (dice, total) = match &term.kind {
+                    TermKind::Dice(dice) => dice.roll(&mut rng),
+                    TermKind::Constant(n) => (Vec::new(), *n),
+                };
+                TermResult {
+                    notation: term.notation.clone(),
+                    subtract: term.subtract,
+                    dice,
+                    total,
+                }
+            })
+            .collect();
+
+        let total = terms
+            .iter()
+            .map(|term| {
+                if term.subtract {
+                    -term.total
+                } else {
+                    term.total
+                }
+            })
+            .sum();
+        RollResult {
+            notation: self.notation.clone(),
+            seed,
+            terms,
+            total,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // enough seeds to hit the rarer cases like explosions and rerolls
+    const SEEDS: u64 = 500;
+
+    fn rolls(notation: &str) -> Vec<RollResult> {
+        let expression = parse(notation).unwrap();
+        (0..SEEDS)
+            .map(|seed| expression.roll_with_seed(seed))
+            .collect()
+    }
+
+    fn kept(term: &TermResult) -> Vec<i64> {
+        term.dice
+            .iter()
+            .filter(|die| !die.dropped)
+            .map(|die| die.value)
+            .collect()
+    }
+
+    #[test]
+    fn keep_highest_with_a_constant() {
+        for roll in rolls("4d6kh3+2") {
+            let dice = &roll.terms[0].dice;
+            assert_eq!(dice.len(), 4);
+            assert_eq!(dice.iter().filter(|die| die.dropped).count(), 1);
+
+            let dropped = dice.iter().find(|die| die.dropped).unwrap().value;
+            assert!(kept(&roll.terms[0]).iter().all(|&value| value >= dropped));
+            assert_eq!(roll.terms[1].total, 2);
+            assert_eq!(roll.total, kept(&roll.terms[0]).iter().sum::<i64>() + 2);
+            assert!((5..=20).contains(&roll.total));
+        }
+    }
+
+    #[test]
+    fn keep_lowest() {
+        for roll in rolls("2d20kl1") {
+            let term = &roll.terms[0];
+            let lowest = term.dice.iter().map(|die| die.value).min().unwrap();
+            assert_eq!(kept(term), vec![lowest]);
+            assert_eq!(roll.total, lowest);
+        }
+    }
+
+    #[test]
+    fn exploding() {
+        let mut exploded = false;
+        for roll in rolls("1d8!") {
+            let dice = &roll.terms[0].dice;
+            for (i, die) in dice.iter().enumerate() {
+                assert_eq!(die.exploded, die.value == 8);
+                // every die but the last one is there because the one before it exploded
+                assert_eq!(die.exploded, i + 1 < dice.len());
+            }
+            exploded |= dice.len() > 1;
+            assert_eq!(roll.total, dice.iter().map(|die| die.value).sum::<i64>());
+        }
+        assert!(exploded);
+    }
+
+    #[test]
+    fn percentile() {
+        let rolls = rolls("d%");
+        for roll in &rolls {
+            assert_eq!(roll.terms[0].dice.len(), 1);
+            assert!((1..=100).contains(&roll.total));
+        }
+        assert!(rolls.iter().any(|roll| roll.total > 20));
+    }
+
+    #[test]
+    fn rerolls() {
+        let mut rerolled = false;
+        for roll in rolls("4d6r<2") {
+            for die in &roll.terms[0].dice {
+                assert!(die.value > 2);
+                assert!(die.rerolled.iter().all(|&value| value <= 2));
+                rerolled |= !die.rerolled.is_empty();
+            }
+        }
+        assert!(rerolled);
+
+        let mut kept_low = false;
+        for roll in rolls("4d6ro<2") {
+            for die in &roll.terms[0].dice {
+                assert!(die.rerolled.len() <= 1);
+                assert!(die.rerolled.iter().all(|&value| value <= 2));
+                kept_low |= !die.rerolled.is_empty() && die.value <= 2;
+            }
+        }
+        // only rerolling once means a low roll can stay
+        assert!(kept_low);
+    }
+
+    #[test]
+    fn counting_successes_and_failures() {
+        // what each die should count as
+        type Expected = fn(i64) -> Option<Outcome>;
+        let cases: [(&str, Expected); 4] = [
+            ("10d10>7", |value| (value >= 7).then_some(Outcome::Success)),
+            ("10d10<3", |value| (value <= 3).then_some(Outcome::Success)),
+            ("10d6=6", |value| (value == 6).then_some(Outcome::Success)),
+            ("10d10>8f1", |value| match value {
+                8.. => Some(Outcome::Success),
+                1 => Some(Outcome::Failure),
+                _ => None,
+            }),
+        ];
+        for (notation, outcome) in cases {
+            for roll in rolls(notation) {
+                let mut total = 0;
+                for die in &roll.terms[0].dice {
+                    assert_eq!(die.outcome, outcome(die.value), "{}", notation);
+                    total += match die.outcome {
+                        Some(Outcome::Success) => 1,
+                        Some(Outcome::Failure) => -1,
+                        None => 0,
+                    };
+                }
+                assert_eq!(roll.total, total, "{}", notation);
+            }
+        }
+    }
+
+    #[test]
+    fn subtracting() {
+        for roll in rolls("1d20-1d4-1") {
+            let [d20, d4, one] = &roll.terms[..] else {
+                panic!("expected 3 terms");
+            };
+            assert!(!d20.subtract && d4.subtract && one.subtract);
+            assert_eq!(roll.total, d20.total - d4.total - 1);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_notation() {
+        let too_long = "1d6+".repeat(30) + "1";
+        let too_many_terms = vec!["1"; MAX_TERMS + 1].join("+");
+        for notation in [
+            "",
+            "   ",
+            "d",
+            "1d",
+            "2d6+",
+            "abc",
+            "1d6x",
+            "1d6>",
+            "1d6r",
+            "1d6d",
+            "0d6",
+            "101d6",
+            "1d0",
+            "1d1001",
+            "100001",
+            "99999999999999999999d6",
+            "4d6kh3kh3",
+            "1d6!!",
+            "1d6!>1",
+            "1d6r<6",
+            "1d6f1",
+            too_long.as_str(),
+            too_many_terms.as_str(),
+        ] {
+            assert!(
+                parse(notation).is_err(),
+                "{:?} should be rejected",
+                notation
+            );
+        }
+    }
+
+    #[test]
+    fn limits_are_allowed() {
+        for notation in ["100d1000", "100000", "1d6r<5", "1d6ro<6", "1d6!>2"] {
+            assert!(parse(notation).is_ok(), "{:?} should be allowed", notation);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_dice() {
+        for notation in ["4d6kh3+2", "1d8!", "10d10>8f1r1", "d%-1d4ro1"] {
+            let seed = rand::thread_rng().gen();
+            let first = parse(notation).unwrap().roll_with_seed(seed);
+            // parsed again, like when verifying a stored roll
+            let second = parse(notation).unwrap().roll_with_seed(seed);
+            assert_eq!(
+                serde_json::to_value(&first).unwrap(),
+                serde_json::to_value(&second).unwrap()
+            );
+            assert_eq!(first.seed, seed);
+        }
+    }
+}
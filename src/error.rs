@@ -0,0 +1,139 @@
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
+use serde::Serialize;
+use std::fmt;
+
+use crate::{combat::CombatError, db::JoinError};
+
+// Error type shared by the http handlers, every variant gets turned into a json body like
+// { "error": "not_found", "message": "Campaign not found" } with the matching status code
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    message: &'a str,
+}
+
+impl ApiError {
+    fn kind(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(m)
+            | ApiError::Unauthorized(m)
+            | ApiError::Forbidden(m)
+            | ApiError::NotFound(m)
+            | ApiError::Conflict(m)
+            | ApiError::Internal(m) => m,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.message())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        if let ApiError::Unauthorized(_) = self {
+            res.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+
+        res.json(ErrorBody {
+            error: self.kind(),
+            message: self.message(),
+        })
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => ApiError::NotFound(String::from("Not found")),
+            sqlx::Error::Database(ref e) if e.is_unique_violation() => {
+                ApiError::Conflict(String::from("Already exists"))
+            }
+            sqlx::Error::Database(ref e) if e.is_foreign_key_violation() => {
+                ApiError::BadRequest(String::from("Referenced row doesn't exist"))
+            }
+            _ => {
+                log::error!("Database error: {}", err);
+                ApiError::Internal(String::from("Database error"))
+            }
+        }
+    }
+}
+
+// Something that doesn't work with the encounter as it is right now, like delaying when it isn't
+// your turn
+impl From<CombatError> for ApiError {
+    fn from(err: CombatError) -> Self {
+        ApiError::Conflict(err.to_string())
+    }
+}
+
+// Has its own error kinds so the client can tell the user why they couldn't join
+impl ResponseError for JoinError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            JoinError::InvalidInvite => StatusCode::NOT_FOUND,
+            JoinError::Expired | JoinError::Exhausted => StatusCode::GONE,
+            JoinError::AlreadyMember => StatusCode::CONFLICT,
+            JoinError::Banned => StatusCode::FORBIDDEN,
+            JoinError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let kind = match self {
+            JoinError::InvalidInvite => "invalid_invite",
+            JoinError::Expired => "invite_expired",
+            JoinError::Exhausted => "invite_exhausted",
+            JoinError::AlreadyMember => "already_member",
+            JoinError::Banned => "banned",
+            JoinError::Database(e) => {
+                log::error!("Database error: {}", e);
+                return ApiError::Internal(String::from("Database error")).error_response();
+            }
+        };
+
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: kind,
+            message: &self.to_string(),
+        })
+    }
+}
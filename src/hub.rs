@@ -0,0 +1,458 @@
+use std::{collections::HashMap, time::Duration};
+
+use actix::{Actor, AsyncContext, Context, Handler};
+use actix_ws::{CloseCode, CloseReason, Session};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{
+    protocol::{ErrorCode, ServerFrame, ServerMessage},
+    rooms::{ConnectionId, Room, RoomMembers, SeenBy, Target},
+    DiscordUser,
+};
+
+// Frames a connection can have queued before it counts as too slow and gets dropped. Has to fit a
+// whole replay buffer plus the Welcome that goes before it
+const OUTBOX_CAPACITY: usize = 512;
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(10);
+
+// The sending half of a socket. Frames get queued here and written out by the socket's own task,
+// so nothing that broadcasts ever waits on a client
+#[derive(Clone)]
+pub struct Outbox {
+    frames: mpsc::Sender<String>,
+    close: mpsc::UnboundedSender<CloseReason>,
+}
+
+impl Outbox {
+    // Starts the task that writes queued frames to the socket
+    pub fn spawn(session: Session) -> Outbox {
+        let (frames, mut frames_rx) = mpsc::channel::<String>(OUTBOX_CAPACITY);
+        let (close, mut close_rx) = mpsc::unbounded_channel::<CloseReason>();
+
+        actix_web::rt::spawn(async move {
+            let mut session = session;
+            loop {
+                tokio::select! {
+                    frame = frames_rx.recv() => match frame {
+                        Some(text) => {
+                            if session.text(text).await.is_err() {
+                                break;
+                            }
+                        }
+                        None => break,
+                    },
+                    Some(reason) = close_rx.recv() => {
+                        let _ = session.close(Some(reason)).await;
+                        break;
+                    }
+                }
+            }
+        });
+
+        Outbox { frames, close }
+    }
+
+    // An outbox without a socket behind it, tests read what would have been written from the
+    // receivers
+    #[cfg(test)]
+    pub fn channel() -> (
+        Outbox,
+        mpsc::Receiver<String>,
+        mpsc::UnboundedReceiver<CloseReason>,
+    ) {
+        let (frames, frames_rx) = mpsc::channel::<String>(OUTBOX_CAPACITY);
+        let (close, close_rx) = mpsc::unbounded_channel::<CloseReason>();
+        (Outbox { frames, close }, frames_rx, close_rx)
+    }
+
+    // For a connection's replies to itself, waits for room in the queue
+    pub async fn send(&self, frame: ServerFrame) {
+        let _ = self.frames.send(frame.to_text()).await;
+    }
+
+    // Returns false if the queue is full, a closed socket isn't an error since its task will
+    // leave the room on its own
+    fn try_send(&self, text: String) -> bool {
+        !matches!(self.frames.try_send(text), Err(TrySendError::Full(_)))
+    }
+
+    fn close(&self, reason: CloseReason) {
+        let _ = self.close.send(reason);
+    }
+}
+
+// Owns every /ws room. Connections talk to it with messages instead of sharing a lock, and it only
+// ever try_sends into outboxes so one slow client can't hold up a room
+#[derive(Default)]
+pub struct Hub {
+    rooms: HashMap<Room, RoomMembers>,
+}
+
+impl Actor for Hub {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(EXPIRE_INTERVAL, |hub, _| hub.expire_detached());
+    }
+}
+
+impl Hub {
+    // Queues the frame for every target, anyone whose queue is full gets disconnected. They're
+    // only detached so the client can Resume and get what it missed from the replay buffer
+    fn deliver(&mut self, room: Room, targets: Vec<Target>, text: &str) {
+        for target in targets {
+            if target.outbox.try_send(text.to_string()) {
+                continue;
+            }
+
+            log::warn!("Connection {} is too slow, disconnecting it", target.id);
+            target.outbox.close(CloseReason {
+                code: CloseCode::Policy,
+                description: Some(String::from("Too slow to keep up")),
+            });
+            if let Some(members) = self.rooms.get_mut(&room) {
+                members.detach(target.id, target.socket);
+            }
+        }
+    }
+
+    fn broadcast(&mut self, room: Room, message: ServerMessage) {
+        let Some(members) = self.rooms.get_mut(&room) else {
+            return;
+        };
+        let (_, text, targets) = members.publish(message, SeenBy::Everyone);
+        self.deliver(room, targets, &text);
+    }
+
+    fn publish(
+        &mut self,
+        room: Room,
+        message: ServerMessage,
+        seen_by: SeenBy,
+        sender: Option<(ConnectionId, Option<String>)>,
+    ) {
+        let Some(members) = self.rooms.get_mut(&room) else {
+            return;
+        };
+
+        let sender_id = sender.as_ref().map(|(id, _)| *id);
+        let (seq, text, targets) = members.publish(message.clone(), seen_by);
+        let (others, sender_target): (Vec<Target>, Vec<Target>) = targets
+            .into_iter()
+            .partition(|target| Some(target.id) != sender_id);
+
+        if let (Some((_, request_id)), Some(target)) = (sender, sender_target.into_iter().next()) {
+            let mut frame = ServerFrame::event(seq, message);
+            frame.request_id = request_id;
+            self.deliver(room, vec![target], &frame.to_text());
+        }
+        self.deliver(room, others, &text);
+    }
+
+    fn expire_detached(&mut self) {
+        let mut changed = Vec::new();
+        for (room, members) in self.rooms.iter_mut() {
+            let mut last = false;
+            for (id, socket) in members.expired() {
+                last |= members.leave(id, socket);
+            }
+            if last {
+                changed.push(*room);
+            }
+        }
+
+        for room in changed {
+            let presence = self.rooms[&room].presence();
+            self.broadcast(room, presence);
+        }
+        self.rooms.retain(|_, members| !members.is_empty());
+    }
+}
+
+// A connection finished its Hello. The hub sends it Welcome and the room's presence
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct Join {
+    pub room: Room,
+    pub id: ConnectionId,
+    pub socket: u64,
+    pub user: DiscordUser,
+    pub version: u32,
+    pub request_id: Option<String>,
+    pub outbox: Outbox,
+}
+
+impl Handler<Join> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
+        let members = self.rooms.entry(msg.room).or_default();
+        let (resume_token, first) = members.join(msg.id, msg.socket, msg.outbox.clone(), &msg.user);
+
+        let welcome = ServerMessage::Welcome {
+            version: msg.version,
+            connection_id: msg.id,
+            user: msg.user,
+            room: msg.room,
+            resume_token,
+            seq: members.seq(),
+            resumed: false,
+        };
+        msg.outbox
+            .try_send(ServerFrame::reply(msg.request_id, welcome).to_text());
+
+        // everyone only hears about it when the user wasn't already in the room from somewhere else
+        let presence = members.presence();
+        if first {
+            self.broadcast(msg.room, presence);
+        } else {
+            msg.outbox.try_send(ServerFrame::new(presence).to_text());
+        }
+    }
+}
+
+// A reconnecting socket wants to take over the connection its resume token belongs to. On success
+// the hub sends Welcome and replays what was missed, and returns the connection id
+#[derive(actix::Message)]
+#[rtype(result = "Option<ConnectionId>")]
+pub struct Resume {
+    pub room: Room,
+    pub socket: u64,
+    // the token has to belong to the same user
+    pub user: DiscordUser,
+    pub version: u32,
+    pub resume_token: String,
+    pub last_seq: u64,
+    pub request_id: Option<String>,
+    pub outbox: Outbox,
+}
+
+impl Handler<Resume> for Hub {
+    type Result = Option<ConnectionId>;
+
+    fn handle(&mut self, msg: Resume, _: &mut Context<Self>) -> Self::Result {
+        let members = self.rooms.get_mut(&msg.room)?;
+        let gap = !members.can_replay_from(msg.last_seq);
+        let resumed = members.claim(
+            &msg.resume_token,
+            &msg.user.id,
+            msg.socket,
+            msg.outbox.clone(),
+        )?;
+
+        if let Some(old_outbox) = resumed.old_outbox {
+            old_outbox.close(CloseReason {
+                code: CloseCode::Normal,
+                description: Some(String::from("Resumed on another socket")),
+            });
+        }
+
+        log::info!("Connection {} resumed by {}", resumed.id, msg.user.id);
+        let missed = members.events_after(msg.last_seq, &msg.user.id);
+        let welcome = ServerMessage::Welcome {
+            version: msg.version,
+            connection_id: resumed.id,
+            user: msg.user,
+            room: msg.room,
+            resume_token: resumed.resume_token,
+            seq: resumed.seq,
+            resumed: true,
+        };
+        msg.outbox
+            .try_send(ServerFrame::reply(msg.request_id, welcome).to_text());
+
+        for text in missed {
+            msg.outbox.try_send(text);
+        }
+        if gap {
+            msg.outbox.try_send(
+                ServerFrame::error(
+                    None,
+                    ErrorCode::ResumeGap,
+                    "Some events are too old to replay, fetch the history again",
+                )
+                .to_text(),
+            );
+        }
+
+        Some(resumed.id)
+    }
+}
+
+// The socket is gone. `resumable` keeps the connection around for a while so the client can Resume
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct Leave {
+    pub room: Room,
+    pub id: ConnectionId,
+    pub socket: u64,
+    pub resumable: bool,
+}
+
+impl Handler<Leave> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, _: &mut Context<Self>) {
+        let Some(members) = self.rooms.get_mut(&msg.room) else {
+            return;
+        };
+
+        if msg.resumable {
+            members.detach(msg.id, msg.socket);
+            return;
+        }
+
+        if members.leave(msg.id, msg.socket) {
+            let presence = members.presence();
+            self.broadcast(msg.room, presence);
+        }
+        if self.rooms.get(&msg.room).is_some_and(|m| m.is_empty()) {
+            self.rooms.remove(&msg.room);
+        }
+    }
+}
+
+// Broadcasts to the room, or only to some users in it. If there's a sender it gets its own copy
+// with its request_id instead of the plain broadcast
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct Publish {
+    pub room: Room,
+    pub message: ServerMessage,
+    pub seen_by: SeenBy,
+    pub sender: Option<(ConnectionId, Option<String>)>,
+}
+
+impl Handler<Publish> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _: &mut Context<Self>) {
+        self.publish(msg.room, msg.message, msg.seen_by, msg.sender);
+    }
+}
+
+// Like Publish, but to every room of the campaign, the campaign wide one and every dnd session's
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct PublishToCampaign {
+    pub campaign_id: i32,
+    pub message: ServerMessage,
+    pub seen_by: SeenBy,
+    pub sender: Option<(ConnectionId, Option<String>)>,
+}
+
+impl Handler<PublishToCampaign> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: PublishToCampaign, _: &mut Context<Self>) {
+        let rooms: Vec<Room> = self
+            .rooms
+            .keys()
+            .filter(|room| room.campaign_id == msg.campaign_id)
+            .copied()
+            .collect();
+        for room in rooms {
+            self.publish(
+                room,
+                msg.message.clone(),
+                msg.seen_by.clone(),
+                msg.sender.clone(),
+            );
+        }
+    }
+}
+
+// The user isn't in the campaign anymore. Closes all their connections to its rooms, without
+// letting them Resume
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct RemoveUser {
+    pub campaign_id: i32,
+    // discord id
+    pub user_id: String,
+}
+
+impl Handler<RemoveUser> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveUser, _: &mut Context<Self>) {
+        let mut changed = Vec::new();
+        for (room, members) in self.rooms.iter_mut() {
+            if room.campaign_id != msg.campaign_id {
+                continue;
+            }
+
+            let mut last = false;
+            for (id, socket, outbox) in members.connections_of(&msg.user_id) {
+                if let Some(outbox) = outbox {
+                    outbox.close(CloseReason {
+                        code: CloseCode::Policy,
+                        description: Some(String::from("Removed from the campaign")),
+                    });
+                }
+                last |= members.leave(id, socket);
+            }
+            if last {
+                changed.push(*room);
+            }
+        }
+
+        for room in changed {
+            let presence = self.rooms[&room].presence();
+            self.broadcast(room, presence);
+        }
+        self.rooms.retain(|_, members| !members.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str) -> DiscordUser {
+        DiscordUser {
+            id: id.to_string(),
+            username: id.to_string(),
+            discriminator: String::from("0"),
+            global_name: None,
+            avatar: None,
+            accent_color: None,
+        }
+    }
+
+    #[test]
+    fn slow_connections_get_dropped() {
+        let room = Room {
+            campaign_id: 1,
+            session_id: None,
+        };
+        let mut hub = Hub::default();
+        let (slow, _slow_frames, mut slow_close) = Outbox::channel();
+        let (fast, mut fast_frames, mut fast_close) = Outbox::channel();
+        let members = hub.rooms.entry(room).or_default();
+        members.join(1, 1, slow, &user("slow"));
+        members.join(2, 2, fast, &user("fast"));
+
+        // the slow one never reads, so its outbox fills up and the one after that doesn't fit
+        let mut received = 0;
+        for _ in 0..=OUTBOX_CAPACITY {
+            hub.publish(room, ServerMessage::Pong, SeenBy::Everyone, None);
+            while fast_frames.try_recv().is_ok() {
+                received += 1;
+            }
+        }
+        assert_eq!(received, OUTBOX_CAPACITY + 1);
+
+        let reason = slow_close.try_recv().unwrap();
+        assert_eq!(reason.code, CloseCode::Policy);
+        assert!(fast_close.try_recv().is_err());
+        // detached rather than gone, so it can still Resume
+        let members = &hub.rooms[&room];
+        assert!(members.connections_of("slow")[0].2.is_none());
+        assert!(members.connections_of("fast")[0].2.is_some());
+
+        hub.publish(room, ServerMessage::Pong, SeenBy::Everyone, None);
+        assert!(fast_frames.try_recv().is_ok());
+        assert!(slow_close.try_recv().is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use actix_web::{get, web, HttpResponse};
+use futures_util::future::{ready, LocalBoxFuture};
+use oauth2::{url, CsrfToken, PkceCodeVerifier};
+use serde::Deserialize;
+
+use super::{AuthorizeRequest, IdentityProvider};
+use crate::{config, db::AccessTokens, error::ApiError, DiscordUser};
+
+// Logs in as one of the users from the config without talking to anything external,
+// meant for local development and tests. Never enable this on a real server
+#[derive(Clone)]
+pub struct DevProvider {
+    base_url: String,
+    // where the consent page sends the browser, the same place discord would
+    redirect_url: url::Url,
+    users: Vec<config::DevUser>,
+}
+
+impl DevProvider {
+    pub fn new(config: &config::Config) -> Result<Self, url::ParseError> {
+        Ok(DevProvider {
+            base_url: config.server.public_url.trim_end_matches('/').to_string(),
+            redirect_url: url::Url::parse(&config.oauth_redirect_url())?,
+            users: config.global.dev_users.clone(),
+        })
+    }
+
+    // the redirect url with the code and state added to whatever query it already has
+    fn login_url(&self, code: &str, state: &str) -> String {
+        let mut url = self.redirect_url.clone();
+        url.query_pairs_mut()
+            .append_pair("code", code)
+            .append_pair("state", state);
+        url.to_string()
+    }
+
+    fn find_user(&self, id: &str) -> Result<&config::DevUser, ApiError> {
+        self.users
+            .iter()
+            .find(|u| u.id == id)
+            .ok_or_else(|| ApiError::Forbidden(String::from("Unknown dev user")))
+    }
+
+    fn tokens(&self, id: &str) -> Result<AccessTokens, ApiError> {
+        let user = self.find_user(id)?;
+        Ok(AccessTokens {
+            access_token: format!("dev:{}", user.id),
+            refresh_token: format!("dev:{}", user.id),
+            expires_at: Some(chrono::Utc::now().naive_utc() + chrono::TimeDelta::days(30)),
+        })
+    }
+}
+
+impl IdentityProvider for DevProvider {
+    fn authorize_url(&self) -> AuthorizeRequest {
+        let state = CsrfToken::new_random().secret().clone();
+
+        AuthorizeRequest {
+            url: format!("{}/dev/authorize?state={}", self.base_url, state),
+            state,
+            pkce_verifier: None,
+        }
+    }
+
+    // the "code" is just the id of the dev user that was picked on /dev/authorize
+    fn exchange_code<'a>(
+        &'a self,
+        code: &'a str,
+        _pkce_verifier: Option<PkceCodeVerifier>,
+    ) -> LocalBoxFuture<'a, Result<AccessTokens, ApiError>> {
+        Box::pin(ready(self.tokens(code)))
+    }
+
+    fn fetch_profile<'a>(
+        &'a self,
+        access_token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<DiscordUser, ApiError>> {
+        let profile = access_token
+            .strip_prefix("dev:")
+            .ok_or_else(|| ApiError::Forbidden(String::from("Invalid token")))
+            .and_then(|id| self.find_user(id))
+            .map(|user| DiscordUser {
+                id: format!("dev-{}", user.id),
+                username: user.username.clone(),
+                discriminator: String::from("0"),
+                global_name: user.global_name.clone(),
+                avatar: None,
+                accent_color: None,
+            });
+
+        Box::pin(ready(profile))
+    }
+
+    fn refresh_token<'a>(
+        &'a self,
+        refresh_token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<AccessTokens, ApiError>> {
+        let tokens = refresh_token
+            .strip_prefix("dev:")
+            .ok_or_else(|| ApiError::Unauthorized(String::from("Invalid refresh token")))
+            .and_then(|id| self.tokens(id));
+
+        Box::pin(ready(tokens))
+    }
+
+    fn revoke_token<'a>(
+        &'a self,
+        _access_token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), ApiError>> {
+        Box::pin(ready(Ok(())))
+    }
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    state: String,
+}
+
+// Stand-in for discord's consent screen, lists the dev users as links back to the redirect url
+#[get("/dev/authorize")]
+pub async fn authorize(
+    query: web::Query<AuthorizeQuery>,
+    provider: web::Data<DevProvider>,
+) -> HttpResponse {
+    let links: String = provider
+        .users
+        .iter()
+        .map(|u| {
+            format!(
+                "<li><a href=\"{}\">{}</a></li>",
+                html_escape(&provider.login_url(&u.id, &query.state)),
+                html_escape(&u.username)
+            )
+        })
+        .collect();
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!DOCTYPE html><html><body><h1>Log in as</h1><ul>{}</ul></body></html>",
+            links
+        ))
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(redirect_url: Option<&str>) -> DevProvider {
+        let mut config: config::Config = toml::from_str(
+            "[global]\ndatabase_url = \"postgres://localhost/dnd\"\nidentity_provider = \"dev\"",
+        )
+        .unwrap();
+        config.global.oauth_redirect_url = redirect_url.map(String::from);
+        DevProvider::new(&config).unwrap()
+    }
+
+    #[test]
+    fn links_go_to_the_redirect_url() {
+        assert_eq!(
+            provider(None).login_url("1", "abc"),
+            "http://localhost:8080/login?code=1&state=abc"
+        );
+        assert_eq!(
+            provider(Some("https://app.example.com/auth/done?from=dev")).login_url("a&b", "x y#"),
+            "https://app.example.com/auth/done?from=dev&code=a%26b&state=x+y%23"
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use futures_util::future::LocalBoxFuture;
+use oauth2::basic::{BasicClient, BasicErrorResponseType, BasicTokenResponse};
+use oauth2::reqwest::async_http_client;
+use oauth2::url;
+use oauth2::{
+    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, RefreshToken, RequestTokenError, Scope, TokenResponse, TokenUrl,
+};
+use reqwest::StatusCode;
+
+use super::{AuthorizeRequest, IdentityProvider};
+use crate::{config, db::AccessTokens, error::ApiError, DiscordUser};
+
+pub struct DiscordProvider {
+    client: BasicClient,
+    client_id: String,
+    client_secret: String,
+    profile_url: String,
+    revocation_url: String,
+}
+
+impl DiscordProvider {
+    pub fn new(config: &config::Config) -> Result<Self, url::ParseError> {
+        let global = &config.global;
+        let client = BasicClient::new(
+            ClientId::new(global.discord_client.clone()),
+            Some(ClientSecret::new(global.discord_secret.clone())),
+            AuthUrl::new(global.discord_authorize_url.clone())?,
+            Some(TokenUrl::new(global.discord_token_url.clone())?),
+        )
+        .set_redirect_uri(RedirectUrl::new(config.oauth_redirect_url())?);
+
+        Ok(DiscordProvider {
+            client,
+            client_id: global.discord_client.clone(),
+            client_secret: global.discord_secret.clone(),
+            profile_url: global.discord_profile_url.clone(),
+            revocation_url: url::Url::parse(&global.discord_revocation_url)?.to_string(),
+        })
+    }
+}
+
+fn into_tokens(t: BasicTokenResponse, old_refresh_token: Option<&str>) -> AccessTokens {
+    AccessTokens {
+        access_token: t.access_token().secret().to_string(),
+        // discord normally sends a new refresh token but keep the old one if it doesn't
+        refresh_token: t
+            .refresh_token()
+            .map(|r| r.secret().to_string())
+            .or(old_refresh_token.map(String::from))
+            .unwrap_or_default(),
+        expires_at: t
+            .expires_in()
+            .and_then(|d| chrono::TimeDelta::from_std(d).ok())
+            .map(|d| chrono::Utc::now().naive_utc() + d),
+    }
+}
+
+impl IdentityProvider for DiscordProvider {
+    fn authorize_url(&self) -> AuthorizeRequest {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let (auth_url, csrf_token) = self
+            .client
+            .authorize_url(CsrfToken::new_random)
+            .add_scope(Scope::new("identify".to_string()))
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+
+        AuthorizeRequest {
+            url: auth_url.to_string(),
+            state: csrf_token.secret().clone(),
+            pkce_verifier: Some(pkce_verifier),
+        }
+    }
+
+    fn exchange_code<'a>(
+        &'a self,
+        code: &'a str,
+        pkce_verifier: Option<PkceCodeVerifier>,
+    ) -> LocalBoxFuture<'a, Result<AccessTokens, ApiError>> {
+        Box::pin(async move {
+            let mut request = self
+                .client
+                .exchange_code(AuthorizationCode::new(code.to_string()));
+            if let Some(verifier) = pkce_verifier {
+                request = request.set_pkce_verifier(verifier);
+            }
+
+            let t = request
+                .request_async(async_http_client)
+                .await
+                .map_err(|_| ApiError::Forbidden(String::from("Failed to log in")))?;
+
+            Ok(into_tokens(t, None))
+        })
+    }
+
+    fn fetch_profile<'a>(
+        &'a self,
+        access_token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<DiscordUser, ApiError>> {
+        Box::pin(async move {
+            let client = reqwest::Client::new();
+            let resp = client
+                .get(&self.profile_url)
+                .header(
+                    reqwest::header::AUTHORIZATION,
+                    format!("Bearer {}", access_token),
+                )
+                .send()
+                .await;
+
+            let user = match resp {
+                Ok(r) => {
+                    if r.status() == StatusCode::OK {
+                        r.json::<DiscordUser>().await.map_err(|_| {
+                            ApiError::Forbidden(String::from("Invalid token parsing"))
+                        })?
+                    } else {
+                        return Err(ApiError::Forbidden(String::from("Invalid token")));
+                    }
+                }
+                Err(_) => {
+                    return Err(ApiError::Forbidden(String::from("Invalid token")));
+                }
+            };
+
+            Ok(user)
+        })
+    }
+
+    fn refresh_token<'a>(
+        &'a self,
+        refresh_token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<AccessTokens, ApiError>> {
+        Box::pin(async move {
+            let t = self
+                .client
+                .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+                .request_async(async_http_client)
+                .await
+                .map_err(|e| match e {
+                    // the refresh token was revoked or already used, retrying won't help
+                    RequestTokenError::ServerResponse(ref resp)
+                        if *resp.error() == BasicErrorResponseType::InvalidGrant =>
+                    {
+                        ApiError::Unauthorized(format!("Failed to refresh token: {}", e))
+                    }
+                    e => ApiError::Internal(format!("Failed to refresh token: {}", e)),
+                })?;
+
+            Ok(into_tokens(t, Some(refresh_token)))
+        })
+    }
+
+    fn revoke_token<'a>(
+        &'a self,
+        access_token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), ApiError>> {
+        // done by hand since oauth2's revoke_token refuses anything that isn't https,
+        // which rules out the mock-oauth server
+        Box::pin(async move {
+            let resp = reqwest::Client::new()
+                .post(&self.revocation_url)
+                .basic_auth(&self.client_id, Some(&self.client_secret))
+                .form(&[("token", access_token), ("token_type_hint", "access_token")])
+                .send()
+                .await
+                .map_err(|e| ApiError::Internal(format!("Failed to revoke token: {}", e)))?;
+
+            if !resp.status().is_success() {
+                return Err(ApiError::Internal(format!(
+                    "Failed to revoke token: {}",
+                    resp.status()
+                )));
+            }
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,45 @@
+use futures_util::future::LocalBoxFuture;
+use oauth2::PkceCodeVerifier;
+
+use crate::{db::AccessTokens, error::ApiError, DiscordUser};
+
+pub mod dev;
+pub mod discord;
+
+pub use dev::DevProvider;
+pub use discord::DiscordProvider;
+
+// What /ws-login sends the client to, `state` is the csrf token the callback has to come back with
+pub struct AuthorizeRequest {
+    pub url: String,
+    pub state: String,
+    pub pkce_verifier: Option<PkceCodeVerifier>,
+}
+
+// Something users can log in with. Profiles are stored in the `session` table the same way
+// no matter which provider they came from, so providers other than discord prefix their ids
+pub trait IdentityProvider: Send + Sync {
+    fn authorize_url(&self) -> AuthorizeRequest;
+
+    fn exchange_code<'a>(
+        &'a self,
+        code: &'a str,
+        pkce_verifier: Option<PkceCodeVerifier>,
+    ) -> LocalBoxFuture<'a, Result<AccessTokens, ApiError>>;
+
+    fn fetch_profile<'a>(
+        &'a self,
+        access_token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<DiscordUser, ApiError>>;
+
+    // Unauthorized means the refresh token itself was rejected and the user has to log in again
+    fn refresh_token<'a>(
+        &'a self,
+        refresh_token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<AccessTokens, ApiError>>;
+
+    fn revoke_token<'a>(
+        &'a self,
+        access_token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), ApiError>>;
+}
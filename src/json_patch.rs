@@ -0,0 +1,415 @@
+// JSON Patch (RFC 6902), e.g.
+//
+// [{ "op": "replace", "path": "/current_hp", "value": 7 }, { "op": "add", "path": "/skills/-", "value": "stealth" }]
+//
+// Paths are JSON Pointers (RFC 6901). A patch is applied all or nothing, if any op fails the
+// document is left as it was. The document can't grow past the size given to `apply`, checked after
+// every op since copies can double it each time.
+use std::fmt;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum PatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    // fails the whole patch if the value at `path` isn't `value`
+    Test { path: String, value: Value },
+}
+
+#[derive(Debug)]
+pub struct PatchError(String);
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+fn error(message: impl Into<String>) -> PatchError {
+    PatchError(message.into())
+}
+
+// Splits a pointer into its parent and the unescaped last token, None for the whole document
+fn split(path: &str) -> Result<Option<(&str, String)>, PatchError> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+    if !path.starts_with('/') {
+        return Err(error(format!("{} isn't a valid path", path)));
+    }
+    let (parent, last) = path.rsplit_once('/').unwrap();
+    Ok(Some((parent, last.replace("~1", "/").replace("~0", "~"))))
+}
+
+fn index(token: &str, len: usize, path: &str) -> Result<usize, PatchError> {
+    // only digits, no leading zeros, signs or exponents
+    if token.is_empty()
+        || !token.bytes().all(|b| b.is_ascii_digit())
+        || (token.len() > 1 && token.starts_with('0'))
+    {
+        return Err(error(format!("{} isn't a valid array index", path)));
+    }
+    // too many digits for a usize is out of bounds as well
+    match token.parse::<usize>() {
+        Ok(i) if i < len => Ok(i),
+        _ => Err(error(format!("{} is out of bounds", path))),
+    }
+}
+
+fn parent_mut<'a>(
+    doc: &'a mut Value,
+    parent: &str,
+    path: &str,
+) -> Result<&'a mut Value, PatchError> {
+    doc.pointer_mut(parent)
+        .ok_or_else(|| error(format!("{} doesn't exist", path)))
+}
+
+fn get(doc: &Value, path: &str) -> Result<Value, PatchError> {
+    doc.pointer(path)
+        .cloned()
+        .ok_or_else(|| error(format!("{} doesn't exist", path)))
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), PatchError> {
+    let Some((parent, token)) = split(path)? else {
+        *doc = value;
+        return Ok(());
+    };
+
+    match parent_mut(doc, parent, path)? {
+        Value::Object(map) => {
+            map.insert(token, value);
+        }
+        Value::Array(array) => {
+            if token == "-" {
+                array.push(value);
+            } else {
+                // inserting right after the last element is fine
+                let i = index(&token, array.len() + 1, path)?;
+                array.insert(i, value);
+            }
+        }
+        _ => return Err(error(format!("Can't add to {}", path))),
+    }
+    Ok(())
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, PatchError> {
+    let Some((parent, token)) = split(path)? else {
+        return Err(error("Can't remove the whole document"));
+    };
+
+    match parent_mut(doc, parent, path)? {
+        Value::Object(map) => map
+            .remove(&token)
+            .ok_or_else(|| error(format!("{} doesn't exist", path))),
+        Value::Array(array) => {
+            let i = index(&token, array.len(), path)?;
+            Ok(array.remove(i))
+        }
+        _ => Err(error(format!("{} doesn't exist", path))),
+    }
+}
+
+fn apply_op(doc: &mut Value, op: &PatchOp) -> Result<(), PatchError> {
+    match op {
+        PatchOp::Add { path, value } => add(doc, path, value.clone()),
+        PatchOp::Remove { path } => remove(doc, path).map(|_| ()),
+        PatchOp::Replace { path, value } => {
+            let target = doc
+                .pointer_mut(path)
+                .ok_or_else(|| error(format!("{} doesn't exist", path)))?;
+            *target = value.clone();
+            Ok(())
+        }
+        PatchOp::Move { from, path } => {
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(error(format!("Can't move {} into itself", from)));
+            }
+            let value = remove(doc, from)?;
+            add(doc, path, value)
+        }
+        PatchOp::Copy { from, path } => {
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(error(format!("Can't copy {} into itself", from)));
+            }
+            let value = get(doc, from)?;
+            add(doc, path, value)
+        }
+        PatchOp::Test { path, value } => {
+            if &get(doc, path)? != value {
+                return Err(error(format!("Test failed for {}", path)));
+            }
+            Ok(())
+        }
+    }
+}
+
+// Size of the document as JSON, in bytes
+fn size(doc: &Value) -> usize {
+    serde_json::to_vec(doc)
+        .map(|json| json.len())
+        .unwrap_or(usize::MAX)
+}
+
+pub fn apply(doc: &mut Value, patch: &[PatchOp], max_size: usize) -> Result<(), PatchError> {
+    let mut patched = doc.clone();
+    for op in patch {
+        apply_op(&mut patched, op)?;
+        if size(&patched) > max_size {
+            return Err(error(format!(
+                "The patched document can be at most {} bytes",
+                max_size
+            )));
+        }
+    }
+    *doc = patched;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const MAX_SIZE: usize = 1024;
+
+    fn ops(patch: Value) -> Vec<PatchOp> {
+        serde_json::from_value(patch).unwrap()
+    }
+
+    fn doc() -> Value {
+        json!({
+            "name": "Vex",
+            "skills": ["stealth", "survival"],
+            "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } }
+        })
+    }
+
+    // (patch, what the document looks like afterwards)
+    fn check(cases: Vec<(Value, Value)>) {
+        for (patch, expected) in cases {
+            let mut doc = doc();
+            apply(&mut doc, &ops(patch.clone()), MAX_SIZE).unwrap();
+            assert_eq!(doc, expected, "{}", patch);
+        }
+    }
+
+    #[test]
+    fn add() {
+        check(vec![
+            (
+                json!([{ "op": "add", "path": "/class", "value": "Ranger" }]),
+                json!({ "name": "Vex", "class": "Ranger", "skills": ["stealth", "survival"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+            (
+                json!([{ "op": "add", "path": "/name", "value": "Vax" }]),
+                json!({ "name": "Vax", "skills": ["stealth", "survival"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+            (
+                json!([{ "op": "add", "path": "/skills/0", "value": "arcana" }]),
+                json!({ "name": "Vex", "skills": ["arcana", "stealth", "survival"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+            (
+                json!([{ "op": "add", "path": "/skills/2", "value": "arcana" }]),
+                json!({ "name": "Vex", "skills": ["stealth", "survival", "arcana"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+            (
+                json!([{ "op": "add", "path": "/skills/-", "value": "arcana" }]),
+                json!({ "name": "Vex", "skills": ["stealth", "survival", "arcana"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+            (
+                json!([{ "op": "add", "path": "", "value": [1] }]),
+                json!([1]),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn remove() {
+        check(vec![
+            (
+                json!([{ "op": "remove", "path": "/name" }]),
+                json!({ "skills": ["stealth", "survival"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+            (
+                json!([{ "op": "remove", "path": "/skills/0" }]),
+                json!({ "name": "Vex", "skills": ["survival"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn replace() {
+        check(vec![
+            (
+                json!([{ "op": "replace", "path": "/extra/speed/walk", "value": 35 }]),
+                json!({ "name": "Vex", "skills": ["stealth", "survival"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 35 } } }),
+            ),
+            (
+                json!([{ "op": "replace", "path": "/skills/1", "value": "arcana" }]),
+                json!({ "name": "Vex", "skills": ["stealth", "arcana"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+            (
+                json!([{ "op": "replace", "path": "/name", "value": null }]),
+                json!({ "name": null, "skills": ["stealth", "survival"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn move_and_copy() {
+        check(vec![
+            (
+                json!([{ "op": "move", "from": "/name", "path": "/extra/name" }]),
+                json!({ "skills": ["stealth", "survival"], "extra": { "a/b": 1, "m~n": 2, "name": "Vex", "speed": { "walk": 30 } } }),
+            ),
+            (
+                json!([{ "op": "move", "from": "/skills/0", "path": "/skills/-" }]),
+                json!({ "name": "Vex", "skills": ["survival", "stealth"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+            (
+                json!([{ "op": "copy", "from": "/skills/1", "path": "/skills/0" }]),
+                json!({ "name": "Vex", "skills": ["survival", "stealth", "survival"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_op() {
+        check(vec![(
+            json!([
+                { "op": "test", "path": "/name", "value": "Vex" },
+                { "op": "test", "path": "/extra/speed", "value": { "walk": 30 } },
+                { "op": "replace", "path": "/name", "value": "Vax" },
+            ]),
+            json!({ "name": "Vax", "skills": ["stealth", "survival"], "extra": { "a/b": 1, "m~n": 2, "speed": { "walk": 30 } } }),
+        )]);
+    }
+
+    #[test]
+    fn escaped_tokens() {
+        check(vec![
+            (
+                json!([{ "op": "replace", "path": "/extra/a~1b", "value": 10 }]),
+                json!({ "name": "Vex", "skills": ["stealth", "survival"], "extra": { "a/b": 10, "m~n": 2, "speed": { "walk": 30 } } }),
+            ),
+            (
+                json!([{ "op": "remove", "path": "/extra/m~0n" }]),
+                json!({ "name": "Vex", "skills": ["stealth", "survival"], "extra": { "a/b": 1, "speed": { "walk": 30 } } }),
+            ),
+            (
+                // ~01 is ~ followed by 1, not /
+                json!([{ "op": "add", "path": "/extra/~01", "value": 3 }]),
+                json!({ "name": "Vex", "skills": ["stealth", "survival"], "extra": { "a/b": 1, "m~n": 2, "~1": 3, "speed": { "walk": 30 } } }),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn failing_patch_changes_nothing() {
+        for patch in [
+            json!([
+                { "op": "replace", "path": "/name", "value": "Vax" },
+                { "op": "test", "path": "/name", "value": "Vex" },
+            ]),
+            json!([
+                { "op": "add", "path": "/skills/-", "value": "arcana" },
+                { "op": "remove", "path": "/nope" },
+            ]),
+        ] {
+            let mut doc = doc();
+            assert!(
+                apply(&mut doc, &ops(patch.clone()), MAX_SIZE).is_err(),
+                "{}",
+                patch
+            );
+            assert_eq!(doc, self::doc(), "{}", patch);
+        }
+    }
+
+    #[test]
+    fn bad_pointers() {
+        for patch in [
+            // no leading slash
+            json!([{ "op": "replace", "path": "name", "value": 1 }]),
+            json!([{ "op": "replace", "path": "/nope", "value": 1 }]),
+            json!([{ "op": "add", "path": "/nope/deeper", "value": 1 }]),
+            json!([{ "op": "add", "path": "/name/x", "value": 1 }]),
+            json!([{ "op": "remove", "path": "" }]),
+            json!([{ "op": "remove", "path": "/skills/2" }]),
+            json!([{ "op": "remove", "path": "/skills/-" }]),
+            json!([{ "op": "remove", "path": "/skills/01" }]),
+            json!([{ "op": "remove", "path": "/skills/-1" }]),
+            json!([{ "op": "add", "path": "/skills/3", "value": 1 }]),
+            json!([{ "op": "copy", "from": "/nope", "path": "/name" }]),
+            json!([{ "op": "move", "from": "/extra", "path": "/extra/speed/extra" }]),
+            json!([{ "op": "copy", "from": "/extra", "path": "/extra/copy" }]),
+            json!([{ "op": "copy", "from": "", "path": "/copy" }]),
+            json!([{ "op": "test", "path": "/nope", "value": null }]),
+        ] {
+            let mut doc = doc();
+            assert!(
+                apply(&mut doc, &ops(patch.clone()), MAX_SIZE).is_err(),
+                "{}",
+                patch
+            );
+            assert_eq!(doc, self::doc(), "{}", patch);
+        }
+    }
+
+    #[test]
+    fn array_indexes_are_only_digits() {
+        for token in ["+1", "-0", "1e2", "abc", "01", " 1"] {
+            for patch in [
+                json!([{ "op": "add", "path": format!("/skills/{}", token), "value": 1 }]),
+                json!([{ "op": "remove", "path": format!("/skills/{}", token) }]),
+            ] {
+                let mut doc = doc();
+                let err = apply(&mut doc, &ops(patch.clone()), MAX_SIZE).unwrap_err();
+                assert!(
+                    err.to_string().contains("valid array index"),
+                    "{}: {}",
+                    patch,
+                    err
+                );
+                assert_eq!(doc, self::doc(), "{}", patch);
+            }
+        }
+
+        let mut doc = doc();
+        let patch = json!([{ "op": "remove", "path": "/skills/2" }]);
+        let err = apply(&mut doc, &ops(patch), MAX_SIZE).unwrap_err();
+        assert!(err.to_string().contains("out of bounds"), "{}", err);
+    }
+
+    #[test]
+    fn stops_growing_past_max_size() {
+        let mut doc = doc();
+        doc["extra"] = json!({ "k0": "x".repeat(100) });
+        let before = doc.clone();
+        // every op doubles extra, so this passes the limit long before running all of them
+        let patch: Vec<Value> = (1..=100)
+            .map(|i| json!({ "op": "copy", "from": "/extra", "path": format!("/copy{}", i) }))
+            .collect();
+        let doubling: Vec<Value> = (1..=100)
+            .map(|i| {
+                json!({ "op": "copy", "from": format!("/extra/k{}", i - 1), "path": format!("/extra/k{}", i) })
+            })
+            .collect();
+        for patch in [patch, doubling] {
+            let err = apply(&mut doc, &ops(Value::Array(patch)), MAX_SIZE).unwrap_err();
+            assert!(err.to_string().contains("at most"), "{}", err);
+            assert_eq!(doc, before);
+        }
+    }
+}
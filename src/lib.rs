@@ -1,8 +1,3 @@
-use oauth2::basic::{
-    BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
-    BasicTokenResponse, BasicTokenType,
-};
-use oauth2::{Client, StandardRevocableToken};
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 use std::collections::HashMap;
@@ -10,17 +5,43 @@ use std::sync::{Arc, Mutex};
 
 pub mod api;
 pub mod auth;
+pub mod combat;
 pub mod config;
 pub mod db;
+pub mod dice;
+pub mod error;
+pub mod hub;
+pub mod identity;
+pub mod json_patch;
+pub mod migrate;
+pub mod permissions;
+pub mod protocol;
+pub mod rooms;
 pub mod ws;
 
+// Sent to the client after logging in, `token` is our own session token (not discord's)
 #[derive(Serialize, Deserialize)]
 pub struct UserSession {
-    access_token: String,
-    refresh_token: String,
+    token: String,
+    expires_at: chrono::NaiveDateTime,
     session: DiscordUser,
 }
 
+// Returned from /session, same as UserSession without the token
+#[derive(Serialize)]
+pub struct CurrentSession {
+    expires_at: chrono::NaiveDateTime,
+    session: DiscordUser,
+}
+
+// A login started on /ws-login, keyed by the oauth state (csrf token) in AppState.pending_logins
+pub struct PendingLogin {
+    pub addr: actix::Addr<ws::LoginActor>,
+    pub device: Option<String>,
+    pub pkce_verifier: Option<oauth2::PkceCodeVerifier>,
+    pub created_at: std::time::Instant,
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct DiscordUser {
     id: String,
@@ -32,16 +53,9 @@ pub struct DiscordUser {
 }
 
 pub struct AppState {
-    pub client: Client<
-        BasicErrorResponse,
-        BasicTokenResponse,
-        BasicTokenType,
-        BasicTokenIntrospectionResponse,
-        StandardRevocableToken,
-        BasicRevocationErrorResponse,
-    >,
-    pub connections: Arc<Mutex<HashMap<String, actix_ws::Session>>>,
-    pub sessions: Arc<Mutex<HashMap<String, DiscordUser>>>,
-    pub pending_logins: Arc<Mutex<HashMap<String, actix::Addr<ws::LoginActor>>>>,
+    pub provider: Box<dyn identity::IdentityProvider>,
+    // owns everyone connected to /ws, grouped by the campaign (and dnd session) they joined
+    pub hub: actix::Addr<hub::Hub>,
+    pub pending_logins: Arc<Mutex<HashMap<String, PendingLogin>>>,
     pub db_conn: Pool<Postgres>,
 }
@@ -1,49 +1,214 @@
-use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
-use dnd_thing_server::{auth, config, ws, AppState};
-use oauth2::basic::BasicClient;
-use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
+use actix::Actor;
+use actix_cors::Cors;
+use actix_web::{get, http, web, App, HttpResponse, HttpServer, Responder};
+use clap::{Parser, Subcommand};
+use dnd_thing_server::config::{Config, IdentityProviderKind};
+use dnd_thing_server::identity::{self, DevProvider, DiscordProvider, IdentityProvider};
+use dnd_thing_server::{api, auth, db, hub, migrate, ws, AppState};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
+#[derive(Parser)]
+#[command(about = "Backend for the D&D app")]
+struct Args {
+    /// Path to the config file, defaults to ./config.toml
+    #[arg(long, short, global = true)]
+    config: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server (the default when no command is given)
+    Serve,
+    /// Manage the database schema
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum MigrateAction {
+    /// List every migration and whether it's been applied
+    Status,
+    /// Apply all pending migrations
+    Run,
+    /// Revert the latest migration, or everything after --target
+    Revert {
+        /// Version to revert back to, this one stays applied
+        #[arg(long)]
+        target: Option<i64>,
+    },
+}
+
 #[get("/")]
 async fn hello() -> impl Responder {
     HttpResponse::Ok().body("Hello world!")
 }
 
+fn cors(config: &Config) -> Cors {
+    let cors = Cors::default()
+        .allowed_methods(vec!["GET", "POST", "PATCH", "PUT", "DELETE"])
+        .allowed_headers(vec![
+            http::header::AUTHORIZATION,
+            http::header::CONTENT_TYPE,
+        ])
+        .max_age(3600);
+
+    config
+        .server
+        .cors_origins
+        .iter()
+        .fold(cors, |cors, origin| match origin.as_str() {
+            "*" => cors.allow_any_origin(),
+            origin => cors.allowed_origin(origin),
+        })
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let client = BasicClient::new(
-        ClientId::new(config::config.global.discord_client.clone()),
-        Some(ClientSecret::new(
-            config::config.global.discord_secret.clone(),
-        )),
-        AuthUrl::new("https://discord.com/oauth2/authorize".to_string()).unwrap(),
-        Some(TokenUrl::new("https://discord.com/api/oauth2/token".to_string()).unwrap()),
-    )
-    .set_redirect_uri(RedirectUrl::new("http://localhost:8080/login".to_string()).unwrap());
-
-    let conn = sqlx::postgres::PgPool::connect(config::config.global.database_url.as_str())
-        .await
-        .unwrap();
+    let args = Args::parse();
+    let loaded = match args.command {
+        None | Some(Command::Serve) => Config::load_server(args.config.as_deref()),
+        Some(Command::Migrate { .. }) => Config::load(args.config.as_deref()),
+    };
+    let config = match loaded {
+        Ok(config) => config,
+        Err(err) => {
+            eprint!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    env_logger::Builder::new()
+        .parse_filters(&config.server.log_level)
+        .init();
+
+    let conn = match db::connect(&config).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::error!("Couldn't connect to the database: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    match args.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config, conn).await,
+        Command::Migrate { action } => {
+            if let Err(err) = run_migrate(action, &conn).await {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn run_migrate(action: MigrateAction, conn: &sqlx::PgPool) -> Result<(), String> {
+    match action {
+        MigrateAction::Status => {
+            let migrations = migrate::status(conn).await.map_err(|e| e.to_string())?;
+            println!(
+                "{:<16} {:<10} {:<11} Description",
+                "Version", "Status", "Reversible"
+            );
+            for m in migrations {
+                let status = match (m.applied, m.checksum_mismatch) {
+                    (true, true) => "modified",
+                    (true, false) => "applied",
+                    (false, _) => "pending",
+                };
+                let reversible = if m.reversible { "yes" } else { "no" };
+                println!(
+                    "{:<16} {:<10} {:<11} {}",
+                    m.version, status, reversible, m.description
+                );
+            }
+        }
+        MigrateAction::Run => {
+            migrate::run(conn).await.map_err(|e| e.to_string())?;
+            println!("Database is up to date");
+        }
+        MigrateAction::Revert { target } => {
+            let reverted = migrate::revert(conn, target).await?;
+            if reverted.is_empty() {
+                println!("Nothing to revert");
+            }
+            for version in reverted {
+                println!("Reverted {}", version);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn serve(config: Config, conn: sqlx::PgPool) -> std::io::Result<()> {
+    if config.database.migrate_on_startup {
+        if let Err(err) = migrate::run(&conn).await {
+            log::error!("Couldn't run database migrations: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let dev_provider = match config.global.identity_provider {
+        IdentityProviderKind::Discord => None,
+        IdentityProviderKind::Dev => {
+            log::warn!("Using the dev identity provider, don't run this in production");
+            Some(DevProvider::new(&config).expect("Invalid oauth url in the config"))
+        }
+    };
+    let provider: Box<dyn IdentityProvider> = match &dev_provider {
+        Some(dev) => Box::new(dev.clone()),
+        None => Box::new(DiscordProvider::new(&config).expect("Invalid oauth url in the config")),
+    };
+    // the dev consent page needs the provider to know where to send the browser back to
+    let dev_provider = dev_provider.map(web::Data::new);
 
     let app_state = web::Data::new(AppState {
-        client,
-        connections: Arc::new(Mutex::new(HashMap::new())),
-        sessions: Arc::new(Mutex::new(HashMap::new())),
+        provider,
+        hub: hub::Hub::default().start(),
         pending_logins: Arc::new(Mutex::new(HashMap::new())),
         db_conn: conn,
     });
 
-    HttpServer::new(move || {
+    actix_web::rt::spawn(auth::refresh_expiring_tokens(app_state.clone()));
+    actix_web::rt::spawn(ws::expire_pending_logins(app_state.clone()));
+
+    let bind = (config.server.bind_address.clone(), config.server.port);
+    let workers = config.server.workers;
+    let config = web::Data::new(config);
+    log::info!(
+        "Listening on {}:{} ({})",
+        bind.0,
+        bind.1,
+        config.server.public_url
+    );
+
+    let mut server = HttpServer::new(move || {
         App::new()
+            .wrap(cors(&config))
             .app_data(app_state.clone())
+            .app_data(config.clone())
             .service(hello)
             .service(auth::discord_token)
             .service(auth::session)
+            .service(auth::logout)
             .service(ws::ws_handler)
             .service(ws::ws_login)
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+            .service(web::scope("/api/v1").configure(api::config))
+            .configure(|cfg| {
+                if let Some(dev) = &dev_provider {
+                    cfg.app_data(dev.clone()).service(identity::dev::authorize);
+                }
+            })
+    });
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+
+    server.bind(bind)?.run().await
 }
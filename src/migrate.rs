@@ -0,0 +1,124 @@
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+
+// Everything in migrations/, built into the binary so deploys don't need the sqlx cli
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+pub async fn run(conn: &Pool<Postgres>) -> Result<(), MigrateError> {
+    MIGRATOR.run(conn).await
+}
+
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+    // applied but the file changed afterwards
+    pub checksum_mismatch: bool,
+    pub reversible: bool,
+}
+
+pub async fn status(conn: &Pool<Postgres>) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let mut c = conn.acquire().await?;
+    c.ensure_migrations_table().await?;
+    let applied: HashMap<i64, Vec<u8>> = c
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| (m.version, m.checksum.into_owned()))
+        .collect();
+
+    Ok(MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .map(|m| MigrationStatus {
+            version: m.version,
+            description: m.description.to_string(),
+            applied: applied.contains_key(&m.version),
+            checksum_mismatch: applied
+                .get(&m.version)
+                .is_some_and(|checksum| *checksum != *m.checksum),
+            reversible: m.migration_type.is_reversible(),
+        })
+        .collect())
+}
+
+// Reverts applied migrations newer than `target`, or just the latest one without a target.
+// Only migrations with a .down.sql can be reverted, so this stops at the first one that doesn't
+pub async fn revert(conn: &Pool<Postgres>, target: Option<i64>) -> Result<Vec<i64>, String> {
+    let migrations = status(conn).await.map_err(|e| e.to_string())?;
+    let applied: Vec<&MigrationStatus> = migrations.iter().filter(|m| m.applied).collect();
+
+    let target = match target {
+        Some(target) => target,
+        // everything after the second newest, so only the newest gets reverted
+        None => match applied.iter().rev().nth(1) {
+            Some(m) => m.version,
+            None => 0,
+        },
+    };
+
+    let to_revert: Vec<&&MigrationStatus> = applied
+        .iter()
+        .rev()
+        .take_while(|m| m.version > target)
+        .collect();
+    if let Some(m) = to_revert.iter().find(|m| !m.reversible) {
+        return Err(format!(
+            "Migration {} ({}) has no down migration and can't be reverted",
+            m.version, m.description
+        ));
+    }
+
+    MIGRATOR
+        .undo(conn, target)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(to_revert.iter().map(|m| m.version).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    // Runs the up migrations from `from` (inclusive) to `to` (exclusive) without recording them
+    async fn run_range(conn: &Pool<Postgres>, from: i64, to: i64) {
+        for m in MIGRATOR
+            .iter()
+            .filter(|m| m.migration_type.is_up_migration() && (from..to).contains(&m.version))
+        {
+            sqlx::raw_sql(&m.sql).execute(conn).await.unwrap();
+        }
+    }
+
+    // Before the campaign_cascade migration, i.e. the schema the first invites were made with
+    const BASELINE: i64 = 20250104190512;
+
+    #[sqlx::test(migrations = false)]
+    async fn baseline_invites_stay_joinable(conn: Pool<Postgres>) {
+        run_range(&conn, 0, BASELINE).await;
+        sqlx::raw_sql(
+            "
+                INSERT INTO users (username, access_token, refresh_token) VALUES ('dm', '', ''), ('player', '', '');
+                INSERT INTO campaign (user_id, name) VALUES (1, 'Old campaign');
+                INSERT INTO campaign_players (campaign_id, player_id, role) VALUES (1, 1, 'dm');
+                INSERT INTO campaign_invites (campaign_id, invite) VALUES (1, 'oldinvite');
+            ",
+        )
+        .execute(&conn)
+        .await
+        .unwrap();
+        run_range(&conn, BASELINE, i64::MAX).await;
+
+        let max_uses =
+            sqlx::query_scalar!("SELECT max_uses FROM campaign_invites WHERE invite = 'oldinvite'")
+                .fetch_one(&conn)
+                .await
+                .unwrap();
+        assert_eq!(max_uses, None);
+        let joined = db::join_dnd_campaign(&conn, 2, "oldinvite").await.unwrap();
+        assert_eq!(joined.campaign_id, 1);
+    }
+}
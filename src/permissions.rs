@@ -0,0 +1,194 @@
+// What each campaign role is allowed to do. Every api and /ws operation on a campaign goes through
+// `require` (or `CampaignRole::can` when it already has the role) instead of checking roles itself
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{self, CampaignRole},
+    error::ApiError,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permission {
+    // see the campaign, its sessions, members, chat, characters and encounters
+    View,
+    SendMessages,
+    RollDice,
+    // create characters and edit your own
+    PlayCharacters,
+    // edit everyone's characters, see everything whispered to the DMs and run encounters
+    Dm,
+    ManageSessions,
+    EditCampaign,
+    // change roles, kick people and manage invites, see `can_change_member` for whose
+    ManageMembers,
+    DeleteCampaign,
+}
+
+impl CampaignRole {
+    pub fn can(self, permission: Permission) -> bool {
+        use CampaignRole::*;
+        use Permission::*;
+
+        match permission {
+            View | SendMessages => true,
+            RollDice | PlayCharacters => self != Spectator,
+            Dm | ManageSessions | EditCampaign | ManageMembers => matches!(self, Owner | CoDm),
+            DeleteCampaign => self == Owner,
+        }
+    }
+
+    pub fn is_dm(self) -> bool {
+        self.can(Permission::Dm)
+    }
+}
+
+// Whether `actor` can give a member with the role `target` the role `new`, or kick them with
+// None. Co-DMs only manage players and spectators, only the owner can make or unmake co-DMs, and
+// nobody can change the owner (that's what `dnd-admin campaigns transfer` is for)
+pub fn can_change_member(
+    actor: CampaignRole,
+    target: CampaignRole,
+    new: Option<CampaignRole>,
+) -> bool {
+    use CampaignRole::*;
+
+    if !actor.can(Permission::ManageMembers) || target == Owner || new == Some(Owner) {
+        return false;
+    }
+    match actor {
+        Owner => true,
+        _ => target != CoDm && new != Some(CoDm),
+    }
+}
+
+// Whether `actor` can manage invites that make whoever joins `role`, the same as giving someone
+// that role
+pub fn can_invite_as(actor: CampaignRole, role: CampaignRole) -> bool {
+    can_change_member(actor, CampaignRole::Player, Some(role))
+}
+
+// The user's role in the campaign if it allows `permission`. Someone that isn't in the campaign
+// gets NotFound so they can't tell whether it exists
+pub async fn require(
+    conn: &Pool<Postgres>,
+    user_id: i32,
+    campaign_id: i32,
+    permission: Permission,
+) -> Result<CampaignRole, ApiError> {
+    let role = db::get_campaign_role(conn, user_id, campaign_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(String::from("Campaign not found")))?;
+    if !role.can(permission) {
+        return Err(forbidden(permission));
+    }
+    Ok(role)
+}
+
+pub fn forbidden(permission: Permission) -> ApiError {
+    let message = match permission {
+        Permission::View => "You can't see this campaign",
+        Permission::SendMessages => "You can't send messages in this campaign",
+        Permission::RollDice => "Spectators can't roll dice",
+        Permission::PlayCharacters => "Spectators can't play characters",
+        Permission::Dm | Permission::ManageSessions | Permission::EditCampaign => {
+            "Only the DMs can do that"
+        }
+        Permission::ManageMembers => "Only the DMs can manage members",
+        Permission::DeleteCampaign => "Only the owner can delete the campaign",
+    };
+    ApiError::Forbidden(String::from(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CampaignRole::*;
+
+    const ROLES: [CampaignRole; 4] = [Owner, CoDm, Player, Spectator];
+
+    #[test]
+    fn role_permissions() {
+        use Permission::*;
+
+        // (permission, the roles that have it)
+        let cases = [
+            (View, vec![Owner, CoDm, Player, Spectator]),
+            (SendMessages, vec![Owner, CoDm, Player, Spectator]),
+            (RollDice, vec![Owner, CoDm, Player]),
+            (PlayCharacters, vec![Owner, CoDm, Player]),
+            (Dm, vec![Owner, CoDm]),
+            (ManageSessions, vec![Owner, CoDm]),
+            (EditCampaign, vec![Owner, CoDm]),
+            (ManageMembers, vec![Owner, CoDm]),
+            (DeleteCampaign, vec![Owner]),
+        ];
+        for (permission, allowed) in cases {
+            for role in ROLES {
+                assert_eq!(
+                    role.can(permission),
+                    allowed.contains(&role),
+                    "{:?} {:?}",
+                    role,
+                    permission
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn changing_members() {
+        // (actor, target, new role or None for kicking, allowed)
+        let cases = [
+            (Owner, CoDm, Some(Player), true),
+            (Owner, Player, Some(CoDm), true),
+            (Owner, Player, Some(Spectator), true),
+            (Owner, Spectator, None, true),
+            (Owner, CoDm, None, true),
+            (Owner, Player, Some(Owner), false),
+            (CoDm, Player, Some(Spectator), true),
+            (CoDm, Spectator, Some(Player), true),
+            (CoDm, Player, None, true),
+            (CoDm, Owner, Some(Player), false),
+            (CoDm, Owner, None, false),
+            (CoDm, CoDm, Some(Player), false),
+            (CoDm, CoDm, None, false),
+            (CoDm, Player, Some(CoDm), false),
+            (CoDm, Player, Some(Owner), false),
+            (Player, Spectator, Some(Player), false),
+            (Player, Player, None, false),
+            (Spectator, Player, None, false),
+        ];
+        for (actor, target, new, allowed) in cases {
+            assert_eq!(
+                can_change_member(actor, target, new),
+                allowed,
+                "{:?} changing {:?} to {:?}",
+                actor,
+                target,
+                new
+            );
+        }
+    }
+
+    #[test]
+    fn inviting() {
+        // (actor, the roles it can invite as)
+        let cases = [
+            (Owner, vec![CoDm, Player, Spectator]),
+            (CoDm, vec![Player, Spectator]),
+            (Player, vec![]),
+            (Spectator, vec![]),
+        ];
+        for (actor, allowed) in cases {
+            for role in ROLES {
+                assert_eq!(
+                    can_invite_as(actor, role),
+                    allowed.contains(&role),
+                    "{:?} inviting as {:?}",
+                    actor,
+                    role
+                );
+            }
+        }
+    }
+}
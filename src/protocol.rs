@@ -0,0 +1,341 @@
+// Wire format for /ws. Every frame is a json object like
+//
+// { "type": "SendMessage", "request_id": "abc", "data": { "content": "hi" } }
+//
+// `request_id` is optional and picked by the client, the server copies it onto the Ack or Error
+// it answers with so the client can match them up. Frames the server sends on its own (chat,
+// presence) don't have one. The first frame a client sends has to be Hello (or Resume), anything
+// before that gets a handshake_required error.
+//
+// Everything broadcast to a room has a `seq` that goes up by one per broadcast in that room. When
+// the socket drops the client can reconnect and send Resume with the resume_token from Welcome
+// and the last seq it saw, and gets everything it missed replayed.
+//
+// Messages and rolls can be whispered or kept to the DMs, see db::Visibility. Whoever isn't
+// allowed to see one never gets it, so there can be gaps in the seqs they see.
+use crate::{
+    db::{
+        CampaignRole, CharacterView, ChatMessage, DiceRoll, Effect, Encounter, MessagePage,
+        Visibility,
+    },
+    json_patch::PatchOp,
+    rooms::Room,
+    DiscordUser,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Bump when a change would break existing clients, and keep the old version in
+// SUPPORTED_VERSIONS for as long as it still works
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const SUPPORTED_VERSIONS: [u32; 1] = [1];
+
+#[derive(Deserialize, Debug)]
+pub struct ClientFrame {
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum ClientMessage {
+    // versions the client can speak, the server picks the newest one it also supports
+    Hello {
+        versions: Vec<u32>,
+    },
+    // instead of Hello when reconnecting, picks the old connection back up
+    Resume {
+        versions: Vec<u32>,
+        resume_token: String,
+        last_seq: u64,
+    },
+    // `to` is the discord ids to whisper to, only used with the whisper visibility
+    SendMessage {
+        content: String,
+        #[serde(default)]
+        visibility: Visibility,
+        #[serde(default)]
+        to: Vec<String>,
+    },
+    // rolls on the server, see dice.rs for the notation. The label is shown with the result,
+    // e.g. "Stealth"
+    Roll {
+        notation: String,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        visibility: Visibility,
+        #[serde(default)]
+        to: Vec<String>,
+    },
+    // JSON Patch ops (see json_patch.rs) against the character's fields, the same ones as
+    // PATCH /characters/{id}. Fails with a conflict error if the character isn't at `version`
+    // anymore, the client should apply the CharacterUpdated it missed and try again
+    PatchCharacter {
+        character_id: i32,
+        version: i32,
+        patch: Vec<PatchOp>,
+    },
+    // a page of the room's chat history, same options as GET /campaigns/{id}/messages
+    FetchHistory {
+        #[serde(default)]
+        before: Option<i64>,
+        #[serde(default)]
+        after: Option<i64>,
+        #[serde(default)]
+        limit: Option<i64>,
+    },
+    Ping,
+    Disconnect {
+        reason: Option<String>,
+    },
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServerFrame {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum ServerMessage {
+    // answer to Hello and Resume
+    Welcome {
+        version: u32,
+        connection_id: u64,
+        user: DiscordUser,
+        room: Room,
+        // for Resume after a reconnect, a new one is handed out every time
+        resume_token: String,
+        // seq of the room's latest broadcast
+        seq: u64,
+        resumed: bool,
+    },
+    ConnectedUsers(HashMap<String, DiscordUser>),
+    // a new chat message, the sender's own copy has the request_id of its SendMessage
+    Message(ChatMessage),
+    // a dice roll with every die in it, the roller's own copy has the request_id of its Roll
+    Roll(DiceRoll),
+    // sent to every room of the campaign, whoever can edit the character gets the whole sheet and
+    // everyone else the summary. The sender of a PatchCharacter gets its request_id on the sheet
+    CharacterCreated(CharacterView),
+    CharacterUpdated(CharacterView),
+    CharacterDeleted {
+        id: i32,
+    },
+    // the combat tracker, sent to every room of the campaign with the whole encounter after every
+    // change
+    EncounterStarted(Encounter),
+    EncounterUpdated(Encounter),
+    EncounterEnded(Encounter),
+    // effects that ran out when a turn started, they're already gone from the encounter
+    EffectsExpired {
+        encounter_id: i32,
+        effects: Vec<Effect>,
+    },
+    // at the start of a turn, what's on whoever's turn it is
+    EffectReminder {
+        encounter_id: i32,
+        round: i32,
+        combatant_id: i32,
+        effects: Vec<Effect>,
+    },
+    // the effects on a character outside of combat changed, also when it joins or leaves an
+    // encounter since its effects move to its combatant and back. Sent to every room of the campaign
+    CharacterEffects {
+        character_id: i32,
+        effects: Vec<Effect>,
+    },
+    // sent to every room of the campaign. Whoever got removed also has their sockets closed
+    MemberRoleChanged {
+        user: String,
+        role: CampaignRole,
+    },
+    MemberRemoved {
+        user: String,
+    },
+    // answer to FetchHistory, also sent once right after Welcome with the latest messages
+    History(MessagePage),
+    // the request went through, only sent when it had a request_id and no other answer
+    Ack,
+    Pong,
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // not json, unknown type or missing fields
+    MalformedMessage,
+    // none of the versions in Hello are supported, the socket gets closed after this
+    UnsupportedVersion,
+    HandshakeRequired,
+    AlreadyHandshaken,
+    InvalidRequest,
+    // the resume token is unknown or expired, send Hello to start over
+    ResumeFailed,
+    // resumed, but some events were too old to replay so history should be refetched
+    ResumeGap,
+    Forbidden,
+    NotFound,
+    // someone else changed it first, e.g. a PatchCharacter with an old version
+    Conflict,
+    // something went wrong on the server, e.g. the database is down
+    Internal,
+}
+
+impl ServerFrame {
+    pub fn new(message: ServerMessage) -> Self {
+        ServerFrame {
+            request_id: None,
+            seq: None,
+            message,
+        }
+    }
+
+    // a broadcast with its seq in the room
+    pub fn event(seq: u64, message: ServerMessage) -> Self {
+        ServerFrame {
+            request_id: None,
+            seq: Some(seq),
+            message,
+        }
+    }
+
+    pub fn reply(request_id: Option<String>, message: ServerMessage) -> Self {
+        ServerFrame {
+            request_id,
+            seq: None,
+            message,
+        }
+    }
+
+    pub fn error(request_id: Option<String>, code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerFrame {
+            request_id,
+            seq: None,
+            message: ServerMessage::Error {
+                code,
+                message: message.into(),
+            },
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+// Picks the version to talk in, None if there's nothing in common
+pub fn negotiate_version(versions: &[u32]) -> Option<u32> {
+    versions
+        .iter()
+        .copied()
+        .filter(|v| SUPPORTED_VERSIONS.contains(v))
+        .max()
+}
+
+// Parses a frame from the client. When that fails the request_id is still pulled out if the text
+// was json at all, so the error can be matched to the request
+pub fn parse_client_frame(text: &str) -> Result<ClientFrame, Box<ServerFrame>> {
+    serde_json::from_str::<ClientFrame>(text).map_err(|err| {
+        let request_id = serde_json::from_str::<serde_json::Value>(text)
+            .ok()
+            .and_then(|v| v.get("request_id")?.as_str().map(String::from));
+        Box::new(ServerFrame::error(
+            request_id,
+            ErrorCode::MalformedMessage,
+            err.to_string(),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_the_newest_common_version() {
+        for (versions, expected) in [
+            (vec![1], Some(1)),
+            (vec![1, 2], Some(1)),
+            (vec![2, 1, 0], Some(1)),
+            (vec![0], None),
+            (vec![2, 3], None),
+            (vec![], None),
+        ] {
+            assert_eq!(negotiate_version(&versions), expected, "{:?}", versions);
+        }
+    }
+
+    #[test]
+    fn parses_hello() {
+        for (text, versions) in [
+            (
+                r#"{ "type": "Hello", "data": { "versions": [1] } }"#,
+                vec![1],
+            ),
+            (r#"{ "type": "Hello", "data": { "versions": [] } }"#, vec![]),
+        ] {
+            let frame = parse_client_frame(text).unwrap();
+            assert!(
+                matches!(&frame.message, ClientMessage::Hello { versions: v } if *v == versions),
+                "{}",
+                text
+            );
+        }
+
+        let frame = parse_client_frame(r#"{ "type": "Ping", "request_id": "abc" }"#).unwrap();
+        assert_eq!(frame.request_id.as_deref(), Some("abc"));
+        assert!(matches!(frame.message, ClientMessage::Ping));
+    }
+
+    #[test]
+    fn malformed_frames() {
+        // (text, the request_id the error should keep)
+        for (text, request_id) in [
+            ("", None),
+            ("not json", None),
+            ("[1, 2]", None),
+            ("{}", None),
+            (r#"{ "type": "Nope" }"#, None),
+            (r#"{ "type": "Hello", "data": {} }"#, None),
+            (r#"{ "type": "Hello", "request_id": "a" }"#, Some("a")),
+            (
+                r#"{ "type": "Hello", "request_id": "b", "data": { "versions": "1" } }"#,
+                Some("b"),
+            ),
+            (
+                r#"{ "type": "Hello", "request_id": "c", "data": { "versions": [-1] } }"#,
+                Some("c"),
+            ),
+            (r#"{ "type": "SendMessage", "request_id": "d" }"#, Some("d")),
+            // not a string, so there's nothing to match it up with
+            (r#"{ "type": "Nope", "request_id": 5 }"#, None),
+        ] {
+            let frame = parse_client_frame(text).unwrap_err();
+            assert_eq!(frame.request_id.as_deref(), request_id, "{}", text);
+            assert!(
+                matches!(
+                    frame.message,
+                    ServerMessage::Error {
+                        code: ErrorCode::MalformedMessage,
+                        ..
+                    }
+                ),
+                "{}",
+                text
+            );
+        }
+    }
+}
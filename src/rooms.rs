@@ -0,0 +1,416 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hub::Outbox,
+    protocol::{ServerFrame, ServerMessage},
+    DiscordUser,
+};
+
+// A campaign, or one dnd session inside it. Chat and presence only go to people in the same room,
+// so someone in a session room doesn't see the campaign wide room and the other way around
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Room {
+    pub campaign_id: i32,
+    pub session_id: Option<i32>,
+}
+
+// Every connection gets its own id, so one user can be connected from several devices/tabs at
+// once. A resumed connection keeps its id even though it's on a new socket
+pub type ConnectionId = u64;
+
+// How many broadcasts each room keeps around for Resume
+const REPLAY_BUFFER: usize = 256;
+// How long a dropped connection can be resumed before the user counts as gone
+pub const RESUME_WINDOW: Duration = Duration::from_secs(60);
+
+struct RoomUser {
+    user: DiscordUser,
+    connections: HashSet<ConnectionId>,
+}
+
+struct RoomConnection {
+    // discord id
+    user_id: String,
+    // which socket currently owns the connection, so an old socket closing after a resume
+    // doesn't take the connection with it
+    socket: u64,
+    // None while the socket is gone and the connection is waiting to be resumed
+    outbox: Option<Outbox>,
+    resume_token: String,
+    detached_at: Option<Instant>,
+}
+
+// Who's connected to a room. A user stays in `users` until their last connection closes (or a
+// dropped one isn't resumed in time), so flaky connections don't make them flicker in and out
+#[derive(Default)]
+pub struct RoomMembers {
+    connections: HashMap<ConnectionId, RoomConnection>,
+    // keyed by discord user id
+    users: HashMap<String, RoomUser>,
+    // seq of the latest broadcast
+    seq: u64,
+    recent: VecDeque<(u64, ServerMessage, SeenBy)>,
+}
+
+// Who a broadcast goes to. Everyone else doesn't get it at all, also not when resuming, so they
+// just see a gap in the seqs
+#[derive(Clone, Debug)]
+pub enum SeenBy {
+    Everyone,
+    // discord ids
+    Users(HashSet<String>),
+    // everyone but these discord ids
+    Except(HashSet<String>),
+}
+
+impl SeenBy {
+    pub fn includes(&self, user_id: &str) -> bool {
+        match self {
+            SeenBy::Everyone => true,
+            SeenBy::Users(users) => users.contains(user_id),
+            SeenBy::Except(users) => !users.contains(user_id),
+        }
+    }
+}
+
+// A connection a broadcast has to go to
+pub struct Target {
+    pub id: ConnectionId,
+    pub socket: u64,
+    pub outbox: Outbox,
+}
+
+// What's left to do after claiming a connection with its resume token
+pub struct Resumed {
+    pub id: ConnectionId,
+    pub resume_token: String,
+    pub seq: u64,
+    // the socket the connection was on, if the server hadn't noticed it dropping yet
+    pub old_outbox: Option<Outbox>,
+}
+
+fn resume_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+impl RoomMembers {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    // Adds a new connection, returns its resume token and whether it's the user's first
+    // connection to the room
+    pub fn join(
+        &mut self,
+        id: ConnectionId,
+        socket: u64,
+        outbox: Outbox,
+        user: &DiscordUser,
+    ) -> (String, bool) {
+        let token = resume_token();
+        self.connections.insert(
+            id,
+            RoomConnection {
+                user_id: user.id.clone(),
+                socket,
+                outbox: Some(outbox),
+                resume_token: token.clone(),
+                detached_at: None,
+            },
+        );
+
+        let room_user = self
+            .users
+            .entry(user.id.clone())
+            .or_insert_with(|| RoomUser {
+                user: user.clone(),
+                connections: HashSet::new(),
+            });
+        room_user.connections.insert(id);
+        (token, room_user.connections.len() == 1)
+    }
+
+    // Removes the connection if `socket` still owns it, returns true if that was the user's last
+    // connection to the room
+    pub fn leave(&mut self, id: ConnectionId, socket: u64) -> bool {
+        let user_id = match self.connections.get(&id) {
+            Some(conn) if conn.socket == socket => conn.user_id.clone(),
+            _ => return false,
+        };
+        self.connections.remove(&id);
+
+        let Some(room_user) = self.users.get_mut(&user_id) else {
+            return false;
+        };
+        room_user.connections.remove(&id);
+        if room_user.connections.is_empty() {
+            self.users.remove(&user_id);
+            return true;
+        }
+        false
+    }
+
+    // The socket dropped without closing (or was too slow), keep the connection around so it can
+    // be resumed
+    pub fn detach(&mut self, id: ConnectionId, socket: u64) {
+        if let Some(conn) = self.connections.get_mut(&id) {
+            if conn.socket == socket {
+                conn.outbox = None;
+                conn.detached_at = Some(Instant::now());
+            }
+        }
+    }
+
+    // Hands the connection with this resume token over to a new socket
+    pub fn claim(
+        &mut self,
+        token: &str,
+        user_id: &str,
+        socket: u64,
+        outbox: Outbox,
+    ) -> Option<Resumed> {
+        let (id, conn) = self
+            .connections
+            .iter_mut()
+            .find(|(_, conn)| conn.resume_token == token && conn.user_id == user_id)?;
+
+        conn.socket = socket;
+        conn.resume_token = resume_token();
+        conn.detached_at = None;
+        Some(Resumed {
+            id: *id,
+            resume_token: conn.resume_token.clone(),
+            seq: self.seq,
+            old_outbox: conn.outbox.replace(outbox),
+        })
+    }
+
+    // Frames for everything broadcast to `user_id` after `seq` that's still buffered
+    pub fn events_after(&self, seq: u64, user_id: &str) -> Vec<String> {
+        self.recent
+            .iter()
+            .filter(|(s, _, seen_by)| *s > seq && seen_by.includes(user_id))
+            .map(|(s, message, _)| ServerFrame::event(*s, message.clone()).to_text())
+            .collect()
+    }
+
+    // Whether everything after `seq` is still in the buffer
+    pub fn can_replay_from(&self, seq: u64) -> bool {
+        match self.recent.front() {
+            Some((oldest, _, _)) => *oldest <= seq + 1,
+            None => seq >= self.seq,
+        }
+    }
+
+    // Connections that dropped and weren't resumed in time, as (id, socket)
+    pub fn expired(&self) -> Vec<(ConnectionId, u64)> {
+        self.connections
+            .iter()
+            .filter(|(_, conn)| {
+                conn.detached_at
+                    .is_some_and(|detached_at| detached_at.elapsed() > RESUME_WINDOW)
+            })
+            .map(|(id, conn)| (*id, conn.socket))
+            .collect()
+    }
+
+    // Every connection the user has to the room, as (id, socket, outbox)
+    pub fn connections_of(&self, user_id: &str) -> Vec<(ConnectionId, u64, Option<Outbox>)> {
+        self.connections
+            .iter()
+            .filter(|(_, conn)| conn.user_id == user_id)
+            .map(|(id, conn)| (*id, conn.socket, conn.outbox.clone()))
+            .collect()
+    }
+
+    pub fn presence(&self) -> ServerMessage {
+        ServerMessage::ConnectedUsers(
+            self.users
+                .iter()
+                .map(|(id, room_user)| (id.clone(), room_user.user.clone()))
+                .collect(),
+        )
+    }
+
+    // Gives the message the room's next seq and keeps it for Resume. Returns the seq, the frame
+    // and every connection it should go to
+    pub fn publish(
+        &mut self,
+        message: ServerMessage,
+        seen_by: SeenBy,
+    ) -> (u64, String, Vec<Target>) {
+        self.seq += 1;
+        let text = ServerFrame::event(self.seq, message.clone()).to_text();
+
+        let targets = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| seen_by.includes(&conn.user_id))
+            .filter_map(|(id, conn)| {
+                Some(Target {
+                    id: *id,
+                    socket: conn.socket,
+                    outbox: conn.outbox.clone()?,
+                })
+            })
+            .collect();
+
+        self.recent.push_back((self.seq, message, seen_by));
+        if self.recent.len() > REPLAY_BUFFER {
+            self.recent.pop_front();
+        }
+        (self.seq, text, targets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str) -> DiscordUser {
+        DiscordUser {
+            id: id.to_string(),
+            username: id.to_string(),
+            discriminator: String::from("0"),
+            global_name: None,
+            avatar: None,
+            accent_color: None,
+        }
+    }
+
+    fn outbox() -> Outbox {
+        Outbox::channel().0
+    }
+
+    fn seqs(frames: &[String]) -> Vec<u64> {
+        frames
+            .iter()
+            .map(|frame| {
+                let frame: serde_json::Value = serde_json::from_str(frame).unwrap();
+                frame["seq"].as_u64().unwrap()
+            })
+            .collect()
+    }
+
+    fn publish(members: &mut RoomMembers, count: usize, seen_by: SeenBy) {
+        for _ in 0..count {
+            members.publish(ServerMessage::Pong, seen_by.clone());
+        }
+    }
+
+    #[test]
+    fn replays_everything_after_the_last_seq() {
+        let mut members = RoomMembers::default();
+        assert!(members.can_replay_from(0));
+        members.join(1, 1, outbox(), &user("a"));
+
+        publish(&mut members, 5, SeenBy::Everyone);
+        assert_eq!(members.seq(), 5);
+        assert!(members.can_replay_from(2));
+        assert_eq!(seqs(&members.events_after(2, "a")), vec![3, 4, 5]);
+        assert!(members.events_after(5, "a").is_empty());
+    }
+
+    #[test]
+    fn only_replays_what_the_user_could_see() {
+        let mut members = RoomMembers::default();
+        publish(&mut members, 1, SeenBy::Everyone);
+        publish(
+            &mut members,
+            1,
+            SeenBy::Users(HashSet::from([String::from("b")])),
+        );
+        publish(
+            &mut members,
+            1,
+            SeenBy::Except(HashSet::from([String::from("b")])),
+        );
+        publish(&mut members, 1, SeenBy::Everyone);
+
+        // the gaps are where the other user's events were
+        assert_eq!(seqs(&members.events_after(0, "a")), vec![1, 3, 4]);
+        assert_eq!(seqs(&members.events_after(0, "b")), vec![1, 2, 4]);
+        assert!(members.can_replay_from(0));
+    }
+
+    #[test]
+    fn resume_window_is_the_buffer_size() {
+        let mut members = RoomMembers::default();
+        publish(&mut members, REPLAY_BUFFER + 44, SeenBy::Everyone);
+        let seq = members.seq();
+
+        // the oldest buffered event is seq 45, so a client that saw 44 is the furthest back that
+        // can still catch up
+        assert!(members.can_replay_from(44));
+        assert!(!members.can_replay_from(43));
+        assert!(!members.can_replay_from(0));
+        assert!(members.can_replay_from(seq));
+        let replayed = seqs(&members.events_after(44, "a"));
+        assert_eq!(replayed.len(), REPLAY_BUFFER);
+        assert_eq!(replayed.first(), Some(&45));
+        assert_eq!(replayed.last(), Some(&seq));
+    }
+
+    #[test]
+    fn claiming_moves_the_connection_to_the_new_socket() {
+        let mut members = RoomMembers::default();
+        let (token, first) = members.join(1, 10, outbox(), &user("a"));
+        assert!(first);
+        publish(&mut members, 3, SeenBy::Everyone);
+
+        // someone else's token, or a made up one
+        assert!(members.claim(&token, "b", 11, outbox()).is_none());
+        assert!(members.claim("nope", "a", 11, outbox()).is_none());
+
+        // the server hadn't noticed the old socket dropping yet, so it gets handed back to close
+        let resumed = members.claim(&token, "a", 11, outbox()).unwrap();
+        assert_eq!(resumed.id, 1);
+        assert_eq!(resumed.seq, 3);
+        assert!(resumed.old_outbox.is_some());
+        assert_ne!(resumed.resume_token, token);
+        // tokens only work once
+        assert!(members.claim(&token, "a", 12, outbox()).is_none());
+
+        // the old socket closing afterwards doesn't take the connection with it
+        assert!(!members.leave(1, 10));
+        assert_eq!(members.connections_of("a").len(), 1);
+        assert!(members.leave(1, 11));
+        assert!(members.is_empty());
+    }
+
+    #[test]
+    fn detached_connections_wait_to_be_resumed() {
+        let mut members = RoomMembers::default();
+        let (token, _) = members.join(1, 10, outbox(), &user("a"));
+        members.join(2, 20, outbox(), &user("b"));
+
+        members.detach(1, 10);
+        // no outbox to send to while it's detached
+        let (_, _, targets) = members.publish(ServerMessage::Pong, SeenBy::Everyone);
+        assert_eq!(targets.iter().map(|t| t.id).collect::<Vec<_>>(), vec![2]);
+        assert!(members.expired().is_empty());
+
+        let resumed = members.claim(&token, "a", 11, outbox()).unwrap();
+        assert!(resumed.old_outbox.is_none());
+        assert_eq!(seqs(&members.events_after(0, "a")), vec![1]);
+
+        members.detach(1, 11);
+        members.connections.get_mut(&1).unwrap().detached_at =
+            Some(Instant::now() - RESUME_WINDOW - Duration::from_secs(1));
+        assert_eq!(members.expired(), vec![(1, 11)]);
+    }
+}
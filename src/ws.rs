@@ -1,32 +1,34 @@
 use std::{
-    collections::HashMap,
-    io::{Error, ErrorKind},
+    collections::HashSet,
+    io::Error,
+    sync::atomic::{AtomicU64, Ordering},
     time::{Duration, Instant},
 };
 
-use crate::{auth::get_discord_user, AppState, DiscordUser};
+use crate::{
+    api::{
+        characters,
+        messages::{history_cursor, DEFAULT_HISTORY_LIMIT},
+    },
+    auth::AuthenticatedUser,
+    db::{self, Audience, HistoryCursor, Visibility},
+    dice::{self, RollResult},
+    error::ApiError,
+    hub::{self, Outbox},
+    permissions::{self, Permission},
+    protocol::{self, ClientMessage, ErrorCode, ServerFrame, ServerMessage, SUPPORTED_VERSIONS},
+    rooms::{ConnectionId, Room, SeenBy},
+    AppState, DiscordUser, PendingLogin,
+};
 use actix::{Actor, ActorContext};
 use actix_web::get;
 use actix_ws::{AggregatedMessage, CloseReason, Session};
 use futures_util::{future, StreamExt as _};
-use oauth2::{CsrfToken, Scope};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use tokio::{pin, time::interval};
 
-#[derive(Deserialize, Serialize, Clone)]
-struct ChatMessage {
-    author: String,
-    content: String,
-}
-
-#[derive(Deserialize, Serialize, Clone)]
-#[serde(tag = "type", content = "data")]
-enum WebsocketMessage {
-    Session(DiscordUser),
-    ConnectedUsers(HashMap<String, DiscordUser>),
-    Message(ChatMessage),
-    Disconnect(String),
-}
+// Used for both connection ids and socket ids
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
 
 // Actor information for login websocket
 pub struct LoginActor {
@@ -58,7 +60,7 @@ impl actix::Handler<LoginPayload> for LoginActor {
             let _ = session
                 .text(serde_json::to_string(&msg.payload).unwrap())
                 .await
-                .map_err(|_| Error::new(ErrorKind::Other, "Failed to send"));
+                .map_err(|_| Error::other("Failed to send"));
 
             let _ = session
                 .close(Some(CloseReason {
@@ -74,145 +76,746 @@ impl actix::Handler<LoginPayload> for LoginActor {
     }
 }
 
+// Closes the login socket without logging in, e.g. when the login timed out or failed
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct CloseLogin {
+    pub reason: String,
+}
+
+impl actix::Handler<CloseLogin> for LoginActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseLogin, ctx: &mut actix::Context<Self>) -> Self::Result {
+        let session: Session = self.session.clone();
+        actix_web::rt::spawn(async move {
+            let _ = session
+                .close(Some(CloseReason {
+                    code: actix_ws::CloseCode::Normal,
+                    description: Some(msg.reason),
+                }))
+                .await;
+        });
+
+        ctx.stop();
+    }
+}
+
+// How long the user has to finish logging in through the browser
+const LOGIN_TTL: Duration = Duration::from_secs(5 * 60);
+const LOGIN_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+// Login sockets are unauthenticated, so don't let them pile up forever
+const MAX_PENDING_LOGINS: usize = 256;
+
+// Background task started from main, closes logins that were never finished
+pub async fn expire_pending_logins(data: actix_web::web::Data<AppState>) {
+    let mut interval = interval(LOGIN_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let expired: Vec<PendingLogin> = {
+            let mut pending_logins = data.pending_logins.lock().unwrap();
+            let states: Vec<String> = pending_logins
+                .iter()
+                .filter(|(_, login)| login.created_at.elapsed() > LOGIN_TTL)
+                .map(|(state, _)| state.clone())
+                .collect();
+
+            states
+                .iter()
+                .filter_map(|state| pending_logins.remove(state))
+                .collect()
+        };
+
+        for login in expired {
+            login.addr.do_send(CloseLogin {
+                reason: String::from("Login timed out"),
+            });
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    // label for the session token this login makes, falls back to the user agent
+    device: Option<String>,
+}
+
 #[get("/ws-login")]
 async fn ws_login(
     req: actix_web::HttpRequest,
     stream: actix_web::web::Payload,
     data: actix_web::web::Data<AppState>,
+    query: actix_web::web::Query<LoginQuery>,
 ) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    println!("Client connected to login");
-    let (res, mut session, _) = actix_ws::handle(&req, stream)?;
+    log::debug!("Client connected to login");
+    if data.pending_logins.lock().unwrap().len() >= MAX_PENDING_LOGINS {
+        return Err(actix_web::error::ErrorServiceUnavailable(
+            "Too many pending logins, try again later",
+        ));
+    }
 
-    let state_value = uuid::Uuid::new_v4();
+    let (res, mut session, _) = actix_ws::handle(&req, stream)?;
 
-    let (auth_url, _) = data
-        .client
-        .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("identify".to_string()))
-        .add_extra_param("state", &state_value.to_string())
-        .url();
+    let authorize = data.provider.authorize_url();
+    let _ = session.text(authorize.url).await;
 
-    let _ = session.text(auth_url.to_string()).await;
+    let device = query
+        .into_inner()
+        .device
+        .or_else(|| {
+            req.headers()
+                .get(actix_web::http::header::USER_AGENT)
+                .and_then(|h| h.to_str().ok())
+                .map(String::from)
+        })
+        .map(|d| d.chars().take(128).collect::<String>());
 
     let addr = LoginActor::new(session).start();
     {
         let mut pending_logins = data.pending_logins.lock().unwrap();
-        pending_logins.insert(state_value.to_string(), addr);
+        pending_logins.insert(
+            authorize.state,
+            PendingLogin {
+                addr,
+                device,
+                pkce_verifier: authorize.pkce_verifier,
+                created_at: Instant::now(),
+            },
+        );
     }
 
     Ok(res)
 }
 
+// Checks the user can join the room before the socket gets upgraded
+async fn check_room_access(data: &AppState, user_id: i32, room: &Room) -> Result<(), ApiError> {
+    permissions::require(&data.db_conn, user_id, room.campaign_id, Permission::View).await?;
+
+    if let Some(session_id) = room.session_id {
+        if !db::dnd_session_in_campaign(&data.db_conn, session_id, room.campaign_id).await? {
+            return Err(ApiError::NotFound(String::from("Session not found")));
+        }
+    }
+
+    Ok(())
+}
+
+const MAX_MESSAGE_LENGTH: usize = 4000;
+const MAX_LABEL_LENGTH: usize = 100;
+const MAX_WHISPER_RECIPIENTS: usize = 20;
+
+// For errors from code shared with the http api
+fn api_error_frame(request_id: Option<String>, err: &ApiError) -> ServerFrame {
+    let code = match err {
+        ApiError::Forbidden(_) => ErrorCode::Forbidden,
+        ApiError::NotFound(_) => ErrorCode::NotFound,
+        ApiError::Conflict(_) => ErrorCode::Conflict,
+        ApiError::Internal(_) | ApiError::Unauthorized(_) => ErrorCode::Internal,
+        ApiError::BadRequest(_) => ErrorCode::InvalidRequest,
+    };
+    ServerFrame::error(request_id, code, err.message())
+}
+
+// State for one /ws socket. Everything room related goes through the hub actor, this only keeps
+// what the socket's own task needs
+struct Connection {
+    id: ConnectionId,
+    socket: u64,
+    // our user id, `user.id` is the discord one
+    user_id: i32,
+    user: DiscordUser,
+    room: Room,
+    outbox: Outbox,
+    // picked in the Hello handshake, the connection only joins its room after that
+    version: Option<u32>,
+}
+
+impl Connection {
+    async fn send(&self, frame: ServerFrame) {
+        self.outbox.send(frame).await;
+    }
+
+    // Checks the versions from Hello/Resume. On Err the error was already sent, and the socket
+    // should be closed if there's a reason
+    async fn negotiate(
+        &self,
+        request_id: &Option<String>,
+        versions: &[u32],
+    ) -> Result<u32, Option<CloseReason>> {
+        if self.version.is_some() {
+            self.send(ServerFrame::error(
+                request_id.clone(),
+                ErrorCode::AlreadyHandshaken,
+                "Hello was already sent",
+            ))
+            .await;
+            return Err(None);
+        }
+
+        match protocol::negotiate_version(versions) {
+            Some(version) => Ok(version),
+            None => {
+                self.send(ServerFrame::error(
+                    request_id.clone(),
+                    ErrorCode::UnsupportedVersion,
+                    format!("Supported versions are {:?}", SUPPORTED_VERSIONS),
+                ))
+                .await;
+                Err(Some(CloseReason {
+                    code: actix_ws::CloseCode::Policy,
+                    description: Some(String::from("Unsupported protocol version")),
+                }))
+            }
+        }
+    }
+
+    // Handles one text frame from the client, returns Some when the socket should be closed
+    async fn handle_text(&mut self, data: &AppState, text: &str) -> Option<CloseReason> {
+        let frame = match protocol::parse_client_frame(text) {
+            Ok(frame) => frame,
+            Err(err) => {
+                self.send(*err).await;
+                return None;
+            }
+        };
+        let request_id = frame.request_id;
+
+        match frame.message {
+            ClientMessage::Hello { versions } => {
+                let version = match self.negotiate(&request_id, &versions).await {
+                    Ok(version) => version,
+                    Err(reason) => return reason,
+                };
+
+                self.version = Some(version);
+                let _ = data
+                    .hub
+                    .send(hub::Join {
+                        room: self.room,
+                        id: self.id,
+                        socket: self.socket,
+                        user: self.user.clone(),
+                        version,
+                        request_id,
+                        outbox: self.outbox.clone(),
+                    })
+                    .await;
+                self.send_history(
+                    data,
+                    None,
+                    HistoryCursor::Before(None),
+                    DEFAULT_HISTORY_LIMIT,
+                )
+                .await;
+            }
+
+            ClientMessage::Resume {
+                versions,
+                resume_token,
+                last_seq,
+            } => {
+                let version = match self.negotiate(&request_id, &versions).await {
+                    Ok(version) => version,
+                    Err(reason) => return reason,
+                };
+
+                let resumed = data
+                    .hub
+                    .send(hub::Resume {
+                        room: self.room,
+                        socket: self.socket,
+                        user: self.user.clone(),
+                        version,
+                        resume_token,
+                        last_seq,
+                        request_id: request_id.clone(),
+                        outbox: self.outbox.clone(),
+                    })
+                    .await;
+
+                match resumed {
+                    Ok(Some(id)) => {
+                        self.id = id;
+                        self.version = Some(version);
+                    }
+                    _ => {
+                        self.send(ServerFrame::error(
+                            request_id,
+                            ErrorCode::ResumeFailed,
+                            "Can't resume that connection, send Hello instead",
+                        ))
+                        .await;
+                    }
+                }
+            }
+
+            _ if self.version.is_none() => {
+                self.send(ServerFrame::error(
+                    request_id,
+                    ErrorCode::HandshakeRequired,
+                    "Send Hello first",
+                ))
+                .await;
+            }
+
+            ClientMessage::SendMessage {
+                content,
+                visibility,
+                to,
+            } => {
+                if content.trim().is_empty() || content.chars().count() > MAX_MESSAGE_LENGTH {
+                    self.send(ServerFrame::error(
+                        request_id,
+                        ErrorCode::InvalidRequest,
+                        format!(
+                            "Message must be between 1 and {} characters",
+                            MAX_MESSAGE_LENGTH
+                        ),
+                    ))
+                    .await;
+                    return None;
+                }
+
+                if let Err(err) = self
+                    .handle_message(data, request_id.clone(), &content, visibility, &to)
+                    .await
+                {
+                    self.send(api_error_frame(request_id, &err)).await;
+                }
+            }
+
+            ClientMessage::Roll {
+                notation,
+                label,
+                visibility,
+                to,
+            } => {
+                let expression = match dice::parse(&notation) {
+                    Ok(expression) => expression,
+                    Err(err) => {
+                        self.send(ServerFrame::error(
+                            request_id,
+                            ErrorCode::InvalidRequest,
+                            err.to_string(),
+                        ))
+                        .await;
+                        return None;
+                    }
+                };
+                let label = label.filter(|label| !label.trim().is_empty());
+                if label
+                    .as_ref()
+                    .is_some_and(|label| label.chars().count() > MAX_LABEL_LENGTH)
+                {
+                    self.send(ServerFrame::error(
+                        request_id,
+                        ErrorCode::InvalidRequest,
+                        format!("Labels can be at most {} characters", MAX_LABEL_LENGTH),
+                    ))
+                    .await;
+                    return None;
+                }
+
+                let result = expression.roll();
+                if let Err(err) = self
+                    .handle_roll(
+                        data,
+                        request_id.clone(),
+                        label.as_deref(),
+                        visibility,
+                        &to,
+                        result,
+                    )
+                    .await
+                {
+                    self.send(api_error_frame(request_id, &err)).await;
+                }
+            }
+
+            ClientMessage::PatchCharacter {
+                character_id,
+                version,
+                patch,
+            } => {
+                match characters::patch_character(
+                    data,
+                    self.user_id,
+                    self.room.campaign_id,
+                    character_id,
+                    version,
+                    &patch,
+                )
+                .await
+                {
+                    Ok(character) => {
+                        if let Err(err) = characters::broadcast_character(
+                            data,
+                            character,
+                            ServerMessage::CharacterUpdated,
+                            Some((self.id, request_id.clone())),
+                        )
+                        .await
+                        {
+                            self.send(api_error_frame(request_id, &err)).await;
+                        }
+                    }
+                    Err(err) => self.send(api_error_frame(request_id, &err)).await,
+                }
+            }
+
+            ClientMessage::FetchHistory {
+                before,
+                after,
+                limit,
+            } => match history_cursor(before, after, limit) {
+                Ok((cursor, limit)) => self.send_history(data, request_id, cursor, limit).await,
+                Err(err) => {
+                    self.send(ServerFrame::error(
+                        request_id,
+                        ErrorCode::InvalidRequest,
+                        err.message(),
+                    ))
+                    .await;
+                }
+            },
+
+            ClientMessage::Ping => {
+                self.send(ServerFrame::reply(request_id, ServerMessage::Pong))
+                    .await;
+            }
+
+            ClientMessage::Disconnect { reason } => {
+                return Some(CloseReason {
+                    code: actix_ws::CloseCode::Normal,
+                    description: reason,
+                });
+            }
+        }
+
+        None
+    }
+
+    // Works out who gets to see a message or roll. The DMs are looked up every time since they can
+    // change while people are connected
+    async fn audience(
+        &self,
+        data: &AppState,
+        visibility: Visibility,
+        to: &[String],
+    ) -> Result<(Audience, SeenBy), ApiError> {
+        if visibility != Visibility::Whisper && !to.is_empty() {
+            return Err(ApiError::BadRequest(String::from(
+                "to is only for whispers",
+            )));
+        }
+
+        let mut recipients = Vec::new();
+        let mut users = HashSet::from([self.user.id.clone()]);
+        match visibility {
+            Visibility::Public => return Ok((Audience::default(), SeenBy::Everyone)),
+            Visibility::Whisper => {
+                if to.is_empty() || to.len() > MAX_WHISPER_RECIPIENTS {
+                    return Err(ApiError::BadRequest(format!(
+                        "Whispers go to between 1 and {} people",
+                        MAX_WHISPER_RECIPIENTS
+                    )));
+                }
+
+                let members = db::get_campaign_members_by_discord_id(
+                    &data.db_conn,
+                    self.room.campaign_id,
+                    to,
+                )
+                .await?;
+                if let Some(missing) = to.iter().find(|id| !members.iter().any(|(_, d)| d == *id)) {
+                    return Err(ApiError::BadRequest(format!(
+                        "{} isn't in this campaign",
+                        missing
+                    )));
+                }
+                for (user_id, discord_id) in members {
+                    recipients.push(user_id);
+                    users.insert(discord_id);
+                }
+            }
+            Visibility::Dm => {
+                users.extend(db::get_campaign_dms(&data.db_conn, self.room.campaign_id).await?);
+            }
+            Visibility::OnlySelf => {}
+            Visibility::Blind => {
+                users = db::get_campaign_dms(&data.db_conn, self.room.campaign_id)
+                    .await?
+                    .into_iter()
+                    .collect();
+                if users.is_empty() {
+                    return Err(ApiError::BadRequest(String::from(
+                        "This campaign has no DM to roll blind for",
+                    )));
+                }
+            }
+        }
+
+        Ok((
+            Audience {
+                visibility,
+                recipients,
+            },
+            SeenBy::Users(users),
+        ))
+    }
+
+    // Saves the message and sends it to everyone allowed to see it, including the sender's other
+    // devices. The sender's own copy has the request_id so it can tell which message got which id
+    async fn handle_message(
+        &self,
+        data: &AppState,
+        request_id: Option<String>,
+        content: &str,
+        visibility: Visibility,
+        to: &[String],
+    ) -> Result<(), ApiError> {
+        permissions::require(
+            &data.db_conn,
+            self.user_id,
+            self.room.campaign_id,
+            Permission::SendMessages,
+        )
+        .await?;
+        if visibility == Visibility::Blind {
+            return Err(ApiError::BadRequest(String::from(
+                "Only rolls can be blind",
+            )));
+        }
+        let (audience, seen_by) = self.audience(data, visibility, to).await?;
+
+        let message = db::add_message(
+            &data.db_conn,
+            self.room.campaign_id,
+            self.room.session_id,
+            self.user_id,
+            content,
+            &audience,
+        )
+        .await?;
+
+        data.hub.do_send(hub::Publish {
+            room: self.room,
+            message: ServerMessage::Message(message),
+            seen_by,
+            sender: Some((self.id, request_id)),
+        });
+        Ok(())
+    }
+
+    // Saves the roll so it can be checked later and sends it out like a chat message. Whoever made a
+    // blind roll only gets an Ack
+    async fn handle_roll(
+        &self,
+        data: &AppState,
+        request_id: Option<String>,
+        label: Option<&str>,
+        visibility: Visibility,
+        to: &[String],
+        result: RollResult,
+    ) -> Result<(), ApiError> {
+        permissions::require(
+            &data.db_conn,
+            self.user_id,
+            self.room.campaign_id,
+            Permission::RollDice,
+        )
+        .await?;
+        let (audience, seen_by) = self.audience(data, visibility, to).await?;
+
+        let roll = db::add_roll(
+            &data.db_conn,
+            self.room.campaign_id,
+            self.room.session_id,
+            self.user_id,
+            label,
+            &audience,
+            result,
+        )
+        .await?;
+
+        let ack = !seen_by.includes(&self.user.id) && request_id.is_some();
+        data.hub.do_send(hub::Publish {
+            room: self.room,
+            message: ServerMessage::Roll(roll),
+            seen_by,
+            sender: Some((self.id, request_id.clone())),
+        });
+        if ack {
+            self.send(ServerFrame::reply(request_id, ServerMessage::Ack))
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn send_history(
+        &self,
+        data: &AppState,
+        request_id: Option<String>,
+        cursor: HistoryCursor,
+        limit: i64,
+    ) {
+        let page = async {
+            let role = permissions::require(
+                &data.db_conn,
+                self.user_id,
+                self.room.campaign_id,
+                Permission::View,
+            )
+            .await?;
+            db::get_messages(
+                &data.db_conn,
+                self.room.campaign_id,
+                self.room.session_id,
+                cursor,
+                limit,
+                self.user_id,
+                role.is_dm(),
+            )
+            .await
+            .map_err(ApiError::from)
+        }
+        .await;
+
+        match page {
+            Ok(page) => {
+                self.send(ServerFrame::reply(request_id, ServerMessage::History(page)))
+                    .await;
+            }
+            // e.g. kicked from the campaign since connecting
+            Err(err @ (ApiError::NotFound(_) | ApiError::Forbidden(_))) => {
+                self.send(api_error_frame(request_id, &err)).await;
+            }
+            Err(err) => {
+                log::error!("Failed to get history for {:?}: {}", self.room, err);
+                self.send(ServerFrame::error(
+                    request_id,
+                    ErrorCode::Internal,
+                    "Couldn't get history",
+                ))
+                .await;
+            }
+        }
+    }
+}
+
+// /ws?campaign_id=1 joins the campaign's room, adding &session_id=2 joins that dnd session's room.
+// See protocol.rs for what gets sent over it
 #[get("/ws")]
 async fn ws_handler(
     req: actix_web::HttpRequest,
     stream: actix_web::web::Payload,
     data: actix_web::web::Data<AppState>,
+    auth: AuthenticatedUser,
+    room: actix_web::web::Query<Room>,
 ) -> Result<actix_web::HttpResponse, actix_web::Error> {
+    let room = room.into_inner();
+    check_room_access(&data, auth.id, &room).await?;
+
+    // stored at login, so opening a socket doesn't need discord
+    let user = db::get_profile(&data.db_conn, auth.id)
+        .await
+        .map_err(ApiError::from)?;
+
     let (res, mut session, stream) = actix_ws::handle(&req, stream)?;
     let mut stream = stream
         .aggregate_continuations()
         .max_continuation_size(2_usize.pow(20));
 
-    let user: DiscordUser = match req.headers().get("Authorization") {
-        Some(header) => match header.to_str() {
-            Ok(token) => match get_discord_user(token[7..].to_string()).await {
-                Ok(user) => user,
-                Err(err) => return Err(err),
-            },
-            Err(_) => return Err(actix_web::error::ErrorForbidden("Failed to parse token")),
-        },
-        None => return Err(actix_web::error::ErrorForbidden("No token provided")),
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut conn = Connection {
+        id,
+        socket: id,
+        user_id: auth.id,
+        user,
+        room,
+        outbox: Outbox::spawn(session.clone()),
+        version: None,
     };
-
-    println!("{}", format!("New connection: {}", user.id.clone()));
-
-    let session_message = WebsocketMessage::Session(user.clone());
-    let _ = session.text(serde_json::to_string(&session_message)?).await;
-
-    {
-        let mut conns = data.connections.lock().unwrap();
-        conns.insert(user.id.clone(), session.clone());
-
-        let mut sessions = data.sessions.lock().unwrap();
-        sessions.insert(user.id.clone(), user.clone());
-
-        let message = WebsocketMessage::ConnectedUsers(sessions.clone());
-
-        for (_, session) in conns.iter_mut() {
-            let _ = session.text(serde_json::to_string(&message)?).await;
-        }
-    }
+    log::info!(
+        "New connection {}: {} in {:?}",
+        conn.id,
+        conn.user.id,
+        conn.room
+    );
 
     // ping variables
     const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
     const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+    let connected_at = Instant::now();
     let mut last_heartbeat = Instant::now();
     let mut interval = interval(HEARTBEAT_INTERVAL);
 
     actix_web::rt::spawn(async move {
-        let reason = loop {
+        // the bool is whether the client went away without closing, so it could still resume
+        let (reason, resumable) = loop {
             let tick = interval.tick();
             pin!(tick);
 
             match future::select(stream.next(), tick).await {
-                future::Either::Left((Some(Ok(msg)), _)) => {
-                    match msg {
-                        AggregatedMessage::Text(text) => {
-                            let time = chrono::Utc::now().format("%H:%M:%S").to_string();
-                            println!(
-                                "{:} msg from {}: {:?}",
-                                time,
-                                user.username,
-                                text.to_string()
-                            );
-
-                            if let Ok(message) = serde_json::from_str::<WebsocketMessage>(&text) {
-                                if let WebsocketMessage::Disconnect(reason) = message {
-                                    break Some(CloseReason {
-                                        code: actix_ws::CloseCode::Normal,
-                                        description: Some(reason),
-                                    });
-                                }
-                            }
-                            handle_message(&data, user.id.clone(), text.to_string()).await;
-                        }
+                future::Either::Left((Some(Ok(msg)), _)) => match msg {
+                    AggregatedMessage::Text(text) => {
+                        log::debug!("msg from {}: {:?}", conn.user.username, text.to_string());
 
-                        // binary not used
-                        AggregatedMessage::Binary(_) => {
-                            continue;
+                        if let Some(reason) = conn.handle_text(&data, &text).await {
+                            break (Some(reason), false);
                         }
+                    }
 
-                        AggregatedMessage::Close(reason) => {
-                            break reason;
-                        }
+                    AggregatedMessage::Binary(_) => {
+                        conn.send(ServerFrame::error(
+                            None,
+                            ErrorCode::MalformedMessage,
+                            "Binary frames aren't supported",
+                        ))
+                        .await;
+                    }
 
-                        AggregatedMessage::Ping(bytes) => {
-                            last_heartbeat = Instant::now();
-                            let _ = session.pong(&bytes).await;
-                        }
+                    AggregatedMessage::Close(reason) => {
+                        break (reason, false);
+                    }
 
-                        AggregatedMessage::Pong(_) => {
-                            last_heartbeat = Instant::now();
-                        }
+                    AggregatedMessage::Ping(bytes) => {
+                        last_heartbeat = Instant::now();
+                        let _ = session.pong(&bytes).await;
                     }
-                }
+
+                    AggregatedMessage::Pong(_) => {
+                        last_heartbeat = Instant::now();
+                    }
+                },
 
                 // client WebSocket stream error
                 future::Either::Left((Some(Err(err)), _)) => {
-                    eprintln!("{}", err);
-                    break None;
+                    log::warn!("Websocket error for {}: {}", conn.user.id, err);
+                    break (None, true);
                 }
 
                 // client WebSocket stream ended
-                future::Either::Left((None, _)) => break None,
+                future::Either::Left((None, _)) => break (None, true),
 
                 // heartbeat ticked
                 future::Either::Right((_inst, _)) => {
                     if Instant::now().duration_since(last_heartbeat) > CLIENT_TIMEOUT {
-                        println!("Client didn't respond to heartbeat, disconnecting");
+                        log::info!("Client didn't respond to heartbeat, disconnecting");
+
+                        break (None, true);
+                    }
 
-                        break None;
+                    if conn.version.is_none() && connected_at.elapsed() > HANDSHAKE_TIMEOUT {
+                        break (
+                            Some(CloseReason {
+                                code: actix_ws::CloseCode::Policy,
+                                description: Some(String::from("No Hello received")),
+                            }),
+                            false,
+                        );
                     }
 
                     let _ = session.ping(b"").await;
@@ -222,36 +825,15 @@ async fn ws_handler(
 
         // disconnect and remove user
         let _ = session.close(reason).await;
-        println!("User {} disconnecting", user.id);
-
-        let mut conns = data.connections.lock().unwrap();
-        let mut sessions = data.sessions.lock().unwrap();
-        conns.remove(&user.id.clone());
-        sessions.remove(&user.id.clone());
-
-        let message = WebsocketMessage::ConnectedUsers(sessions.clone());
-
-        for (_, session) in conns.iter_mut() {
-            let _ = session.text(serde_json::to_string(&message).unwrap()).await;
+        log::info!("Connection {} ({}) disconnecting", conn.id, conn.user.id);
+        if conn.version.is_some() {
+            data.hub.do_send(hub::Leave {
+                room: conn.room,
+                id: conn.id,
+                socket: conn.socket,
+                resumable,
+            });
         }
     });
     Ok(res)
 }
-
-async fn handle_message(state: &AppState, sender_id: String, message: String) {
-    let mut conns = state.connections.lock().unwrap();
-    for (id, session) in conns.iter_mut() {
-        if *id != sender_id {
-            // just one message type for now, will handle more message types later
-            let _ = session
-                .text(
-                    serde_json::to_string(&WebsocketMessage::Message(ChatMessage {
-                        author: sender_id.clone(),
-                        content: message.clone(),
-                    }))
-                    .unwrap(),
-                )
-                .await;
-        }
-    }
-}